@@ -1,6 +1,10 @@
 use std::any::Any;
 use std::any::AnyRefExt;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::intrinsics::TypeId;
+use std::io;
+use std::io::IoResult;
 use std::mem::transmute;
 use std::mem::transmute_copy;
 use std::raw::TraitObject;
@@ -13,6 +17,51 @@ pub trait Message : Send + Clone + Any {
   fn clone_me(&self) -> Box<Message:Send> {
     box self.clone() as Box<Message:Send>
   }
+
+  // The name of the message's type, for traces and logs, found once
+  // per type. An enum is named by the first of its variants seen;
+  // override this to name it otherwise.
+  fn type_name(&self) -> String {
+    let type_id = self.get_type_id();
+    if TYPE_NAMES.get().is_none() {
+      TYPE_NAMES.replace(Some(RefCell::new(HashMap::new())));
+    }
+    let names = TYPE_NAMES.get().unwrap();
+    let mut names = names.borrow_mut();
+    names.find_or_insert_with(type_id, |_| type_name_of(self)).clone()
+  }
+}
+
+// Names of message types already seen by this task, by TypeId.
+local_data_key!(TYPE_NAMES: RefCell<HashMap<TypeId, String>>)
+
+// The name of the value's type: what {:?} writes of the value up to
+// its fields, which are never formatted.
+pub fn type_name_of<T>(value: &T) -> String {
+  let mut w = NameWriter { name: Vec::new(), done: false };
+  let _ = write!(&mut w, "{:?}", *value);
+  let name = String::from_utf8(w.name).unwrap_or(String::new());
+  name.as_slice().trim_left_chars('&').trim().to_string()
+}
+
+// Keeps what is written up to the first '{' or '(', then refuses the
+// rest, so a value's fields are never formatted.
+struct NameWriter {
+  name: Vec<u8>,
+  done: bool
+}
+
+impl Writer for NameWriter {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    for &b in buf.iter() {
+      if self.done || b == '{' as u8 || b == '(' as u8 {
+        self.done = true;
+        return Err(io::standard_error(io::OtherIoError));
+      }
+      self.name.push(b);
+    }
+    Ok(())
+  }
 }
 
 // copied from std::any, like Chris Morgan's HTTP headers in Teepee
@@ -92,4 +141,10 @@ pub trait Actor {
   // Called after this Actor permanently ceases receiving messages.
   fn post_stop(&mut self,
               context: &mut Context) {}
+
+  // The name of the Actor's type, shown in the Stage's tree. Called
+  // once, on the newly made Actor.
+  fn type_name(&self) -> String {
+    type_name_of(self)
+  }
 }
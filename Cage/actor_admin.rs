@@ -0,0 +1,663 @@
+/*
+ * The admin server lets an operator inspect a running Stage over
+ * HTTP, without attaching a debugger. It is off until started, ex.
+ *
+ *   let admin = stage.serve_admin(8558).unwrap();
+ *
+ * and only accepts connections from the same machine, for requests
+ * whose Host is 127.0.0.1 or localhost, so a web page can't reach it
+ * through a name that resolves to the loopback address. Port 0 picks
+ * a free port. It answers until the server returned is closed or
+ * dropped, with JSON:
+ *   GET  /tree                the Actor hierarchy, as stage.tree()
+ *   GET  /metrics             every Actor's metrics, as stage.metrics()
+ *   GET  /metrics/prometheus  the same in the Prometheus text format
+ *   GET  /dead-letters        dead letters received, in total and by reason
+ *   GET  /failures            the most recent failures, oldest first
+ *   POST /stop/<path>         kills the Actor at /<path>
+ *
+ * A stop request must carry the token made as the server started,
+ * admin.token, as a bearer token, ex.
+ *   curl -X POST -H "Authorization: Bearer $TOKEN" localhost:8558/stop/bank/audit
+ *
+ * Requests are read for at most READ_TIMEOUT ms, and refused if their
+ * request line and headers are longer than MAX_HEAD_SIZE bytes or
+ * number more than MAX_HEADERS.
+ *
+ * Dead letter counts are asked of the dead letter office, so the
+ * admin server suits Stages whose Actors run in their own tasks,
+ * rather than deterministic ones.
+ *
+ * An AdminClient reads the same from another process, ex.
+ *   let tree = AdminClient::new("127.0.0.1", 8558).tree();
+ * as the cage-top monitor does, and stops Actors if made with the
+ * token, ex.
+ *   AdminClient::with_token("127.0.0.1", 8558, token).stop("/bank/audit");
+ */
+use std::any::AnyRefExt;
+use std::ascii::StrAsciiExt;
+use std::collections::TreeMap;
+use std::io::Acceptor;
+use std::io;
+use std::io::BufferedReader;
+use std::io::IoError;
+use std::io::IoResult;
+use std::io::Listener;
+use std::io::net::ip::SocketAddr;
+use std::io::net::tcp::TcpListener;
+use std::io::net::tcp::TcpStream;
+use std::rand::OsRng;
+use std::rand::Rng;
+use std::sync::atomics::AtomicBool;
+use std::sync::atomics::SeqCst;
+use sync::Arc;
+use serialize::Decodable;
+use serialize::Encodable;
+use serialize::json;
+
+use actor_agent::Agent;
+use actor_agent::ROOT_ADDRESS;
+use actor_agent::SYSTEM_ADDRESS;
+use actor_dead_letters::CountDeadLetters;
+use actor_dead_letters::DeadLetterCount;
+use actor_log::Logger;
+use actor_metrics;
+use actor_metrics::ActorMetrics;
+use actor_metrics::FailureLog;
+use actor_metrics::FailureRecord;
+use actor_registry::Registry;
+use actor_tree;
+use actor_tree::ActorNode;
+  use actor_tree::Starting;
+  use actor_tree::Running;
+use cage_message::Kill;
+
+// The admin server only listens on the loopback interface.
+pub static ADMIN_HOST: &'static str = "127.0.0.1";
+
+static OK: &'static str = "200 OK";
+static ACCEPTED: &'static str = "202 Accepted";
+static BAD_REQUEST: &'static str = "400 Bad Request";
+static UNAUTHORIZED: &'static str = "401 Unauthorized";
+static FORBIDDEN: &'static str = "403 Forbidden";
+static NOT_FOUND: &'static str = "404 Not Found";
+static CONFLICT: &'static str = "409 Conflict";
+static TOO_LARGE: &'static str = "431 Request Header Fields Too Large";
+static UNAVAILABLE: &'static str = "503 Service Unavailable";
+
+static JSON: &'static str = "application/json";
+static TEXT: &'static str = "text/plain; version=0.0.4";
+
+// The longest request line and headers read, in bytes, and the most
+// headers read.
+pub static MAX_HEAD_SIZE: uint = 8192;
+pub static MAX_HEADERS: uint = 64;
+// Milliseconds a connection has to send its request.
+pub static READ_TIMEOUT: u64 = 5000;
+// Milliseconds between looks at whether the server was closed.
+static ACCEPT_POLL: u64 = 100;
+
+// A running admin server: where it listens, and the token stop
+// requests must carry. It stops accepting connections once closed
+// or dropped.
+pub struct AdminServer {
+  pub address: SocketAddr,
+  pub token: String,
+  closed: Arc<AtomicBool>,
+  // Told once the acceptor has closed.
+  accepting: Option<Receiver<()>>
+}
+
+impl AdminServer {
+  // Closes the acceptor, returning once it no longer takes connections.
+  // Requests already being answered are finished.
+  pub fn close(&mut self) {
+    self.closed.store(true, SeqCst);
+    match self.accepting.take() {
+      Some(accepting) => { let _ = accepting.recv_opt(); },
+      None => ()
+    }
+  }
+}
+
+impl Drop for AdminServer {
+  fn drop(&mut self) {
+    self.close();
+  }
+}
+
+/*
+ * What the admin server answers with.
+ */
+
+// The dead letters a Stage has received.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct DeadLetterSummary {
+  pub count: uint,
+  // Keyed by reason, ex. "recipient stopped".
+  pub by_reason: TreeMap<String, uint>
+}
+
+impl DeadLetterSummary {
+  fn of(count: &DeadLetterCount) -> DeadLetterSummary {
+    let mut by_reason = TreeMap::new();
+    for &(ref reason, n) in count.by_reason.iter() {
+      by_reason.insert(reason.to_string(), n);
+    }
+    DeadLetterSummary { count: count.count, by_reason: by_reason }
+  }
+}
+
+// The answer to a stop request.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct Stopping {
+  pub path: String
+}
+
+// The answer when a request can't be served.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct AdminError {
+  pub error: String
+}
+
+// What the server reads of a request.
+struct Request {
+  method: String,
+  target: String,
+  host: Option<String>,
+  authorization: Option<String>
+}
+
+struct Response {
+  status: &'static str,
+  content_type: &'static str,
+  body: Vec<u8>
+}
+
+impl Response {
+  fn json<'a, T: Encodable<json::Encoder<'a>, IoError>>(status: &'static str, value: &T) -> Response {
+    Response {
+      status: status,
+      content_type: JSON,
+      body: json::Encoder::buffer_encode(value)
+    }
+  }
+
+  fn error(status: &'static str, error: String) -> Response {
+    Response::json(status, &AdminError { error: error })
+  }
+}
+
+/*
+ * The server.
+ */
+#[deriving(Clone)]
+pub struct Admin {
+  root: Agent,
+  registry: Registry,
+  dead_letters: Agent,
+  failures: FailureLog,
+  log: Logger,
+  // Made as the server starts.
+  token: String
+}
+
+impl Admin {
+  pub fn new(root: Agent,
+             registry: Registry,
+             dead_letters: Agent,
+             failures: FailureLog,
+             log: Logger) -> Admin {
+    Admin {
+      root: root,
+      registry: registry,
+      dead_letters: dead_letters,
+      failures: failures,
+      log: log,
+      token: String::new()
+    }
+  }
+
+  // Answers requests on the loopback interface at port, returning
+  // the server, with the address bound and a new token for stop
+  // requests.
+  pub fn serve(&self, port: u16) -> IoResult<AdminServer> {
+    let mut rng = try!(OsRng::new());
+    let token = format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64());
+    let mut listener = try!(TcpListener::bind(ADMIN_HOST, port));
+    let bound = try!(listener.socket_name());
+    let mut acceptor = try!(listener.listen());
+
+    let mut admin = self.clone();
+    admin.token = token.clone();
+    let closed = Arc::new(AtomicBool::new(false));
+    let (accepting, accepted) = channel();
+    let closing = closed.clone();
+    spawn(proc() {
+      // Accepting gives up every ACCEPT_POLL ms to see if the server
+      // was closed; the acceptor closes as the task ends.
+      while !closing.load(SeqCst) {
+        acceptor.set_timeout(Some(ACCEPT_POLL));
+        match acceptor.accept() {
+          Ok(stream) => {
+            let admin = admin.clone();
+            spawn(proc() admin.answer(stream));
+          },
+          Err(ref err) if err.kind == io::TimedOut => (),
+          Err(err) => admin.log.warn(format!("accepting an admin connection failed: {}", err).as_slice())
+        }
+      }
+      drop(acceptor);
+      accepting.send(());
+    });
+    Ok(AdminServer { address: bound, token: token, closed: closed, accepting: Some(accepted) })
+  }
+
+  // Reads one request from the connection, within READ_TIMEOUT, and
+  // writes the response.
+  fn answer(&self, stream: TcpStream) {
+    let mut stream = stream;
+    stream.set_read_timeout(Some(READ_TIMEOUT));
+    let mut writer = stream.clone();
+    let mut reader = BufferedReader::new(stream);
+    let response = match read_request(&mut reader) {
+      Ok(Some(request)) => self.route(&request),
+      Ok(None) => Response::error(BAD_REQUEST, "malformed request line".to_string()),
+      Err(ref err) if err.kind == io::InvalidInput => Response::error(TOO_LARGE, err.desc.to_string()),
+      Err(_) => return
+    };
+    // There is no one to tell if the client has gone.
+    let _ = write_response(&mut writer, &response);
+  }
+
+  fn route(&self, request: &Request) -> Response {
+    if !is_local(&request.host) {
+      return Response::error(FORBIDDEN, "requests must be addressed to 127.0.0.1 or localhost".to_string());
+    }
+    let (method, target) = (request.method.as_slice(), request.target.as_slice());
+    // Queries are ignored.
+    let target = target.split('?').next().unwrap_or(target);
+    match (method, target) {
+      ("GET", "/tree") =>
+        Response::json(OK, &actor_tree::build(&self.root, self.registry.agents())),
+      ("GET", "/metrics") =>
+        Response::json(OK, &actor_metrics::collect(self.registry.agents().as_slice())),
+      ("GET", "/metrics/prometheus") => {
+        let metrics = actor_metrics::collect(self.registry.agents().as_slice());
+        Response {
+          status: OK,
+          content_type: TEXT,
+          body: actor_metrics::to_prometheus(metrics.as_slice()).into_bytes()
+        }
+      },
+      ("GET", "/dead-letters") => self.dead_letter_summary(),
+      ("GET", "/failures") => Response::json(OK, &self.failures.recent()),
+      ("POST", stop) if stop.starts_with("/stop/") => {
+        if !self.authorized(request) {
+          return Response::error(UNAUTHORIZED, "stopping an Actor needs the admin token".to_string());
+        }
+        self.stop(stop.slice_from("/stop".len()))
+      },
+      _ => Response::error(NOT_FOUND, format!("no such resource: {} {}", method, target))
+    }
+  }
+
+  fn dead_letter_summary(&self) -> Response {
+    match self.dead_letters.request(box CountDeadLetters).unwrap() {
+      Some(reply) => match reply.as_ref::<DeadLetterCount>() {
+        Some(count) => Response::json(OK, &DeadLetterSummary::of(count)),
+        None => Response::error(UNAVAILABLE, "unexpected reply from the dead letter office".to_string())
+      },
+      None => Response::error(UNAVAILABLE, "the dead letter office has stopped".to_string())
+    }
+  }
+
+  // Kills the Actor at path on behalf of the Stage. The root and
+  // the system Actors keep the Stage running, so they can't be.
+  fn stop(&self, path: &str) -> Response {
+    if path == ROOT_ADDRESS || path == SYSTEM_ADDRESS || path.starts_with(SYSTEM_ADDRESS.to_string().append("/").as_slice()) {
+      return Response::error(FORBIDDEN, format!("{} can't be stopped", path));
+    }
+    match self.registry.lookup(path) {
+      Some(agent) => match agent.state() {
+        Starting | Running => {
+          if !agent.try_deliver(Kill(self.root.clone())) {
+            return Response::error(CONFLICT, format!("{} has stopped", path));
+          }
+          self.log.info(format!("stopping {} at an operator's request", path).as_slice());
+          Response::json(ACCEPTED, &Stopping { path: path.to_string() })
+        },
+        state => Response::error(CONFLICT, format!("{} is {}", path, state))
+      },
+      None => Response::error(NOT_FOUND, format!("no Actor at {}", path))
+    }
+  }
+
+  // Whether the request carries the token as a bearer token.
+  fn authorized(&self, request: &Request) -> bool {
+    match request.authorization {
+      Some(ref authorization) => {
+        let mut words = authorization.as_slice().words();
+        match (words.next(), words.next(), words.next()) {
+          (Some(scheme), Some(token), None) =>
+            scheme.eq_ignore_ascii_case("Bearer") && same(token.as_bytes(), self.token.as_bytes()),
+          _ => false
+        }
+      },
+      None => false
+    }
+  }
+}
+
+// Whether the request's Host, less any port, is the loopback
+// interface by name or address.
+fn is_local(host: &Option<String>) -> bool {
+  match *host {
+    Some(ref host) => {
+      let host = host.as_slice();
+      let name = match host.rfind(':') {
+        Some(i) if host.slice_from(i + 1).chars().all(|c| c.is_digit()) => host.slice_to(i),
+        _ => host
+      };
+      name == ADMIN_HOST || name.eq_ignore_ascii_case("localhost")
+    },
+    None => false
+  }
+}
+
+// Compares the bytes in time that depends only on their lengths.
+fn same(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (*x ^ *y)) == 0
+}
+
+// Reads a request line and its headers, returning None if the
+// request line is malformed. Requests whose head is longer than
+// MAX_HEAD_SIZE, or has more than MAX_HEADERS headers, are refused
+// with an InvalidInput error.
+fn read_request<R: Buffer>(reader: &mut R) -> IoResult<Option<Request>> {
+  let mut budget = MAX_HEAD_SIZE;
+  let line = try!(read_line_within(reader, &mut budget));
+  let (mut host, mut authorization) = (None, None);
+  let mut headers = 0u;
+  loop {
+    let header = try!(read_line_within(reader, &mut budget));
+    let header = header.as_slice().trim();
+    if header.is_empty() {
+      break;
+    }
+    headers += 1;
+    if headers > MAX_HEADERS {
+      return Err(too_large("too many request headers"));
+    }
+    match header.find(':') {
+      Some(i) => {
+        let value = Some(header.slice_from(i + 1).trim().to_string());
+        match header.slice_to(i).trim().to_ascii_lower().as_slice() {
+          "host" => host = value,
+          "authorization" => authorization = value,
+          _ => ()
+        }
+      },
+      None => ()
+    }
+  }
+  let mut words = line.as_slice().words();
+  match (words.next(), words.next()) {
+    (Some(method), Some(target)) => Ok(Some(Request {
+      method: method.to_string(),
+      target: target.to_string(),
+      host: host,
+      authorization: authorization
+    })),
+    _ => Ok(None)
+  }
+}
+
+// Reads a line, taking its bytes from the budget, and refusing it if
+// the budget runs out first.
+fn read_line_within<R: Buffer>(reader: &mut R, budget: &mut uint) -> IoResult<String> {
+  let mut line = Vec::new();
+  loop {
+    if *budget == 0 {
+      return Err(too_large("request headers are too long"));
+    }
+    let b = try!(reader.read_byte());
+    *budget -= 1;
+    if b == '\n' as u8 {
+      break;
+    }
+    line.push(b);
+  }
+  Ok(String::from_utf8_lossy(line.as_slice()).to_string())
+}
+
+fn too_large(desc: &'static str) -> IoError {
+  IoError { kind: io::InvalidInput, desc: desc, detail: None }
+}
+
+fn write_response(writer: &mut TcpStream, response: &Response) -> IoResult<()> {
+  try!(writer.write_str(format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                response.status,
+                                response.content_type,
+                                response.body.len()).as_slice()));
+  writer.write(response.body.as_slice())
+}
+
+/*
+ * Reading the admin server from another process.
+ */
+#[deriving(Clone)]
+pub struct AdminClient {
+  host: String,
+  port: u16,
+  token: Option<String>
+}
+
+impl AdminClient {
+  pub fn new(host: &str, port: u16) -> AdminClient {
+    AdminClient { host: host.to_string(), port: port, token: None }
+  }
+
+  // A client that can also stop Actors, with the token the server
+  // made as it started.
+  pub fn with_token(host: &str, port: u16, token: &str) -> AdminClient {
+    AdminClient { host: host.to_string(), port: port, token: Some(token.to_string()) }
+  }
+
+  pub fn tree(&self) -> IoResult<ActorNode> {
+    self.get("/tree")
+  }
+
+  pub fn metrics(&self) -> IoResult<Vec<ActorMetrics>> {
+    self.get("/metrics")
+  }
+
+  pub fn dead_letters(&self) -> IoResult<DeadLetterSummary> {
+    self.get("/dead-letters")
+  }
+
+  pub fn failures(&self) -> IoResult<Vec<FailureRecord>> {
+    self.get("/failures")
+  }
+
+  // Asks the Stage to kill the Actor at path.
+  pub fn stop(&self, path: &str) -> IoResult<Stopping> {
+    self.call("POST", format!("/stop{}", path).as_slice())
+  }
+
+  fn get<T: Decodable<json::Decoder, json::DecoderError>>(&self, target: &str) -> IoResult<T> {
+    self.call("GET", target)
+  }
+
+  // Makes a request, decoding the JSON answer.
+  fn call<T: Decodable<json::Decoder, json::DecoderError>>(&self, method: &str, target: &str) -> IoResult<T> {
+    let mut stream = try!(TcpStream::connect(self.host.as_slice(), self.port));
+    let authorization = match self.token {
+      Some(ref token) => format!("Authorization: Bearer {}\r\n", token),
+      None => String::new()
+    };
+    try!(stream.write_str(format!("{} {} HTTP/1.0\r\nHost: {}:{}\r\n{}\r\n",
+                                  method, target, self.host, self.port, authorization).as_slice()));
+    let response = try!(stream.read_to_string());
+    let (status, body) = match response.as_slice().find_str("\r\n\r\n") {
+      Some(i) => (response.as_slice().slice_to(i), response.as_slice().slice_from(i + 4)),
+      None => return Err(AdminClient::error("malformed response", response.clone()))
+    };
+    let succeeded = status.words().nth(1).map_or(false, |code| code.starts_with("2"));
+    if !succeeded {
+      let detail = match json::decode::<AdminError>(body) {
+        Ok(error) => error.error,
+        Err(_) => status.lines().next().unwrap_or("").to_string()
+      };
+      return Err(AdminClient::error("admin request refused", detail));
+    }
+    json::decode::<T>(body).map_err(|err| AdminClient::error("malformed response", format!("{}", err)))
+  }
+
+  fn error(desc: &'static str, detail: String) -> IoError {
+    IoError {
+      kind: io::OtherIoError,
+      desc: desc,
+      detail: Some(detail)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io;
+  use std::io::BufReader;
+  use std::io::net::tcp::TcpStream;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_tree::Stopped;
+  use super::ADMIN_HOST;
+  use super::AdminClient;
+  use super::AdminServer;
+  use super::MAX_HEAD_SIZE;
+  use super::MAX_HEADERS;
+  use super::is_local;
+  use super::read_request;
+
+  struct Worker;
+
+  impl Actor for Worker {
+    fn new() -> Worker {
+      Worker
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {}
+  }
+
+  // The server, a client without the token, and one with it.
+  fn serve(stage: &Stage) -> (AdminServer, AdminClient, AdminClient) {
+    let admin = stage.serve_admin(0).unwrap();
+    let port = admin.address.port;
+    let writer = AdminClient::with_token(ADMIN_HOST, port, admin.token.as_slice());
+    (admin, AdminClient::new(ADMIN_HOST, port), writer)
+  }
+
+  #[test]
+  fn accepts_only_loopback_hosts() {
+    assert!(is_local(&Some("localhost:8558".to_string())));
+    assert!(is_local(&Some("LocalHost".to_string())));
+    assert!(is_local(&Some("127.0.0.1".to_string())));
+    assert!(!is_local(&Some("evil.example:8558".to_string())));
+    assert!(!is_local(&Some("127.0.0.1.evil.example".to_string())));
+    assert!(!is_local(&None));
+  }
+
+  // Whether reading the request head is refused as too large.
+  fn refused(head: &str) -> bool {
+    match read_request(&mut BufReader::new(head.as_bytes())) {
+      Err(err) => err.kind == io::InvalidInput,
+      Ok(_) => false
+    }
+  }
+
+  #[test]
+  fn reads_requests_with_bounded_heads() {
+    let head = "GET /tree HTTP/1.0\r\nHost: localhost\r\n\r\n";
+    let request = read_request(&mut BufReader::new(head.as_bytes())).unwrap().unwrap();
+    assert_eq!(request.target.as_slice(), "/tree");
+    assert_eq!(request.host, Some("localhost".to_string()));
+
+    let long = format!("GET /tree HTTP/1.0\r\nX-Padding: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE));
+    assert!(refused(long.as_slice()));
+
+    let mut crowded = "GET /tree HTTP/1.0\r\n".to_string();
+    for _ in range(0, MAX_HEADERS + 1) {
+      crowded.push_str("X: y\r\n");
+    }
+    crowded.push_str("\r\n");
+    assert!(refused(crowded.as_slice()));
+  }
+
+  #[test]
+  fn refuses_requests_addressed_to_other_hosts() {
+    let stage = Stage::deterministic();
+    let (admin, _, _) = serve(&stage);
+    let mut stream = TcpStream::connect(ADMIN_HOST, admin.address.port).unwrap();
+    stream.write_str("GET /tree HTTP/1.0\r\nHost: evil.example\r\n\r\n").unwrap();
+    let response = stream.read_to_string().unwrap();
+    assert!(response.as_slice().starts_with("HTTP/1.0 403"));
+  }
+
+  #[test]
+  fn reads_without_the_token() {
+    let mut stage = Stage::deterministic();
+    stage.start_name::<Worker>("worker".to_string());
+    stage.run_until_idle();
+    let (_admin, reader, _) = serve(&stage);
+    assert!(reader.tree().unwrap().find("/worker").is_some());
+  }
+
+  #[test]
+  fn stops_actors_only_with_the_token() {
+    let mut stage = Stage::deterministic();
+    let worker = stage.start_name::<Worker>("worker".to_string());
+    stage.run_until_idle();
+    let (admin, reader, writer) = serve(&stage);
+    assert!(reader.stop("/worker").is_err());
+    assert!(AdminClient::with_token(ADMIN_HOST, admin.address.port, "guess").stop("/worker").is_err());
+    assert_eq!(writer.stop("/worker").unwrap().path, "/worker".to_string());
+    stage.run_until_idle();
+    assert_eq!(worker.state(), Stopped);
+  }
+
+  #[test]
+  fn refuses_to_stop_a_stopped_actor() {
+    let mut stage = Stage::deterministic();
+    stage.start_name::<Worker>("worker".to_string());
+    stage.run_until_idle();
+    let (_admin, _, writer) = serve(&stage);
+    writer.stop("/worker").unwrap();
+    stage.run_until_idle();
+    assert!(writer.stop("/worker").is_err());
+  }
+
+  #[test]
+  fn refuses_to_stop_the_root_or_system_actors() {
+    let stage = Stage::deterministic();
+    let (_admin, _, writer) = serve(&stage);
+    assert!(writer.stop("/").is_err());
+    assert!(writer.stop("/system/log").is_err());
+  }
+
+  #[test]
+  fn stops_accepting_connections_once_closed() {
+    let stage = Stage::deterministic();
+    let (mut admin, reader, _) = serve(&stage);
+    assert!(reader.failures().unwrap().is_empty());
+    admin.close();
+    assert!(TcpStream::connect(ADMIN_HOST, admin.address.port).is_err());
+    assert!(reader.failures().is_err());
+  }
+}
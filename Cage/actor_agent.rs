@@ -3,12 +3,28 @@
  * for an Actor in the Cage system.
  */
 use std::any::Any;
+use std::any::AnyRefExt;
 use std::comm::channel;
 use std::comm::Sender;
+use std::sync::atomics::AtomicInt;
+use std::sync::atomics::AtomicUint;
+use std::sync::atomics::SeqCst;
+use sync::Arc;
+use sync::Mutex;
 use sync::Future;
+use time;
 
 use actor::Message;
+use actor_dead_letters::DeadLetter;
+use actor_dead_letters::DeadLetterReason;
+  use actor_dead_letters::RecipientStopped;
+  use actor_dead_letters::Unreturnable;
+use actor_metrics::ActorMetrics;
+use actor_trace::TraceContext;
+use actor_tree::ActorState;
+  use actor_tree::Starting;
 use cage_message::CageMessage;
+use cage_message::Envelope;
   use cage_message::UserMessage;
   use cage_message::Find;
   use cage_message::Terminated;
@@ -18,11 +34,58 @@ use cage_message::CageMessage;
 
 pub static NO_ADDRESS: &'static str = "";
 pub static ROOT_ADDRESS: &'static str = "/";
+pub static SYSTEM_ADDRESS: &'static str = "/system";
+pub static SYSTEM_NAME: &'static str = "system";
+pub static DEAD_LETTERS_NAME: &'static str = "deadLetters";
+pub static LOG_NAME: &'static str = "log";
+pub static CLUSTER_NAME: &'static str = "cluster";
 pub static NAME_LENGTH: uint = 20;
 
+// Counters for an Actor's mailbox, shared by every Agent to it.
+// Senders update them on every message, so they take no lock.
+pub struct MailboxStats {
+  size: AtomicInt,
+  received: AtomicUint,
+  // 1 while the Actor is handling a message.
+  handling: AtomicUint,
+  // Nanoseconds messages have waited in the mailbox, and the Actor
+  // has spent handling them.
+  wait_ns: AtomicUint,
+  receive_ns: AtomicUint,
+  failures: AtomicUint,
+  lifecycle: Mutex<Lifecycle>
+}
+
+// What the Actor is and where it is in its life.
+struct Lifecycle {
+  type_name: String,
+  state: ActorState,
+  watchers: uint
+}
+
+impl MailboxStats {
+  fn new() -> MailboxStats {
+    MailboxStats {
+      size: AtomicInt::new(0),
+      received: AtomicUint::new(0),
+      handling: AtomicUint::new(0),
+      wait_ns: AtomicUint::new(0),
+      receive_ns: AtomicUint::new(0),
+      failures: AtomicUint::new(0),
+      lifecycle: Mutex::new(Lifecycle {
+        type_name: String::new(),
+        state: Starting,
+        watchers: 0
+      })
+    }
+  }
+}
+
 #[deriving(Clone)]
 pub struct Agent {
-  inbox: Sender<CageMessage>,
+  inbox: Sender<Envelope>,
+  mailbox: Arc<MailboxStats>,
+  dead_letters: Option<Box<Agent>>,
   path: String,
   name: String
 }
@@ -30,32 +93,68 @@ pub struct Agent {
 impl Agent {
     // Instructs the Agent to deliver the message to the Actor.
   pub fn deliver(&self, msg: CageMessage) {
-    match self.inbox.send_opt(msg) {
-      Err(err) => match err {
-        UserMessage(orig, sender) => sender.deliver(
-          Undelivered(self.clone(), orig)
-        ),
-        Find(_, orig, sender) => sender.deliver(
-          Undelivered(self.clone(), orig)
-        ),
-        Watch(watcher) => watcher.deliver(
-          Terminated(self.clone())
-        ),
-        _ => ()
+    self.try_deliver(msg);
+  }
+
+  // Delivers the message as deliver does, returning false if the
+  // Actor had stopped.
+  pub fn try_deliver(&self, msg: CageMessage) -> bool {
+    match self.enqueue(msg) {
+      Ok(()) => true,
+      Err(err) => {
+        match err {
+          UserMessage(orig, sender) => {
+            self.dead_letter(orig.clone_me(), self, &sender, RecipientStopped);
+            sender.deliver(Undelivered(self.clone(), orig))
+          },
+          Find(_, orig, sender) => {
+            self.dead_letter(orig.clone_me(), self, &sender, RecipientStopped);
+            sender.deliver(Undelivered(self.clone(), orig))
+          },
+          Failure(err, failed) =>
+            self.dead_letter(err, self, &failed, RecipientStopped),
+          Undelivered(target, orig) =>
+            self.dead_letter(orig, &target, self, Unreturnable),
+          Watch(watcher) => watcher.deliver(
+            Terminated(self.clone())
+          ),
+          _ => ()
+        }
+        false
+      }
+    }
+  }
+
+  // Sends a message that could not be delivered to the dead letter office,
+  // noting who it was meant for, who sent it, and why it was lost.
+  pub fn dead_letter(&self,
+                     msg: Box<Message:Send>,
+                     recipient: &Agent,
+                     sender: &Agent,
+                     reason: DeadLetterReason) {
+    // Dead letters that can't reach the office are dropped, rather
+    // than bouncing back into it forever.
+    if msg.is::<DeadLetter>() {
+      return;
+    }
+    match self.dead_letters {
+      Some(ref office) => {
+        let letter = DeadLetter::new(msg, recipient.clone(), sender.clone(), reason);
+        let _ = office.enqueue(UserMessage(box letter, sender.clone()));
       },
-      _ => ()
+      None => ()
     }
   }
 
   // For message sending from a non-Actor.
   pub fn request(&self, msg: Box<Message:Send>) -> Future<Option<Box<Message:Send>>> {
     let (send, recv) = channel();
-    self.deliver(UserMessage(msg.clone_me(), Agent::dummy(send))); 
+    self.deliver(UserMessage(msg.clone_me(), self.dummy(send))); 
 
     Future::from_fn(proc() {
       match recv.recv_opt() {
-        Ok(m) =>
-          match m {
+        Ok(envelope) =>
+          match envelope.msg {
             UserMessage(msg, _) => Some(msg),
             Failure(err, _) => Some(msg),
             _ => None
@@ -65,10 +164,15 @@ impl Agent {
     })
   }
 
-  // For message sending from a non-Actor without a response. 
+  // For message sending from a non-Actor without a response.
+  // Any reply goes straight to the dead letter office.
   pub fn fire_and_forget(&self, msg: Box<Message:Send>) {
-    let (send, recv) = channel();
-    self.deliver(UserMessage(msg.clone_me(), Agent::dummy(send)));
+    let (send, _) = channel();
+    let sender = match self.dead_letters() {
+      Some(office) => office,
+      None => self.dummy(send)
+    };
+    self.deliver(UserMessage(msg, sender));
   }
 
 
@@ -82,18 +186,165 @@ impl Agent {
     self.name.clone()
   }
   
+  // Returns the number of messages waiting to be received by the Actor.
+  // Shared by every clone of this Agent.
+  pub fn mailbox_size(&self) -> uint {
+    let size = self.mailbox.size.load(SeqCst);
+    if size < 0 { 0 } else { size as uint }
+  }
+
+  // Returns the number of messages the Actor has taken out of its
+  // mailbox since it started.
+  pub fn received_count(&self) -> uint {
+    self.mailbox.received.load(SeqCst)
+  }
+
+  // Returns the number of messages waiting, plus the one being
+  // handled, if any.
+  pub fn pending(&self) -> uint {
+    self.mailbox_size() + self.mailbox.handling.load(SeqCst)
+  }
+
+  // Called by the Cage system as the Actor starts and finishes
+  // handling a message.
+  pub fn set_handling(&self, handling: bool) {
+    self.mailbox.handling.store(if handling { 1 } else { 0 }, SeqCst);
+  }
+
+  // Called by the Cage system each time the Actor takes a message,
+  // enqueued at the given time, out of its mailbox.
+  pub fn dequeued(&self, enqueued: u64) {
+    self.mailbox.size.fetch_sub(1, SeqCst);
+    self.mailbox.received.fetch_add(1, SeqCst);
+    self.mailbox.wait_ns.fetch_add((time::precise_time_ns() - enqueued) as uint, SeqCst);
+  }
+
+  // Called by the Cage system after the Actor handles a message.
+  pub fn received_in(&self, nanoseconds: u64) {
+    self.mailbox.receive_ns.fetch_add(nanoseconds as uint, SeqCst);
+  }
+
+  // Called by the Cage system when the Actor fails.
+  pub fn failed(&self) {
+    self.mailbox.failures.fetch_add(1, SeqCst);
+  }
+
+  // Called by the Cage system as the Actor is created.
+  pub fn set_type_name(&self, type_name: String) {
+    self.mailbox.lifecycle.lock().type_name = type_name;
+  }
+
+  // The name of the Actor's type.
+  pub fn type_name(&self) -> String {
+    self.mailbox.lifecycle.lock().type_name.clone()
+  }
+
+  // Called by the Cage system as the Actor starts and stops.
+  pub fn set_state(&self, state: ActorState) {
+    self.mailbox.lifecycle.lock().state = state;
+  }
+
+  pub fn state(&self) -> ActorState {
+    self.mailbox.lifecycle.lock().state.clone()
+  }
+
+  // Called by the Cage system as Actors watch and unwatch this one.
+  pub fn set_watcher_count(&self, watchers: uint) {
+    self.mailbox.lifecycle.lock().watchers = watchers;
+  }
+
+  // The number of Actors watching this one.
+  pub fn watcher_count(&self) -> uint {
+    self.mailbox.lifecycle.lock().watchers
+  }
+
+  // Returns a snapshot of the Actor's metrics.
+  pub fn metrics(&self) -> ActorMetrics {
+    ActorMetrics {
+      path: self.path.clone(),
+      mailbox_size: self.mailbox_size(),
+      processed: self.received_count(),
+      receive_ns: self.mailbox.receive_ns.load(SeqCst) as u64,
+      wait_ns: self.mailbox.wait_ns.load(SeqCst) as u64,
+      failures: self.mailbox.failures.load(SeqCst)
+    }
+  }
+
+  // Returns an Agent to the dead letter office this Agent reports to.
+  pub fn dead_letters(&self) -> Option<Agent> {
+    match self.dead_letters {
+      Some(ref office) => Some((**office).clone()),
+      None => None
+    }
+  }
+
   // Returns a new Agent with a given name.
-  pub fn new(sender: Sender<CageMessage>, dir: String, name: String) -> Agent {
+  pub fn new(sender: Sender<Envelope>, dir: String, name: String) -> Agent {
+    Agent::with_dead_letters(sender, dir, name, None)
+  }
+
+  // Returns a new Agent that reports undeliverable messages to the
+  // given dead letter office.
+  pub fn with_dead_letters(sender: Sender<Envelope>,
+                           dir: String,
+                           name: String,
+                           dead_letters: Option<Agent>) -> Agent {
     Agent {
       inbox: sender,
+      mailbox: Arc::new(MailboxStats::new()),
+      dead_letters: dead_letters.map(|office| box office),
       path: dir.append(name.as_slice()),
       name: name
     }
   }
 
+  // Returns a new Agent placed under this one, sharing its dead letter office.
+  pub fn child(&self, sender: Sender<Envelope>, name: String) -> Agent {
+    let mut dir = self.path.clone();
+    if !dir.as_slice().ends_with("/") {
+      dir.push_char('/');
+    }
+    Agent::with_dead_letters(sender, dir, name, self.dead_letters())
+  }
+
+  // Returns an Agent with this one's path and mailbox counters whose
+  // inbox is closed, for a task that reads the mailbox without keeping
+  // it open; what is delivered to it is handled as for a stopped Actor.
+  pub fn detached(&self) -> Agent {
+    let (sender, _) = channel::<Envelope>();
+    Agent {
+      inbox: sender,
+      mailbox: self.mailbox.clone(),
+      dead_letters: self.dead_letters.clone(),
+      path: self.path.clone(),
+      name: self.name.clone()
+    }
+  }
+
   // Returns an Agent with no directory information.
-  fn dummy(sender: Sender<CageMessage>) -> Agent {
-    Agent::new(sender, NO_ADDRESS.to_string(), NO_ADDRESS.to_string())
+  fn dummy(&self, sender: Sender<Envelope>) -> Agent {
+    Agent::with_dead_letters(sender,
+                             NO_ADDRESS.to_string(),
+                             NO_ADDRESS.to_string(),
+                             self.dead_letters())
+  }
+
+  // Puts the message in the Actor's mailbox, counting it, or hands
+  // it back if the Actor has stopped.
+  fn enqueue(&self, msg: CageMessage) -> Result<(), CageMessage> {
+    self.mailbox.size.fetch_add(1, SeqCst);
+    let result = self.inbox.send_opt(Envelope {
+      msg: msg,
+      trace: TraceContext::current(),
+      enqueued: time::precise_time_ns()
+    });
+    match result {
+      Ok(()) => Ok(()),
+      Err(envelope) => {
+        self.mailbox.size.fetch_sub(1, SeqCst);
+        Err(envelope.msg)
+      }
+    }
   }
 }
 
@@ -0,0 +1,357 @@
+/*
+ * Chaos mode injects faults into user message delivery, to see how
+ * a system copes before production does it for real. Faults are set
+ * per path pattern, as chances per message, ex.
+ *
+ *   let chaos = stage.chaos();
+ *   chaos.set_seed(42);
+ *   chaos.inject("/bank/*", Faults { drop: 0.05, fail: 0.01, ..Faults::none() });
+ *
+ * Patterns are paths whose segments may be *, and cover the Actors
+ * they match and everything beneath them; the last pattern set that
+ * covers an Actor decides its faults. Each message an Actor takes
+ * from its mailbox is, at most one of:
+ *   dropped     never received
+ *   duplicated  received twice
+ *   delayed     sent back to the Actor up to max_delay ms later
+ *   reordered   sent back to the end of the Actor's mailbox
+ *   failed      the Actor stops instead of receiving it, sending its
+ *               parent a Failure of InjectedFailure
+ * Messages sent back can meet faults again, so chances stay below one.
+ * Messages found by path meet faults as those sent to an Agent do.
+ *
+ * A failure is injected without panicking inside receive: a deterministic
+ * Stage runs Actors in the test's own task, which a panic would fail.
+ * The Actor is stopped and its parent told as for a panic instead, on
+ * every Stage alike.
+ *
+ * Each Actor's faults are drawn from a generator of its own, seeded
+ * from the seed and its path, so how Actors interleave doesn't change
+ * which messages meet faults: the same seed injects the same faults
+ * into the nth message of an Actor at a given path, deterministic
+ * Stage or not. Actors started with random names have other paths
+ * each run, and so other faults.
+ */
+use std::collections::HashMap;
+use std::hash;
+use std::rand::Rng;
+use std::rand::SeedableRng;
+use std::rand::StdRng;
+use std::sync::atomics::AtomicBool;
+use std::sync::atomics::SeqCst;
+use sync::Arc;
+use sync::Mutex;
+
+use actor::Message;
+use actor_agent::ROOT_ADDRESS;
+
+// Chances per message, from 0 to 1, of each fault.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Faults {
+  pub drop: f64,
+  pub duplicate: f64,
+  pub delay: f64,
+  // The longest delay in milliseconds.
+  pub max_delay: u64,
+  pub reorder: f64,
+  pub fail: f64
+}
+
+impl Faults {
+  pub fn none() -> Faults {
+    Faults {
+      drop: 0.0,
+      duplicate: 0.0,
+      delay: 0.0,
+      max_delay: 0,
+      reorder: 0.0,
+      fail: 0.0
+    }
+  }
+}
+
+// The error in the Failure an Actor's parent receives when a failed
+// fault stops it.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct InjectedFailure {
+  pub path: String
+}
+impl Message for InjectedFailure {}
+
+// What happens to one message.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Fault {
+  NoFault,
+  Dropped,
+  Duplicated,
+  Delayed(u64),
+  Reordered,
+  Failed
+}
+
+// How many of each fault have been injected.
+#[deriving(Clone, PartialEq, Show)]
+pub struct ChaosCounts {
+  pub dropped: uint,
+  pub duplicated: uint,
+  pub delayed: uint,
+  pub reordered: uint,
+  pub failed: uint
+}
+
+struct ChaosState {
+  rules: Vec<(String, Faults)>,
+  seed: uint,
+  // Each Actor's generator, by path.
+  rngs: HashMap<String, StdRng>,
+  counts: ChaosCounts
+}
+
+#[deriving(Clone)]
+pub struct Chaos {
+  // Whether any rule is set, checked before taking the lock.
+  active: Arc<AtomicBool>,
+  state: Arc<Mutex<ChaosState>>
+}
+
+impl Chaos {
+  pub fn new() -> Chaos {
+    Chaos {
+      active: Arc::new(AtomicBool::new(false)),
+      state: Arc::new(Mutex::new(ChaosState {
+        rules: Vec::new(),
+        seed: 0,
+        rngs: HashMap::new(),
+        counts: ChaosCounts { dropped: 0, duplicated: 0, delayed: 0, reordered: 0, failed: 0 }
+      }))
+    }
+  }
+
+  // Restarts the choice of faults from the seed.
+  pub fn set_seed(&self, seed: uint) {
+    let mut state = self.state.lock();
+    state.seed = seed;
+    state.rngs.clear();
+  }
+
+  // Injects faults into the Actors matching pattern and beneath them.
+  pub fn inject(&self, pattern: &str, faults: Faults) {
+    let mut state = self.state.lock();
+    state.rules.retain(|&(ref p, _)| p.as_slice() != pattern);
+    state.rules.push((pattern.to_string(), faults));
+    self.active.store(true, SeqCst);
+  }
+
+  // Stops injecting faults; the counts remain.
+  pub fn clear(&self) {
+    self.state.lock().rules.clear();
+    self.active.store(false, SeqCst);
+  }
+
+  pub fn counts(&self) -> ChaosCounts {
+    self.state.lock().counts.clone()
+  }
+
+  // Picks the fault, if any, for a message to the Actor at path,
+  // and counts it.
+  pub fn fault_for(&self, path: &str) -> Fault {
+    if !self.active.load(SeqCst) {
+      return NoFault;
+    }
+    let mut guard = self.state.lock();
+    let state = &mut *guard;
+    let faults = match state.rules.iter().rev().find(|&&(ref p, _)| Chaos::covers(p.as_slice(), path)) {
+      Some(&(_, ref faults)) => faults.clone(),
+      None => return NoFault
+    };
+    let seed = state.seed;
+    let rng = state.rngs.find_or_insert_with(path.to_string(), |path| {
+      SeedableRng::from_seed(&[seed, hash::hash(path) as uint])
+    });
+    let roll: f64 = rng.gen();
+    let chances = [faults.drop, faults.duplicate, faults.delay, faults.reorder, faults.fail];
+    let mut threshold = 0.0;
+    let mut picked = None;
+    for (i, chance) in chances.iter().enumerate() {
+      threshold += *chance;
+      if roll < threshold {
+        picked = Some(i);
+        break;
+      }
+    }
+    match picked {
+      Some(0) => { state.counts.dropped += 1; Dropped },
+      Some(1) => { state.counts.duplicated += 1; Duplicated },
+      Some(2) => {
+        state.counts.delayed += 1;
+        Delayed(rng.gen_range(0, faults.max_delay + 1))
+      },
+      Some(3) => { state.counts.reordered += 1; Reordered },
+      Some(_) => { state.counts.failed += 1; Failed },
+      None => NoFault
+    }
+  }
+
+  // Whether pattern matches path or one of its ancestors.
+  fn covers(pattern: &str, path: &str) -> bool {
+    if pattern == ROOT_ADDRESS {
+      return true;
+    }
+    let mut segments = path.split('/');
+    for expected in pattern.split('/') {
+      match segments.next() {
+        Some(segment) if expected == "*" || expected == segment => (),
+        _ => return false
+      }
+    }
+    true
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestActorRef;
+  use cage_message::Find;
+  use super::Chaos;
+  use super::Fault;
+  use super::Faults;
+  use super::InjectedFailure;
+    use super::Dropped;
+    use super::NoFault;
+
+  #[test]
+  fn covers_matching_paths_and_beneath_them() {
+    assert!(Chaos::covers("/", "/bank/audit"));
+    assert!(Chaos::covers("/bank", "/bank"));
+    assert!(Chaos::covers("/bank", "/bank/audit"));
+    assert!(Chaos::covers("/bank/*", "/bank/audit/1"));
+    assert!(Chaos::covers("/*/audit", "/bank/audit"));
+    assert!(!Chaos::covers("/bank", "/banking"));
+    assert!(!Chaos::covers("/bank/*", "/bank"));
+    assert!(!Chaos::covers("/*/audit", "/bank/ledger"));
+  }
+
+  #[test]
+  fn lets_the_last_covering_pattern_decide() {
+    let chaos = Chaos::new();
+    chaos.inject("/bank", Faults { drop: 1.0, ..Faults::none() });
+    chaos.inject("/bank/audit", Faults::none());
+    assert_eq!(chaos.fault_for("/bank/ledger"), Dropped);
+    assert_eq!(chaos.fault_for("/bank/audit"), NoFault);
+    assert_eq!(chaos.fault_for("/shop"), NoFault);
+    assert_eq!(chaos.counts().dropped, 1);
+
+    chaos.clear();
+    assert_eq!(chaos.fault_for("/bank/ledger"), NoFault);
+  }
+
+  // The faults of ten messages to /a, with messages to /b in between.
+  fn faults_of_a(seed: uint, interleaved: uint) -> Vec<Fault> {
+    let chaos = Chaos::new();
+    chaos.set_seed(seed);
+    chaos.inject("/", Faults { drop: 0.5, ..Faults::none() });
+    range(0u, 10).map(|_| {
+      for _ in range(0, interleaved) {
+        chaos.fault_for("/b");
+      }
+      chaos.fault_for("/a")
+    }).collect()
+  }
+
+  #[test]
+  fn injects_the_same_faults_into_an_actor_however_others_interleave() {
+    assert_eq!(faults_of_a(42, 0), faults_of_a(42, 0));
+    assert_eq!(faults_of_a(42, 0), faults_of_a(42, 3));
+    assert!(range(0u, 10).any(|seed| faults_of_a(seed, 0) != faults_of_a(42, 0)));
+  }
+
+  #[deriving(Clone)]
+  struct Work;
+  impl Message for Work {}
+
+  struct Worker;
+
+  impl Actor for Worker {
+    fn new() -> Worker {
+      Worker
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {}
+  }
+
+  // Starts a Worker, and keeps what it hears of its failures.
+  struct Supervisor {
+    worker: Option<Agent>,
+    failures: Vec<InjectedFailure>,
+    terminated: uint
+  }
+
+  impl Actor for Supervisor {
+    fn new() -> Supervisor {
+      Supervisor { worker: None, failures: Vec::new(), terminated: 0 }
+    }
+
+    fn pre_start(&mut self, context: &mut Context) {
+      let worker = context.start_child_name::<Worker>("worker".to_string());
+      worker.deliver(context.watch());
+      self.worker = Some(worker);
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {}
+
+    fn failed(&mut self, context: &mut Context, err: Box<Message>, failed: Agent) {
+      match err.as_ref::<InjectedFailure>() {
+        Some(failure) => self.failures.push(failure.clone()),
+        None => ()
+      }
+    }
+
+    fn terminated(&mut self, context: &mut Context, terminated: Agent) {
+      self.terminated += 1;
+    }
+  }
+
+  #[test]
+  fn stops_actors_failed_by_chaos_through_supervision() {
+    let mut stage = Stage::deterministic();
+    stage.chaos().inject("/supervisor/worker", Faults { fail: 1.0, ..Faults::none() });
+    let mut supervisor = TestActorRef::<Supervisor>::new(&mut stage, "supervisor".to_string());
+    stage.run_until_idle();
+    supervisor.run_mailbox();
+
+    let worker = supervisor.actor().worker.clone().unwrap();
+    worker.fire_and_forget(box Work);
+    worker.fire_and_forget(box Work);
+    stage.run_until_idle();
+    supervisor.run_mailbox();
+
+    assert_eq!(supervisor.actor().failures, vec!(InjectedFailure { path: worker.path() }));
+    assert_eq!(supervisor.actor().terminated, 1);
+    assert_eq!(stage.chaos().counts().failed, 1);
+  }
+
+  #[test]
+  fn injects_faults_into_messages_found_by_path() {
+    let mut stage = Stage::deterministic();
+    stage.chaos().inject("/supervisor/worker", Faults { fail: 1.0, ..Faults::none() });
+    let mut supervisor = TestActorRef::<Supervisor>::new(&mut stage, "supervisor".to_string());
+    stage.run_until_idle();
+    supervisor.run_mailbox();
+
+    let worker = supervisor.actor().worker.clone().unwrap();
+    worker.deliver(Find(Vec::new(), box Work, supervisor.agent()));
+    stage.run_until_idle();
+    supervisor.run_mailbox();
+
+    assert_eq!(supervisor.actor().failures, vec!(InjectedFailure { path: worker.path() }));
+    assert_eq!(supervisor.actor().terminated, 1);
+    assert_eq!(stage.chaos().counts().failed, 1);
+  }
+}
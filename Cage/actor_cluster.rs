@@ -0,0 +1,560 @@
+/*
+ * Cluster membership. Stages that listen for remote connections
+ * form a cluster by joining through seed Stages, ex.
+ *   stage.join_cluster(vec![seed.to_string()]);
+ * The first seed, joining itself, starts the cluster.
+ *
+ * Each Stage's view of the members lives in the cluster daemon at
+ * /system/cluster, and spreads by gossip: every gossip_interval the
+ * daemon sends its view to a random other member, which merges it
+ * into its own. A member's status only moves forward,
+ *   Joining -> Up -> Leaving -> Removed
+ * or, once the member can't be reached, to Down then Removed, so
+ * merging keeps the later status of each member. The leader (the
+ * live member with the lowest address) moves Joining members Up and
+ * Leaving and Down members to Removed.
+ *
+ * A member is one incarnation of a Stage: its address and the time
+ * its daemon started, or last joined. A Stage that restarts, or
+ * joins again after leaving, is a new member; the earlier
+ * incarnation at its address goes Down and is removed. Removed
+ * members are kept, so gossip from a Stage that hasn't heard of the
+ * removal can't bring them back.
+ *
+ * Each change a Stage sees is published on its EventStream as a
+ * MemberEvent. Send SubscribeCluster to the daemon to receive the
+ * current members, then every MemberEvent.
+ */
+use std::any::AnyRefExt;
+use std::cmp;
+use std::fmt;
+use std::intrinsics::TypeId;
+use std::rand;
+use std::rand::Rng;
+use time;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_context::Context;
+use actor_remote::RemoteAddress;
+
+// Path of the cluster daemon on every Stage.
+pub static CLUSTER_PATH: &'static str = "/system/cluster";
+
+// Milliseconds between rounds of gossip.
+pub static GOSSIP_INTERVAL: u64 = 1000;
+
+/*
+ * Membership.
+ */
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show, Encodable, Decodable)]
+pub enum MemberStatus {
+  Joining,
+  Up,
+  Leaving,
+  Down,
+  Removed
+}
+
+#[deriving(Clone, PartialEq, Encodable, Decodable)]
+pub struct Member {
+  pub address: RemoteAddress,
+  // Tells incarnations of the Stage at address apart; later ones
+  // are higher.
+  pub incarnation: u64,
+  pub status: MemberStatus
+}
+
+impl Member {
+  // Whether other is the same incarnation of the same Stage.
+  pub fn is(&self, other: &Member) -> bool {
+    self.address == other.address && self.incarnation == other.incarnation
+  }
+
+  // Whether the member takes part in gossip and leadership.
+  pub fn is_live(&self) -> bool {
+    match self.status {
+      Joining | Up | Leaving => true,
+      Down | Removed => false
+    }
+  }
+
+  fn daemon_uri(&self) -> String {
+    self.address.uri(CLUSTER_PATH)
+  }
+}
+
+impl fmt::Show for Member {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} #{} ({})", self.address, self.incarnation, self.status)
+  }
+}
+
+// The leader among the members: the live member with the lowest address.
+pub fn leader_of(members: &[Member]) -> Option<RemoteAddress> {
+  members.iter()
+         .filter(|m| m.is_live())
+         .min_by(|m| m.address.to_string())
+         .map(|m| m.address.clone())
+}
+
+/*
+ * Events published on each Stage's EventStream.
+ */
+
+// A member's status changed in this Stage's view of the cluster.
+#[deriving(Clone)]
+pub struct MemberEvent {
+  pub member: Member
+}
+impl Message for MemberEvent {}
+
+/*
+ * Messages handled by the cluster daemon.
+ */
+
+// Sent by Stage::join_cluster.
+#[deriving(Clone)]
+pub struct JoinCluster {
+  pub seeds: Vec<String>
+}
+impl Message for JoinCluster {}
+
+// Marks this Stage Leaving; the leader then removes it.
+#[deriving(Clone)]
+pub struct LeaveCluster;
+impl Message for LeaveCluster {}
+
+// Marks the member at the address Down, as if it could not be reached.
+#[deriving(Clone)]
+pub struct DownMember {
+  pub address: RemoteAddress
+}
+impl Message for DownMember {}
+
+// Asks for the members, answered with a CurrentClusterState.
+#[deriving(Clone)]
+pub struct GetClusterState;
+impl Message for GetClusterState {}
+
+// Subscribes the sender to MemberEvents, answered first with a
+// CurrentClusterState.
+#[deriving(Clone)]
+pub struct SubscribeCluster;
+impl Message for SubscribeCluster {}
+
+#[deriving(Clone)]
+pub struct UnsubscribeCluster;
+impl Message for UnsubscribeCluster {}
+
+#[deriving(Clone)]
+pub struct CurrentClusterState {
+  pub members: Vec<Member>,
+  pub leader: Option<RemoteAddress>
+}
+impl Message for CurrentClusterState {}
+
+/*
+ * Messages between cluster daemons.
+ */
+
+// Sent to seeds by a Stage joining the cluster.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Join {
+  pub address: RemoteAddress,
+  pub incarnation: u64
+}
+impl Message for Join {}
+
+// One daemon's view of the members.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Gossip {
+  pub members: Vec<Member>
+}
+impl Message for Gossip {}
+
+// Sent by the daemon to itself each gossip_interval.
+#[deriving(Clone)]
+struct GossipTick;
+impl Message for GossipTick {}
+
+/*
+ * The daemon itself.
+ */
+pub struct ClusterDaemon {
+  address: Option<RemoteAddress>,
+  incarnation: u64,
+  seeds: Vec<String>,
+  members: Vec<Member>,
+  ticking: bool
+}
+
+// A new incarnation: the time now, in nanoseconds.
+fn new_incarnation() -> u64 {
+  let now = time::get_time();
+  now.sec as u64 * 1000000000 + now.nsec as u64
+}
+
+impl ClusterDaemon {
+  fn find(&self, member: &Member) -> Option<uint> {
+    self.members.iter().position(|m| m.is(member))
+  }
+
+  // This Stage as a member, with the given status.
+  fn me(&self, status: MemberStatus) -> Option<Member> {
+    self.address.clone().map(|address| {
+      Member { address: address, incarnation: self.incarnation, status: status }
+    })
+  }
+
+  fn member_status(&self) -> Option<MemberStatus> {
+    match self.me(Joining) {
+      Some(me) => self.find(&me).map(|i| self.members[i].status),
+      None => None
+    }
+  }
+
+  // Marks every live incarnation of the Stage at address Down.
+  fn down(&mut self, context: &mut Context, address: &RemoteAddress, before: Option<u64>) {
+    let lost: Vec<Member> = self.members.iter().filter(|m| {
+      m.address == *address && m.is_live() && before.map_or(true, |i| m.incarnation < i)
+    }).map(|m| Member { status: Down, ..m.clone() }).collect();
+    for member in lost.move_iter() {
+      self.update(context, member);
+    }
+  }
+
+  fn leader(&self) -> Option<RemoteAddress> {
+    leader_of(self.members.as_slice())
+  }
+
+  // Moves the member to status if that is later than its current
+  // status, adding it if unknown, and publishes the change.
+  fn update(&mut self, context: &mut Context, member: Member) {
+    let mut member = member;
+    match self.find(&member) {
+      Some(i) => {
+        if member.status <= self.members[i].status {
+          return;
+        }
+        self.members.get_mut(i).status = member.status;
+      },
+      None => {
+        // A later incarnation has replaced this one already.
+        let replaced = self.members.iter().any(|m| {
+          m.address == member.address && m.incarnation > member.incarnation
+        });
+        if replaced {
+          member.status = cmp::max(member.status, Down);
+        }
+        // Watching a new member's daemon marks it Down if it stops
+        // or its Stage can no longer be reached.
+        if member.is_live() && Some(member.address.clone()) != self.address {
+          match context.remote_agent(member.daemon_uri().as_slice()) {
+            Some(daemon) => daemon.deliver(context.watch()),
+            None => ()
+          }
+        }
+        self.members.push(member.clone());
+      }
+    }
+    context.log().info(format!("member {}", member).as_slice());
+    context.publish(box MemberEvent { member: member.clone() });
+    if member.is_live() {
+      self.down(context, &member.address, Some(member.incarnation));
+    }
+  }
+
+  fn gossip(&self, context: &mut Context) {
+    let others: Vec<&Member> = self.members.iter()
+                                           .filter(|m| m.is_live() && Some(m.address.clone()) != self.address)
+                                           .collect();
+    if others.is_empty() {
+      return;
+    }
+    let other = others[rand::task_rng().gen_range(0, others.len())];
+    match context.remote_agent(other.daemon_uri().as_slice()) {
+      Some(daemon) => daemon.deliver(context.send(box Gossip { members: self.members.clone() })),
+      None => ()
+    }
+  }
+
+  // What the leader does each round.
+  fn lead(&mut self, context: &mut Context) {
+    if self.leader() != self.address {
+      return;
+    }
+    let changes: Vec<Member> = self.members.iter().filter_map(|m| {
+      let next = match m.status {
+        Joining => Up,
+        Leaving | Down => Removed,
+        _ => return None
+      };
+      Some(Member { status: next, ..m.clone() })
+    }).collect();
+    for member in changes.move_iter() {
+      self.update(context, member);
+    }
+  }
+
+  // Asks each seed to let this Stage in, until one does.
+  fn join(&self, context: &mut Context) {
+    let address = match self.address {
+      Some(ref address) => address.clone(),
+      None => return
+    };
+    for seed in self.seeds.iter() {
+      let join = box Join { address: address.clone(), incarnation: self.incarnation };
+      match context.remote_agent(seed.clone().append(CLUSTER_PATH).as_slice()) {
+        Some(daemon) => daemon.deliver(context.send(join)),
+        None => context.log().warn(format!("malformed seed address {}", seed).as_slice())
+      }
+    }
+  }
+
+  fn tick(&mut self, context: &mut Context) {
+    match self.member_status() {
+      None => self.join(context),
+      Some(Removed) => {
+        // This Stage has left; gossip stops.
+        self.ticking = false;
+        return;
+      },
+      Some(_) => {
+        self.lead(context);
+        self.gossip(context);
+      }
+    }
+    context.schedule_once(GOSSIP_INTERVAL, &context.agent(), box GossipTick);
+  }
+
+  fn state(&self) -> CurrentClusterState {
+    CurrentClusterState { members: self.members.clone(), leader: self.leader() }
+  }
+}
+
+impl Actor for ClusterDaemon {
+  fn new() -> ClusterDaemon {
+    ClusterDaemon {
+      address: None,
+      incarnation: new_incarnation(),
+      seeds: Vec::new(),
+      members: Vec::new(),
+      ticking: false
+    }
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    if msg.is::<JoinCluster>() {
+      self.address = context.remoting().address();
+      let address = match self.address {
+        Some(ref address) => address.clone(),
+        None => {
+          context.log().error("a Stage must listen before joining a cluster");
+          return;
+        }
+      };
+      self.seeds = msg.as_ref::<JoinCluster>().unwrap().seeds.clone();
+      match self.member_status() {
+        // Joining again after leaving, this Stage is a new member.
+        Some(Down) | Some(Removed) => self.incarnation = new_incarnation(),
+        _ => ()
+      }
+      if self.seeds.iter().next() == Some(&address.to_string()) {
+        // The first seed starts the cluster by itself.
+        let me = self.me(Up).unwrap();
+        self.update(context, me);
+      }
+      if !self.ticking {
+        self.ticking = true;
+        self.tick(context);
+      }
+    } else if msg.is::<Join>() {
+      let join = msg.as_ref::<Join>().unwrap().clone();
+      if self.member_status().is_some() {
+        self.update(context, Member {
+          address: join.address,
+          incarnation: join.incarnation,
+          status: Joining
+        });
+        sender.deliver(context.send(box Gossip { members: self.members.clone() }));
+      }
+    } else if msg.is::<Gossip>() {
+      for member in msg.as_ref::<Gossip>().unwrap().members.iter() {
+        self.update(context, member.clone());
+      }
+    } else if msg.is::<GossipTick>() {
+      self.tick(context);
+    } else if msg.is::<LeaveCluster>() {
+      match self.me(Leaving) {
+        Some(me) => self.update(context, me),
+        None => ()
+      }
+    } else if msg.is::<DownMember>() {
+      let address = msg.as_ref::<DownMember>().unwrap().address.clone();
+      self.down(context, &address, None);
+    } else if msg.is::<GetClusterState>() {
+      sender.deliver(context.send(box self.state()));
+    } else if msg.is::<SubscribeCluster>() {
+      context.event_stream().subscribe(&sender, TypeId::of::<MemberEvent>());
+      sender.deliver(context.send(box self.state()));
+    } else if msg.is::<UnsubscribeCluster>() {
+      context.event_stream().unsubscribe(&sender, TypeId::of::<MemberEvent>());
+    }
+  }
+
+  // A member's daemon stopped, or its Stage can't be reached.
+  fn terminated(&mut self,
+                context: &mut Context,
+                terminated: Agent) {
+    let lost = self.members.iter()
+                           .find(|m| m.daemon_uri() == terminated.path())
+                           .map(|m| m.address.clone());
+    match lost {
+      Some(address) => self.down(context, &address, None),
+      None => ()
+    }
+  }
+
+  // Gossip crosses Stages, so its messages are serialized.
+  fn pre_start(&mut self, context: &mut Context) {
+    context.serialization().register_json::<Join>("cage.cluster.join");
+    context.serialization().register_json::<Gossip>("cage.cluster.gossip");
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use actor_remote::RemoteAddress;
+  use actor_stage::Stage;
+  use actor_testkit::TestActorRef;
+  use actor_testkit::TestProbe;
+  use super::ClusterDaemon;
+  use super::CurrentClusterState;
+  use super::Gossip;
+  use super::GossipTick;
+  use super::Join;
+  use super::Member;
+  use super::MemberEvent;
+  use super::MemberStatus;
+    use super::Joining;
+    use super::Leaving;
+    use super::Up;
+    use super::Down;
+    use super::Removed;
+  use super::SubscribeCluster;
+
+  // A listening Stage standing in for another member, so that the
+  // daemon under test watching its daemon reaches a real port. Its
+  // system sorts after the daemon's, which so leads.
+  fn peer() -> (Stage, RemoteAddress) {
+    let stage = Stage::new();
+    let address = stage.listen("peer", "127.0.0.1", 0).unwrap();
+    (stage, address)
+  }
+
+  fn member(address: &RemoteAddress, incarnation: u64, status: MemberStatus) -> Member {
+    Member { address: address.clone(), incarnation: incarnation, status: status }
+  }
+
+  // The daemon of a listening, Up Stage, incarnation 1.
+  fn daemon(stage: &mut Stage) -> TestActorRef<ClusterDaemon> {
+    let address = stage.listen("leader", "127.0.0.1", 0).unwrap();
+    let mut daemon = TestActorRef::<ClusterDaemon>::new(stage, "cluster".to_string());
+    daemon.actor().address = Some(address.clone());
+    daemon.actor().incarnation = 1;
+    daemon.actor().members = vec!(member(&address, 1, Up));
+    daemon
+  }
+
+  fn status_of(daemon: &mut TestActorRef<ClusterDaemon>,
+               address: &RemoteAddress,
+               incarnation: u64) -> Option<MemberStatus> {
+    let wanted = member(address, incarnation, Joining);
+    daemon.actor().members.iter().find(|m| m.is(&wanted)).map(|m| m.status)
+  }
+
+  // Waits, on the probe subscribed to a Stage's MemberEvents, until
+  // each member has had its status, in any order.
+  fn expect_statuses(probe: &mut TestProbe, statuses: Vec<(RemoteAddress, MemberStatus)>) {
+    let mut waiting = statuses;
+    for _ in range(0u, 100) {
+      if waiting.is_empty() {
+        return;
+      }
+      let event = probe.expect_msg::<MemberEvent>(10000);
+      waiting.retain(|&(ref address, status)| {
+        !(event.member.address == *address && event.member.status == status)
+      });
+    }
+    fail!("members never reached {}", waiting);
+  }
+
+  #[test]
+  fn only_moves_members_forward() {
+    let (_peer, other) = peer();
+    let mut stage = Stage::deterministic();
+    let mut daemon = daemon(&mut stage);
+    daemon.receive(box Gossip { members: vec!(member(&other, 1, Leaving)) });
+    daemon.receive(box Gossip { members: vec!(member(&other, 1, Up)) });
+    assert_eq!(status_of(&mut daemon, &other, 1), Some(Leaving));
+  }
+
+  #[test]
+  fn lets_a_restarted_stage_join_again() {
+    let (_peer, other) = peer();
+    let mut stage = Stage::deterministic();
+    let mut daemon = daemon(&mut stage);
+    daemon.receive(box Gossip { members: vec!(member(&other, 1, Up)) });
+
+    daemon.receive(box Join { address: other.clone(), incarnation: 2 });
+    assert_eq!(status_of(&mut daemon, &other, 2), Some(Joining));
+    assert_eq!(status_of(&mut daemon, &other, 1), Some(Down));
+
+    // The leader moves both on.
+    daemon.receive(box GossipTick);
+    assert_eq!(status_of(&mut daemon, &other, 2), Some(Up));
+    assert_eq!(status_of(&mut daemon, &other, 1), Some(Removed));
+
+    // Gossip from a Stage that missed the restart doesn't revive the
+    // earlier incarnation.
+    daemon.receive(box Gossip { members: vec!(member(&other, 1, Up)) });
+    assert_eq!(status_of(&mut daemon, &other, 1), Some(Removed));
+    assert_eq!(status_of(&mut daemon, &other, 2), Some(Up));
+  }
+
+  #[test]
+  fn takes_stale_incarnations_as_down() {
+    let (_peer, other) = peer();
+    let mut stage = Stage::deterministic();
+    let mut daemon = daemon(&mut stage);
+    daemon.receive(box Gossip { members: vec!(member(&other, 2, Up), member(&other, 1, Up)) });
+    assert_eq!(status_of(&mut daemon, &other, 2), Some(Up));
+    assert_eq!(status_of(&mut daemon, &other, 1), Some(Down));
+  }
+
+  #[test]
+  fn joins_stages_through_a_seed_and_removes_those_that_leave() {
+    let stages = vec![Stage::new(), Stage::new(), Stage::new()];
+    let addresses: Vec<RemoteAddress> = stages.iter().map(|stage| {
+      stage.listen("cluster", "127.0.0.1", 0).unwrap()
+    }).collect();
+
+    let mut probe = TestProbe::new(&stages[0]);
+    probe.send(&stages[0].cluster(), box SubscribeCluster);
+    assert!(probe.expect_msg::<CurrentClusterState>(5000).members.is_empty());
+
+    let seeds = vec![addresses[0].to_string()];
+    for stage in stages.iter() {
+      stage.join_cluster(seeds.clone());
+    }
+    expect_statuses(&mut probe, addresses.iter().map(|address| (address.clone(), Up)).collect());
+
+    stages[2].leave_cluster();
+    expect_statuses(&mut probe, vec![(addresses[2].clone(), Removed)]);
+  }
+}
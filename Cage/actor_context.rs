@@ -3,8 +3,14 @@
  * Cage system. They create Actors, track the Actor's parent
  * and children, and format messages.
  */
+use std::any::AnyRefExt;
 use std::rand;
 use std::rand::Rng;
+use std::task;
+use log;
+use sync::Arc;
+use sync::Mutex;
+use time;
 
 use actor::Actor;
 use actor::Message;
@@ -12,7 +18,55 @@ use actor_agent::Agent;
 use actor_agent::NAME_LENGTH;
 use actor_agent::NO_ADDRESS;
 use actor_agent::ROOT_ADDRESS;
+use actor_agent::SYSTEM_ADDRESS;
+use actor_agent::SYSTEM_NAME;
+use actor_agent::DEAD_LETTERS_NAME;
+use actor_agent::LOG_NAME;
+use actor_agent::CLUSTER_NAME;
+use actor_cluster::ClusterDaemon;
+use actor_cluster::CLUSTER_PATH;
+use actor_chaos::Chaos;
+use actor_chaos::InjectedFailure;
+  use actor_chaos::NoFault;
+  use actor_chaos::Dropped;
+  use actor_chaos::Duplicated;
+  use actor_chaos::Delayed;
+  use actor_chaos::Reordered;
+  use actor_chaos::Failed;
+use actor_dead_letters::DeadLetterOffice;
+  use actor_dead_letters::RecipientStopped;
+  use actor_dead_letters::PathNotFound;
+use actor_dispatcher::Dispatcher;
+use actor_dispatcher::Runnable;
+use actor_dispatcher::Step;
+  use actor_dispatcher::Handled;
+  use actor_dispatcher::Idle;
+  use actor_dispatcher::Stopped;
+use actor_event_stream::EventStream;
+use actor_event_stream::ActorStarted;
+use actor_event_stream::ActorStopped;
+use actor_log::Logger;
+use actor_metrics::FailureLog;
+use actor_log::LogActor;
+use actor_log::LogFilter;
+use actor_persistence::Persistence;
+use actor_registry::Registry;
+use actor_remote::Remoting;
+use actor_serialization::Serialization;
+use actor_group_router::GroupRouter;
+use actor_group_router::GroupConfig;
+use actor_router::Router;
+use actor_router::Routing;
+use actor_router::PoolConfig;
+use actor_router::Resizer;
+use actor_stage::SystemGuardian;
+use actor_trace::TraceContext;
+use actor_trace::Tracer;
+use actor_tree;
+use actor_tree::Running;
+use actor_tree::Stopping;
 use cage_message::CageMessage;
+use cage_message::Envelope;
   use cage_message::UserMessage;
   use cage_message::Find;
   use cage_message::Terminated;
@@ -22,12 +76,54 @@ use cage_message::CageMessage;
   use cage_message::Unwatch;
   use cage_message::Kill;
 
+// Sent with find to learn the Agents at a path (which may contain *),
+// each answering with an ActorIdentity.
+#[deriving(Clone)]
+pub struct Identify;
+impl Message for Identify {}
+
+#[deriving(Clone)]
+pub struct ActorIdentity {
+  pub agent: Agent
+}
+impl Message for ActorIdentity {}
+
+// The error in the Failure an Actor's parent receives when the
+// Actor's task fails while handling a message.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct TaskFailed {
+  pub path: String
+}
+impl Message for TaskFailed {}
+
+// What a Find reaching a * passes to each child, given the rest of the
+// path. A trailing * hands the message to the children themselves,
+// except Identify, which each child answers through Find. Segments
+// after a * go on being looked up beneath each child.
+pub fn wildcard(rest: &Vec<String>, msg: Box<Message:Send>, sender: Agent) -> CageMessage {
+  if rest.is_empty() && !msg.is::<Identify>() {
+    UserMessage(msg, sender)
+  } else {
+    Find(rest.clone(), msg, sender)
+  }
+}
+
 #[deriving(Clone)]
 pub struct Context {
   agent: Agent,
   parent: Agent,
   children: Vec<Agent>,
-  root: Agent
+  root: Agent,
+  event_stream: EventStream,
+  log: Logger,
+  persistence: Persistence,
+  registry: Registry,
+  remoting: Remoting,
+  serialization: Serialization,
+  dispatcher: Dispatcher,
+  chaos: Chaos,
+  tracer: Tracer,
+  failures: FailureLog
 }
 
 impl Context {
@@ -68,6 +164,11 @@ impl Context {
             self.parent.deliver(
               Find(sendable_path, msg, self.agent.clone())
             ),
+          // A leading / starts the search from the root.
+          "" =>
+            self.root.deliver(
+              Find(sendable_path, msg, self.agent.clone())
+            ),
           _ => {
             sendable_path.push(s);
             self.root.deliver(
@@ -79,12 +180,30 @@ impl Context {
     }
   }
 
+  // Delivers the message to target after delay milliseconds, as if
+  // sent by this Actor then.
+  pub fn schedule_once(&self, delay: u64, target: &Agent, msg: Box<Message:Send>) {
+    self.dispatcher.schedule_once(delay, target.clone(), self.send(msg));
+  }
+
+  // Publishes an event on the Stage's EventStream, to be delivered
+  // to every Actor subscribed to its type.
+  pub fn publish(&self, event: Box<Message:Send>) {
+    self.event_stream.publish(event, &self.agent);
+  }
+
   // Formats a message that will tell the receiving Actor that a
   // failure occurred while consuming the message.
   pub fn failure(&self, err: Box<Message:Send>) -> CageMessage {
     Failure(err, self.agent.clone())
   }
 
+  // Counts the failure of failed with err, as a Failure is handled.
+  pub fn count_failure(&self, err: &Message, failed: &Agent) {
+    failed.failed();
+    self.failures.record(failed, self.serialization.describe(err));
+  }
+
   // Formats a message that will tell the receiving Actor to inform
   // this Actor of its death.
   pub fn watch(&self) -> CageMessage {
@@ -118,6 +237,59 @@ impl Context {
   pub fn children(&self) -> Vec<Agent> {
     self.children.clone()
   }
+  // Returns an Agent to the dead letter office at /system/deadLetters.
+  pub fn dead_letters(&self) -> Agent {
+    self.agent.dead_letters().expect("Context created without a dead letter office.")
+  }
+  // Returns the Stage-wide EventStream.
+  pub fn event_stream(&self) -> EventStream {
+    self.event_stream.clone()
+  }
+  // Returns a Logger that records under this Actor's path.
+  pub fn log(&self) -> Logger {
+    self.log.clone()
+  }
+  // Returns the Stage-wide persistence plugins.
+  pub fn persistence(&self) -> Persistence {
+    self.persistence.clone()
+  }
+  // Returns the Stage's Remoting.
+  pub fn remoting(&self) -> Remoting {
+    self.remoting.clone()
+  }
+  // Returns an Agent to the cluster daemon at /system/cluster.
+  pub fn cluster(&self) -> Agent {
+    self.registry.lookup(CLUSTER_PATH).expect("Context created without a cluster daemon.")
+  }
+  // Returns the Stage's message Serialization.
+  pub fn serialization(&self) -> Serialization {
+    self.serialization.clone()
+  }
+  // Returns the Stage's Tracer.
+  pub fn tracer(&self) -> Tracer {
+    self.tracer.clone()
+  }
+  // Returns the Registry of the Stage's running Actors.
+  pub fn registry(&self) -> Registry {
+    self.registry.clone()
+  }
+  // Returns the Dispatcher the Stage's Actors run on.
+  pub fn dispatcher(&self) -> Dispatcher {
+    self.dispatcher.clone()
+  }
+  // Returns the Stage's fault injector.
+  pub fn chaos(&self) -> Chaos {
+    self.chaos.clone()
+  }
+  // Returns the Stage's record of recent failures.
+  pub fn failures(&self) -> FailureLog {
+    self.failures.clone()
+  }
+  // Returns an Agent for the Actor at a cage://system@host:port/path
+  // address, or None if the address is malformed.
+  pub fn remote_agent(&self, uri: &str) -> Option<Agent> {
+    self.remoting.agent_for(uri)
+  }
 
   /*
    * Spins off a task for the passed Actor and places it
@@ -130,10 +302,47 @@ impl Context {
     self.start_child_name::<T>(name)
   }
 
+  // Starts a Router as a child of this Actor, which starts size
+  // routees of type T as its own children and distributes messages
+  // to them by the given Routing.
+  pub fn start_router<T: Actor>(&mut self, routing: Routing, size: uint) -> Agent {
+    self.start_pool::<T>(PoolConfig { routing: routing, size: size, resizer: None, init: None })
+  }
+
+  // Starts a Router whose pool of routees of type T grows and shrinks
+  // with load, within the bounds of the Resizer.
+  pub fn start_resizable_router<T: Actor>(&mut self, routing: Routing, resizer: Resizer) -> Agent {
+    self.start_pool::<T>(PoolConfig {
+      routing: routing,
+      size: resizer.lower_bound,
+      resizer: Some(resizer),
+      init: None
+    })
+  }
+
+  // Starts a Router over a pool of routees of type T as configured.
+  // Every Actor in the Cage system is made by its type's Actor::new,
+  // so the type is all a pool needs to start routees; settings they
+  // share go in config.init, which the Router sends to every routee
+  // it starts, replacements and routees the Resizer adds included.
+  pub fn start_pool<T: Actor>(&mut self, config: PoolConfig) -> Agent {
+    let router = self.start_child::<Router<T>>();
+    router.deliver(self.send(box config));
+    router
+  }
+
+  // Starts a Router as a child of this Actor over the existing Actors
+  // at the given absolute paths (which may contain *).
+  pub fn start_group_router(&mut self, routing: Routing, paths: Vec<String>) -> Agent {
+    let router = self.start_child::<GroupRouter>();
+    router.deliver(self.send(box GroupConfig { routing: routing, paths: paths }));
+    router
+  }
+
   // Mends Contexts to reflect the child Actor with the given name.
   pub fn start_child_name<T: Actor>(&mut self, name: String) -> Agent {
     // Creation of the Context.
-    let (send, recv) = channel::<CageMessage>();
+    let (send, recv) = channel::<Envelope>();
     let context = self.child(send, name);
 
     // Get the child's Agent.
@@ -141,6 +350,7 @@ impl Context {
 
     // Push the child's Agent onto this Actor's child list.
     self.children.push(agent.clone());
+    self.registry.register(&agent);
 
     // Consume the Receiver and Context to spawn the child.
     Context::spawn_child::<T>(recv, context);
@@ -149,93 +359,54 @@ impl Context {
     agent
    }
   
+  // Kills the child and forgets it, so it is no longer among this
+  // Actor's children.
+  pub fn stop_child(&mut self, child: &Agent) {
+    self.children.retain(|c| c != child);
+    child.deliver(self.kill());
+  }
+
+  // Places an Actor of type T under this one without running it, so
+  // a TestActorRef can drive it in the test's task.
+  pub fn inline_child<T: Actor>(&mut self, name: String) -> ActorCell<T> {
+    let (send, recv) = channel::<Envelope>();
+    let context = self.child(send, name);
+    let agent = context.agent();
+    self.children.push(agent.clone());
+    self.registry.register(&agent);
+    ActorCell::new(recv, context)
+  }
+
   // Used to construct a child Context from a parent.
-  fn child(&self, sender: Sender<CageMessage>, name: String) -> Context {
+  fn child(&self, sender: Sender<Envelope>, name: String) -> Context {
+    self.child_with(self.agent.child(sender, name))
+  }
+
+  // Used to construct a child Context around an existing Agent.
+  fn child_with(&self, agent: Agent) -> Context {
     Context {
-      agent: Agent::new(sender, self.agent.path(), name), 
+      log: self.log.for_path(agent.path()),
+      agent: agent,
       parent: self.agent.clone(),
       children: Vec::new(),
-      root: self.root.clone()
+      root: self.root.clone(),
+      event_stream: self.event_stream.clone(),
+      persistence: self.persistence.clone(),
+      registry: self.registry.clone(),
+      remoting: self.remoting.clone(),
+      serialization: self.serialization.clone(),
+      dispatcher: self.dispatcher.clone(),
+      chaos: self.chaos.clone(),
+      tracer: self.tracer.clone(),
+      failures: self.failures.clone()
     }
   }
 
-  // The magnificent function that runs an Actor.
-  fn spawn_child<T: Actor>(recv: Receiver<CageMessage>, context: Context) {
-    spawn(proc() {
-      // Creation of the user Actor.
-      let mut actor: T = Actor::new();
-
-      // Mutable capture of Context.
-      let mut context = context;
-
-      // List of Agents watching for death.
-      let mut watchers = Vec::new();
-
-      // User Actor setup.
-      actor.pre_start(&mut context);
-
-      // Receive messages and dispatch to user Actor.
-      loop {
-        match recv.recv() {
-          UserMessage(msg, sender) => actor.receive(&mut context, msg, sender),
-          Find(path, msg, sender) => {
-            let mut _path = path;
-            match _path.pop() {
-              None => actor.receive(&mut context, msg, sender),
-              Some(ref s) =>
-                match s.as_slice() {
-                  "*" => { 
-                    for child in context.children.iter() {
-                      child.deliver(UserMessage(msg.clone_me(), sender.clone()));
-                    }  
-                  },
-                  ".." => context.parent.deliver(Find(_path, msg, sender)),
-                  _ => {
-                    let mut found = false;
-                    for child in context.children.iter() {
-                      if child.name() == *s {
-                        child.deliver(Find(_path, msg.clone_me(), sender.clone()));
-                        found = true;
-                        break;
-                      }
-                    }
-                    if !found {
-                      sender.deliver(Undelivered(context.agent.clone(), msg));
-                    }
-                  }
-                }
-            }
-          },
-          Terminated(terminated) => actor.terminated(&mut context, terminated),
-          Failure(err, failed) => actor.failed(&mut context, err, failed),
-          Undelivered(attempted, orig_msg) => actor.undelivered(&mut context, attempted, orig_msg),
-          Watch(watcher) => watchers.push(watcher),
-          Unwatch(unwatcher) => Context::remove_unwatcher(&mut watchers, unwatcher),
-          Kill(killer) => {
-            // Drain and consume the receiver.
-            Context::drain_recv(recv, &context);
-
-            // Notify user Actor that it has been killed.
-            actor.killed(&mut context, killer);
-            
-            // Continue cleanup.
-            break;
-          }
-        }
-      }
-    
-      // Notify watchers of this Actor's death.
-      for watcher in watchers.move_iter() {
-        watcher.deliver(Terminated(context.agent.clone()));
-      }
-  
-      // Reap this Actor's children.
-      for child in context.children().iter() {
-        child.deliver(Kill(context.agent()));
-      }  
-
-      // User Actor cleanup.
-      actor.post_stop(&mut context);    
+  // Runs an Actor on the Stage's Dispatcher.
+  fn spawn_child<T: Actor>(recv: Receiver<Envelope>, context: Context) {
+    let dispatcher = context.dispatcher.clone();
+    dispatcher.run(proc() {
+      box ActorCell::<T>::new(recv, context) as Box<Runnable>
     });
   }
 
@@ -256,38 +427,369 @@ impl Context {
   }
 
   // Drain the remaining messages from the Receiver, sending Undelivered and Terminated.
-  fn drain_recv(recv: Receiver<CageMessage>, context: &Context) {
+  fn drain_recv(recv: &Receiver<Envelope>, context: &Context) {
     loop {
       match recv.try_recv() {
-        Ok(cage_msg) =>
-          match cage_msg {
-            UserMessage(orig, sender) => sender.deliver(
-              Undelivered(context.agent.clone(), orig)
-            ),
-            Find(_, orig, sender) => sender.deliver(
-              Undelivered(context.agent.clone(), orig)
-            ),
+        Ok(envelope) =>
+          match envelope.msg {
+            UserMessage(orig, sender) => {
+              context.agent.dead_letter(orig.clone_me(), &context.agent, &sender, RecipientStopped);
+              sender.deliver(Undelivered(context.agent.clone(), orig))
+            },
+            Find(_, orig, sender) => {
+              context.agent.dead_letter(orig.clone_me(), &context.agent, &sender, RecipientStopped);
+              sender.deliver(Undelivered(context.agent.clone(), orig))
+            },
             Watch(watcher) => watcher.deliver(
               Terminated(context.agent.clone())
             ),
             _ => ()
           },
-        Err(_) => ()
+        Err(_) => break
       }
     }
   }
 
+  // Though publicly visible, the user can't use this due to the type of recv.
+  // Used by the Stage to start /system and the system Actors under it,
+  // with the dead letter office and logging Actor consuming the given Receivers.
+  pub fn start_system(&mut self,
+                      dead_letters: Receiver<Envelope>,
+                      log: Receiver<Envelope>) -> Agent {
+    // Creation of the /system Context.
+    let (send, recv) = channel::<Envelope>();
+    let mut system = self.child(send, SYSTEM_NAME.to_string());
+
+    // System Actors reuse the Senders every Context already holds.
+    let office = system.child_with(self.dead_letters());
+    system.children.push(office.agent());
+    self.registry.register(&office.agent());
+    Context::spawn_child::<DeadLetterOffice>(dead_letters, office);
+
+    let logger = system.child_with(self.log.agent());
+    system.children.push(logger.agent());
+    self.registry.register(&logger.agent());
+    Context::spawn_child::<LogActor>(log, logger);
+
+    system.start_child_name::<ClusterDaemon>(CLUSTER_NAME.to_string());
+
+    // Place /system under the root.
+    let agent = system.agent();
+    self.children.push(agent.clone());
+    self.registry.register(&agent);
+    Context::spawn_child::<SystemGuardian>(recv, system);
+    agent
+  }
+
   // Though publicly visible, the user can't use this due to the type of sender.
   // Used to construct a new Context for the root.
-  pub fn root(sender: Sender<CageMessage>, parent: Agent) -> Context {
-    let root_agent = Agent::new(sender,
-                                NO_ADDRESS.to_string(),
-                                ROOT_ADDRESS.to_string());
+  pub fn root(sender: Sender<Envelope>,
+              parent: Agent,
+              dead_letters: Sender<Envelope>,
+              log: Sender<Envelope>,
+              dispatcher: Dispatcher) -> Context {
+    let office = Agent::new(dead_letters,
+                            SYSTEM_ADDRESS.to_string().append("/"),
+                            DEAD_LETTERS_NAME.to_string());
+    let root_agent = Agent::with_dead_letters(sender,
+                                              NO_ADDRESS.to_string(),
+                                              ROOT_ADDRESS.to_string(),
+                                              Some(office.clone()));
+    let log_agent = Agent::with_dead_letters(log,
+                                             SYSTEM_ADDRESS.to_string().append("/"),
+                                             LOG_NAME.to_string(),
+                                             Some(office.clone()));
+    let log = Logger::new(root_agent.path(), log_agent, LogFilter::new());
+    let registry = Registry::new();
+    registry.register(&root_agent);
+    let serialization = Serialization::new();
+    // Failures are recorded as their serialized form.
+    serialization.register_json::<TaskFailed>("cage.task_failed");
+    let remoting = Remoting::new(registry.clone(),
+                                 serialization.clone(),
+                                 office,
+                                 log.for_path(SYSTEM_ADDRESS.to_string().append("/remote")));
     Context {  
       agent: root_agent.clone(),
       parent: parent,
       children: Vec::new(),
-      root: root_agent.clone()
+      root: root_agent.clone(),
+      event_stream: EventStream::new(),
+      log: log,
+      persistence: Persistence::new(),
+      registry: registry,
+      remoting: remoting,
+      serialization: serialization,
+      dispatcher: dispatcher,
+      chaos: Chaos::new(),
+      tracer: Tracer::new(),
+      failures: FailureLog::new()
     }
   }
 }
+
+/*
+ * The magnificent struct that runs an Actor: the user Actor with its
+ * Context, mailbox and watchers, stepped one message at a time by
+ * the Dispatcher.
+ */
+pub struct ActorCell<T> {
+  actor: T,
+  context: Context,
+  recv: Receiver<Envelope>,
+  // List of Agents watching for death, shared with the guard that
+  // tells them if the task fails.
+  watchers: Arc<Mutex<Vec<Agent>>>,
+  started: bool
+}
+
+impl<T: Actor> ActorCell<T> {
+  fn new(recv: Receiver<Envelope>, context: Context) -> ActorCell<T> {
+    // Creation of the user Actor.
+    let actor: T = Actor::new();
+    context.agent.set_type_name(actor.type_name());
+    ActorCell {
+      actor: actor,
+      context: context,
+      recv: recv,
+      watchers: Arc::new(Mutex::new(Vec::new())),
+      started: false
+    }
+  }
+
+  // Returns the user Actor, ex. for a test to inspect its state.
+  pub fn actor<'a>(&'a mut self) -> &'a mut T {
+    &mut self.actor
+  }
+
+  pub fn context<'a>(&'a mut self) -> &'a mut Context {
+    &mut self.context
+  }
+
+  // Installs the task-wide services for the Actor. A deterministic
+  // Dispatcher runs every Actor in one task, so this happens before
+  // each of their steps.
+  pub fn enter(&self) {
+    // Route the log crate's macros to the logging Actor.
+    log::set_logger(box self.context.log());
+
+    // Let events and other stored messages find the Serialization.
+    self.context.serialization.set_current();
+  }
+
+  // User Actor setup.
+  fn start(&mut self) {
+    self.actor.pre_start(&mut self.context);
+    self.context.agent.set_state(Running);
+    self.context.publish(box ActorStarted { agent: self.context.agent() });
+  }
+
+  // Handles the message in the current task, in a span following
+  // on from trace, cleaning up if it killed the Actor.
+  pub fn process(&mut self, cage_msg: CageMessage, trace: Option<TraceContext>) -> Step {
+    let _failing = Failing {
+      agent: self.context.agent.clone(),
+      parent: self.context.parent.clone(),
+      watchers: self.watchers.clone(),
+      registry: self.context.registry.clone(),
+      event_stream: self.context.event_stream.clone()
+    };
+    let span = self.context.tracer.enter(self.context.agent.path().as_slice(), &cage_msg, trace);
+    let started = time::precise_time_ns();
+    self.context.agent.set_handling(true);
+    let step = if self.handle(cage_msg) {
+      Handled
+    } else {
+      self.stop();
+      Stopped
+    };
+    self.context.agent.set_handling(false);
+    self.context.agent.received_in(time::precise_time_ns() - started);
+    self.context.tracer.exit(span);
+    step
+  }
+
+  // Dispatches the message to the user Actor, returning false once
+  // the Actor has been killed.
+  fn handle(&mut self, cage_msg: CageMessage) -> bool {
+    let context = &mut self.context;
+    match cage_msg {
+      UserMessage(msg, sender) =>
+        return ActorCell::receive(&mut self.actor, context, &self.recv, msg, sender,
+                                  |msg, sender| UserMessage(msg, sender)),
+      Find(path, msg, sender) => {
+        let mut _path = path;
+        match _path.pop() {
+          // Identify is answered by the Cage system on the Actor's behalf.
+          None if msg.is::<Identify>() =>
+            sender.deliver(context.send(box ActorIdentity { agent: context.agent() })),
+          None =>
+            return ActorCell::receive(&mut self.actor, context, &self.recv, msg, sender,
+                                      |msg, sender| Find(Vec::new(), msg, sender)),
+          Some(ref s) =>
+            match s.as_slice() {
+              "*" => {
+                for child in context.children.iter() {
+                  child.deliver(wildcard(&_path, msg.clone_me(), sender.clone()));
+                }
+              },
+              ".." => context.parent.deliver(Find(_path, msg, sender)),
+              _ => {
+                let mut found = false;
+                for child in context.children.iter() {
+                  if child.name() == *s {
+                    child.deliver(Find(_path, msg.clone_me(), sender.clone()));
+                    found = true;
+                    break;
+                  }
+                }
+                if !found {
+                  context.agent.dead_letter(msg.clone_me(), &context.agent, &sender, PathNotFound);
+                  sender.deliver(Undelivered(context.agent.clone(), msg));
+                }
+              }
+            }
+        }
+      },
+      Terminated(terminated) => self.actor.terminated(context, terminated),
+      Failure(err, failed) => {
+        context.count_failure(&*err, &failed);
+        self.actor.failed(context, err, failed);
+      },
+      Undelivered(attempted, orig_msg) => self.actor.undelivered(context, attempted, orig_msg),
+      Watch(watcher) => {
+        let mut watchers = self.watchers.lock();
+        watchers.push(watcher);
+        context.agent.set_watcher_count(watchers.len());
+      },
+      Unwatch(unwatcher) => {
+        let mut watchers = self.watchers.lock();
+        Context::remove_unwatcher(&mut *watchers, unwatcher);
+        context.agent.set_watcher_count(watchers.len());
+      },
+      Kill(killer) => {
+        context.agent.set_state(Stopping);
+
+        // Drain and consume the receiver.
+        Context::drain_recv(&self.recv, context);
+
+        // Notify user Actor that it has been killed.
+        self.actor.killed(context, killer);
+
+        // Continue cleanup.
+        return false;
+      }
+    }
+    true
+  }
+
+  // Hands a message to the user Actor, unless chaos injects a fault
+  // into it; resend makes the message again for faults that send it
+  // back. Returns false if an injected failure stopped the Actor.
+  fn receive(actor: &mut T,
+             context: &mut Context,
+             recv: &Receiver<Envelope>,
+             msg: Box<Message:Send>,
+             sender: Agent,
+             resend: |Box<Message:Send>, Agent| -> CageMessage) -> bool {
+    match context.chaos.fault_for(context.agent.path().as_slice()) {
+      NoFault => actor.receive(context, msg, sender),
+      Dropped => (),
+      Duplicated => {
+        actor.receive(context, msg.clone_me(), sender.clone());
+        actor.receive(context, msg, sender);
+      },
+      Delayed(delay) =>
+        context.dispatcher.schedule_once(delay, context.agent.clone(), resend(msg, sender)),
+      Reordered => context.agent.deliver(resend(msg, sender)),
+      // The Actor fails as a supervised Actor would, without
+      // failing the task, which may be a deterministic Stage's.
+      Failed => {
+        context.log.warn(format!("chaos: injected failure in {}", context.agent.path()).as_slice());
+        let failure = context.failure(box InjectedFailure { path: context.agent.path() });
+        context.parent.deliver(failure);
+        context.agent.set_state(Stopping);
+        Context::drain_recv(recv, context);
+        return false;
+      }
+    }
+    true
+  }
+
+  fn stop(&mut self) {
+    let context = &mut self.context;
+
+    // The Actor can no longer be reached by path.
+    context.registry.unregister(&context.agent);
+
+    // Notify watchers of this Actor's death.
+    for watcher in self.watchers.lock().iter() {
+      watcher.deliver(Terminated(context.agent.clone()));
+    }
+
+    // Reap this Actor's children.
+    for child in context.children().iter() {
+      child.deliver(Kill(context.agent()));
+    }
+
+    // Stopped Actors receive no more events.
+    context.event_stream.unsubscribe_all(&context.agent);
+
+    // User Actor cleanup.
+    self.actor.post_stop(context);
+    context.agent.set_state(actor_tree::Stopped);
+    context.publish(box ActorStopped { agent: context.agent() });
+  }
+}
+
+// Takes the Actor out of the Stage if its task fails while handling
+// a message, as it can no longer receive events or be reached by
+// path, then tells its watchers it terminated and its parent it
+// failed, which counts the failure.
+struct Failing {
+  agent: Agent,
+  parent: Agent,
+  watchers: Arc<Mutex<Vec<Agent>>>,
+  registry: Registry,
+  event_stream: EventStream
+}
+
+impl Drop for Failing {
+  fn drop(&mut self) {
+    if task::failing() {
+      self.registry.unregister(&self.agent);
+      self.agent.set_state(actor_tree::Failed);
+      self.event_stream.unsubscribe_all(&self.agent);
+      for watcher in self.watchers.lock().iter() {
+        watcher.deliver(Terminated(self.agent.clone()));
+      }
+      let err = box TaskFailed { path: self.agent.path() } as Box<Message:Send>;
+      self.parent.deliver(Failure(err, self.agent.clone()));
+    }
+  }
+}
+
+impl<T: Actor> Runnable for ActorCell<T> {
+  fn step(&mut self, block: bool) -> Step {
+    if !self.started || self.context.dispatcher.is_deterministic() {
+      self.enter();
+    }
+    if !self.started {
+      self.started = true;
+      self.start();
+      return Handled;
+    }
+
+    // Receive messages and dispatch to user Actor.
+    let envelope = if block {
+      self.recv.recv()
+    } else {
+      match self.recv.try_recv() {
+        Ok(envelope) => envelope,
+        Err(_) => return Idle
+      }
+    };
+    self.context.agent.dequeued(envelope.enqueued);
+    self.process(envelope.msg, envelope.trace)
+  }
+}
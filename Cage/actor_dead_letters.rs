@@ -0,0 +1,286 @@
+/*
+ * The dead letter office is the system Actor at /system/deadLetters
+ * that receives every message in the Cage system that could not
+ * be delivered to its intended Actor, and publishes each one on
+ * the EventStream.
+ */
+use std::any::AnyRefExt;
+use std::fmt;
+use std::intrinsics::TypeId;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_context::Context;
+use actor_log::LogEntry;
+
+/*
+ * Messages handled by the dead letter office.
+ */
+
+// Why a message ended up in the dead letter office.
+#[deriving(Clone, PartialEq)]
+pub enum DeadLetterReason {
+  // The recipient had stopped before the message arrived.
+  RecipientStopped,
+  // No Actor exists at the path the message was sent to.
+  PathNotFound,
+  // The message could not be returned to its sender.
+  Unreturnable,
+  // The message was a reply with no one waiting for it.
+  NoRecipient,
+  // The message was bound for another Stage, but its type can't be encoded.
+  NotSerializable
+}
+
+impl fmt::Show for DeadLetterReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      RecipientStopped => write!(f, "recipient stopped"),
+      PathNotFound => write!(f, "path not found"),
+      Unreturnable => write!(f, "could not return to sender"),
+      NoRecipient => write!(f, "no recipient"),
+      NotSerializable => write!(f, "not serializable")
+    }
+  }
+}
+
+// An undeliverable message along with where it was headed.
+pub struct DeadLetter {
+  pub msg: Box<Message:Send>,
+  pub recipient: Agent,
+  pub sender: Agent,
+  pub reason: DeadLetterReason
+}
+
+impl DeadLetter {
+  pub fn new(msg: Box<Message:Send>,
+             recipient: Agent,
+             sender: Agent,
+             reason: DeadLetterReason) -> DeadLetter {
+    DeadLetter {
+      msg: msg,
+      recipient: recipient,
+      sender: sender,
+      reason: reason
+    }
+  }
+}
+
+impl Clone for DeadLetter {
+  fn clone(&self) -> DeadLetter {
+    DeadLetter::new(self.msg.clone_me(),
+                    self.recipient.clone(),
+                    self.sender.clone(),
+                    self.reason.clone())
+  }
+}
+impl Message for DeadLetter {}
+
+// Asks the office to forward every dead letter to the sender.
+// Equivalent to subscribing to DeadLetter on the EventStream.
+#[deriving(Clone)]
+pub struct SubscribeDeadLetters;
+impl Message for SubscribeDeadLetters {}
+
+// Asks the office to stop forwarding dead letters to the sender.
+#[deriving(Clone)]
+pub struct UnsubscribeDeadLetters;
+impl Message for UnsubscribeDeadLetters {}
+
+// Asks the office for the number of dead letters it has received,
+// answered with a DeadLetterCount.
+#[deriving(Clone)]
+pub struct CountDeadLetters;
+impl Message for CountDeadLetters {}
+
+#[deriving(Clone)]
+pub struct DeadLetterCount {
+  pub count: uint,
+  // How many were lost for each reason, for the reasons seen so far.
+  pub by_reason: Vec<(DeadLetterReason, uint)>
+}
+impl Message for DeadLetterCount {}
+
+// Turns logging of each dead letter on or off.
+#[deriving(Clone)]
+pub struct LogDeadLetters {
+  pub on: bool
+}
+impl Message for LogDeadLetters {}
+
+/*
+ * The office itself.
+ */
+pub struct DeadLetterOffice {
+  count: uint,
+  by_reason: Vec<(DeadLetterReason, uint)>,
+  logging: bool
+}
+
+impl DeadLetterOffice {
+  fn post(&mut self, context: &mut Context, letter: &DeadLetter) {
+    self.count += 1;
+    match self.by_reason.iter().position(|&(ref reason, _)| *reason == letter.reason) {
+      Some(i) => match *self.by_reason.get_mut(i) { (_, ref mut count) => *count += 1 },
+      None => self.by_reason.push((letter.reason.clone(), 1))
+    }
+
+    // Lost log records aren't logged again, in case the logging Actor is gone.
+    if self.logging && !letter.msg.is::<LogEntry>() {
+      context.log().info(format!("dead letter from {} to {} ({}): {}",
+                                 letter.sender.path(),
+                                 letter.recipient.path(),
+                                 letter.reason,
+                                 context.serialization().describe(&*letter.msg)).as_slice());
+    }
+
+    context.publish(box letter.clone());
+  }
+}
+
+impl Actor for DeadLetterOffice {
+  fn new() -> DeadLetterOffice {
+    DeadLetterOffice {
+      count: 0,
+      by_reason: Vec::new(),
+      logging: true
+    }
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    if msg.is::<DeadLetter>() {
+      let letter = msg.as_ref::<DeadLetter>().unwrap();
+      self.post(context, letter);
+    } else if msg.is::<SubscribeDeadLetters>() {
+      context.event_stream().subscribe(&sender, TypeId::of::<DeadLetter>());
+    } else if msg.is::<UnsubscribeDeadLetters>() {
+      context.event_stream().unsubscribe(&sender, TypeId::of::<DeadLetter>());
+    } else if msg.is::<CountDeadLetters>() {
+      sender.deliver(context.send(box DeadLetterCount {
+        count: self.count,
+        by_reason: self.by_reason.clone()
+      }));
+    } else if msg.is::<LogDeadLetters>() {
+      self.logging = msg.as_ref::<LogDeadLetters>().unwrap().on;
+    } else {
+      // Anything else sent here, such as a reply to fire_and_forget,
+      // had no one to receive it.
+      let letter = DeadLetter::new(msg.clone_me(), context.agent(), sender, NoRecipient);
+      self.post(context, &letter);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use std::intrinsics::TypeId;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestProbe;
+  use cage_message::Kill;
+  use super::CountDeadLetters;
+  use super::DeadLetter;
+  use super::DeadLetterCount;
+  use super::SubscribeDeadLetters;
+  use super::UnsubscribeDeadLetters;
+  use super::DeadLetterReason;
+    use super::RecipientStopped;
+    use super::PathNotFound;
+    use super::NoRecipient;
+
+  #[deriving(Clone, PartialEq, Show)]
+  struct Lost(uint);
+  impl Message for Lost {}
+
+  struct Sink;
+
+  impl Actor for Sink {
+    fn new() -> Sink {
+      Sink
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {}
+  }
+
+  fn letter(about: &Agent, reason: DeadLetterReason) -> Box<Message:Send> {
+    box DeadLetter::new(box Lost(1), about.clone(), about.clone(), reason)
+  }
+
+  fn count(stage: &Stage, probe: &mut TestProbe) -> DeadLetterCount {
+    probe.send(&stage.dead_letters(), box CountDeadLetters);
+    probe.expect_msg::<DeadLetterCount>(0)
+  }
+
+  fn count_of(count: &DeadLetterCount, reason: DeadLetterReason) -> uint {
+    count.by_reason.iter().find(|&&(ref r, _)| *r == reason).map_or(0, |&(_, n)| n)
+  }
+
+  #[test]
+  fn counts_dead_letters_in_total_and_by_reason() {
+    let mut stage = Stage::deterministic();
+    let mut probe = TestProbe::new(&stage);
+    let before = count(&stage, &mut probe);
+
+    let office = stage.dead_letters();
+    office.fire_and_forget(letter(&probe.agent(), PathNotFound));
+    office.fire_and_forget(letter(&probe.agent(), PathNotFound));
+
+    // A message to a stopped Actor reaches the office by itself.
+    let sink = stage.start_name::<Sink>("sink".to_string());
+    sink.deliver(Kill(probe.agent()));
+    stage.run_until_idle();
+    probe.send(&sink, box Lost(2));
+
+    let after = count(&stage, &mut probe);
+    assert_eq!(after.count, before.count + 3);
+    assert_eq!(count_of(&after, PathNotFound), count_of(&before, PathNotFound) + 2);
+    assert_eq!(count_of(&after, RecipientStopped), count_of(&before, RecipientStopped) + 1);
+  }
+
+  #[test]
+  fn counts_messages_sent_to_the_office_as_having_no_recipient() {
+    let stage = Stage::deterministic();
+    let mut probe = TestProbe::new(&stage);
+    let before = count(&stage, &mut probe);
+    probe.send(&stage.dead_letters(), box Lost(1));
+    let after = count(&stage, &mut probe);
+    assert_eq!(count_of(&after, NoRecipient), count_of(&before, NoRecipient) + 1);
+  }
+
+  #[test]
+  fn publishes_dead_letters_on_the_event_stream() {
+    let stage = Stage::deterministic();
+    let mut probe = TestProbe::new(&stage);
+    stage.event_stream().subscribe(&probe.agent(), TypeId::of::<DeadLetter>());
+
+    let sender = TestProbe::new(&stage);
+    sender.send(&stage.dead_letters(), box Lost(7));
+    let letter = probe.expect_msg::<DeadLetter>(0);
+    assert_eq!(letter.msg.as_ref::<Lost>(), Some(&Lost(7)));
+    assert_eq!(letter.sender.path(), sender.agent().path());
+    assert!(letter.reason == NoRecipient);
+  }
+
+  #[test]
+  fn forwards_dead_letters_to_subscribers_until_they_unsubscribe() {
+    let stage = Stage::deterministic();
+    let mut probe = TestProbe::new(&stage);
+    let office = stage.dead_letters();
+    probe.send(&office, box SubscribeDeadLetters);
+    office.fire_and_forget(letter(&office, PathNotFound));
+    assert!(probe.expect_msg::<DeadLetter>(0).reason == PathNotFound);
+
+    probe.send(&office, box UnsubscribeDeadLetters);
+    office.fire_and_forget(letter(&office, PathNotFound));
+    probe.expect_no_msg(0);
+  }
+}
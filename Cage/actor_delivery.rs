@@ -0,0 +1,418 @@
+/*
+ * At-least-once delivery. An Actor keeps an AtLeastOnceDelivery and
+ * sends through it the messages that must not be lost; each is given
+ * a delivery id, and is sent again every redeliver_interval until the
+ * Actor confirms the id, ex. when the recipient acknowledges it:
+ *
+ *   self.delivery.deliver(context, agent.path(), |id| Job { id: id, .. });
+ *   ...
+ *   self.delivery.confirm(ack.id);
+ *
+ * Messages are delivered by path, so a redelivery reaches whatever
+ * Actor has taken the place of one that stopped. Recipients may see
+ * a message more than once, and should deduplicate by delivery id.
+ *
+ * The Actor passes every message it receives to receive(), which
+ * handles the redelivery timer.
+ *
+ * To keep the unconfirmed messages across restarts, a PersistentActor
+ * persists DeliveryEvents, made with a helper built by recovering():
+ *
+ *   fn receive_command(..) -> Vec<DeliveryEvent<Job>> {
+ *     // Sending a job:
+ *     self.delivery.sent(path, |id| Job { id: id, .. }).move_iter().collect()
+ *     // Or, on an acknowledgement:
+ *     self.delivery.confirmed(ack.id).move_iter().collect()
+ *   }
+ *   fn apply(&mut self, context: &mut Context, event: &DeliveryEvent<Job>) {
+ *     self.delivery.apply(context, event);
+ *   }
+ *   fn recovery_completed(&mut self, context: &mut Context) {
+ *     self.delivery.recovery_completed(context);
+ *   }
+ *
+ * Messages sent while events replay are only sent once
+ * recovery_completed is called, and the max_unconfirmed limit doesn't
+ * hold then: what was sent before has to be sent again. The Actor can
+ * snapshot the unconfirmed messages with snapshot() and restore().
+ */
+use std::any::AnyRefExt;
+use std::cmp;
+use std::collections::TreeMap;
+use std::io::IoError;
+use serialize::Decodable;
+use serialize::Encodable;
+use serialize::json;
+
+use actor::Message;
+use actor_context::Context;
+use actor_persistence::Event;
+
+// Redeliveries of a message after which a warning is logged.
+pub static WARN_AFTER_ATTEMPTS: uint = 5;
+
+// Sent by an AtLeastOnceDelivery to its Actor each redeliver_interval.
+#[deriving(Clone)]
+pub struct RedeliveryTick;
+impl Message for RedeliveryTick {}
+
+struct Unconfirmed<M> {
+  destination: String,
+  msg: M,
+  attempts: uint,
+  // The tick the message was last sent in.
+  sent: uint
+}
+
+// What a PersistentActor persists to keep its unconfirmed messages.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub enum DeliveryEvent<M> {
+  // A message was given a delivery id and sent to destination.
+  Sent(u64, String, M),
+  // The message with the delivery id was confirmed.
+  Confirmed(u64)
+}
+
+impl<'a, M: Message + Encodable<json::Encoder<'a>, IoError>
+                    + Decodable<json::Decoder, json::DecoderError>> Event for DeliveryEvent<M> {
+  fn encode(&self) -> String {
+    json::Encoder::str_encode(self)
+  }
+
+  fn decode(encoded: &str) -> Option<DeliveryEvent<M>> {
+    json::decode(encoded).ok()
+  }
+}
+
+// A message waiting for confirmation, in a DeliverySnapshot.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct UnconfirmedDelivery<M> {
+  pub id: u64,
+  pub destination: String,
+  pub msg: M
+}
+
+// The messages waiting for confirmation, to keep in a snapshot.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct DeliverySnapshot<M> {
+  pub next_id: u64,
+  pub unconfirmed: Vec<UnconfirmedDelivery<M>>
+}
+
+pub struct AtLeastOnceDelivery<M> {
+  redeliver_interval: u64,
+  max_unconfirmed: uint,
+  next_id: u64,
+  unconfirmed: TreeMap<u64, Unconfirmed<M>>,
+  recovering: bool,
+  tick: uint,
+  scheduled: bool
+}
+
+impl<M: Message> AtLeastOnceDelivery<M> {
+  // Redelivers every redeliver_interval milliseconds, and holds at most
+  // max_unconfirmed messages at once.
+  pub fn new(redeliver_interval: u64, max_unconfirmed: uint) -> AtLeastOnceDelivery<M> {
+    AtLeastOnceDelivery {
+      redeliver_interval: redeliver_interval,
+      max_unconfirmed: max_unconfirmed,
+      next_id: 1,
+      unconfirmed: TreeMap::new(),
+      recovering: false,
+      tick: 0,
+      scheduled: false
+    }
+  }
+
+  // Holds deliveries back until recovery_completed, for PersistentActors.
+  pub fn recovering(self) -> AtLeastOnceDelivery<M> {
+    AtLeastOnceDelivery { recovering: true, ..self }
+  }
+
+  // Sends the message make builds from a new delivery id to the Actor
+  // at destination, returning the id. Returns None, sending nothing,
+  // if max_unconfirmed messages are already waiting for confirmation.
+  pub fn deliver(&mut self,
+                 context: &mut Context,
+                 destination: String,
+                 make: |u64| -> M) -> Option<u64> {
+    if self.is_full() {
+      return None;
+    }
+    let id = self.next_id;
+    self.next_id += 1;
+    self.send(context, id, destination, make(id));
+    Some(id)
+  }
+
+  // Stops redelivering the message, returning false if the id was
+  // already confirmed or never given out.
+  pub fn confirm(&mut self, id: u64) -> bool {
+    self.unconfirmed.remove(&id)
+  }
+
+  /*
+   * For PersistentActors.
+   */
+
+  // Returns the event to persist to send the message make builds from
+  // a new delivery id, or None if max_unconfirmed messages are already
+  // waiting for confirmation. Nothing is sent until the event is applied.
+  pub fn sent(&mut self, destination: String, make: |u64| -> M) -> Option<DeliveryEvent<M>> {
+    if self.is_full() {
+      return None;
+    }
+    let id = self.next_id;
+    self.next_id += 1;
+    Some(Sent(id, destination, make(id)))
+  }
+
+  // Returns the event to persist to confirm the id, or None if it was
+  // already confirmed or never given out.
+  pub fn confirmed(&self, id: u64) -> Option<DeliveryEvent<M>> {
+    if self.unconfirmed.contains_key(&id) { Some(Confirmed(id)) } else { None }
+  }
+
+  // Applies a persisted event, both as events replay and once persisted.
+  pub fn apply(&mut self, context: &mut Context, event: &DeliveryEvent<M>) {
+    match *event {
+      Sent(id, ref destination, ref msg) => {
+        self.next_id = cmp::max(self.next_id, id + 1);
+        self.send(context, id, destination.clone(), msg.clone());
+      },
+      Confirmed(id) => {
+        self.confirm(id);
+      }
+    }
+  }
+
+  // Returns the messages waiting for confirmation.
+  pub fn snapshot(&self) -> DeliverySnapshot<M> {
+    DeliverySnapshot {
+      next_id: self.next_id,
+      unconfirmed: self.unconfirmed.iter().map(|(&id, pending)| {
+        UnconfirmedDelivery {
+          id: id,
+          destination: pending.destination.clone(),
+          msg: pending.msg.clone()
+        }
+      }).collect()
+    }
+  }
+
+  // Takes the messages waiting for confirmation from a snapshot, in
+  // place of any already held.
+  pub fn restore(&mut self, context: &mut Context, snapshot: DeliverySnapshot<M>) {
+    self.unconfirmed.clear();
+    self.next_id = snapshot.next_id;
+    for delivery in snapshot.unconfirmed.move_iter() {
+      self.send(context, delivery.id, delivery.destination, delivery.msg);
+    }
+  }
+
+  // Handles RedeliveryTick, returning false for any other message.
+  pub fn receive(&mut self, context: &mut Context, msg: &Box<Message>) -> bool {
+    if !msg.is::<RedeliveryTick>() {
+      return false;
+    }
+    self.scheduled = false;
+    self.redeliver(context);
+    true
+  }
+
+  // Sends every delivery made while recovering.
+  pub fn recovery_completed(&mut self, context: &mut Context) {
+    self.recovering = false;
+    for (_, pending) in self.unconfirmed.mut_iter() {
+      context.find(pending.destination.clone(), box pending.msg.clone());
+      pending.sent = self.tick;
+    }
+    self.schedule(context);
+  }
+
+  // Returns how many messages wait for confirmation.
+  pub fn unconfirmed_count(&self) -> uint {
+    self.unconfirmed.len()
+  }
+
+  // While recovering, messages sent before are being sent again, so
+  // the limit doesn't hold.
+  fn is_full(&self) -> bool {
+    !self.recovering && self.unconfirmed.len() >= self.max_unconfirmed
+  }
+
+  // Sends the message, unless recovering, and keeps it until confirmed.
+  fn send(&mut self, context: &mut Context, id: u64, destination: String, msg: M) {
+    if !self.recovering {
+      context.find(destination.clone(), box msg.clone());
+    }
+    self.unconfirmed.insert(id, Unconfirmed {
+      destination: destination,
+      msg: msg,
+      attempts: 1,
+      sent: self.tick
+    });
+    if !self.recovering {
+      self.schedule(context);
+    }
+  }
+
+  // Resends the messages that went a whole interval unconfirmed.
+  fn redeliver(&mut self, context: &mut Context) {
+    let tick = self.tick;
+    for (id, pending) in self.unconfirmed.mut_iter() {
+      if pending.sent >= tick {
+        continue;
+      }
+      context.find(pending.destination.clone(), box pending.msg.clone());
+      pending.attempts += 1;
+      pending.sent = tick;
+      if pending.attempts == WARN_AFTER_ATTEMPTS + 1 {
+        context.log().warn(format!("delivery {} to {} unconfirmed after {} attempts",
+                                   id, pending.destination, WARN_AFTER_ATTEMPTS).as_slice());
+      }
+    }
+    self.tick += 1;
+    self.schedule(context);
+  }
+
+  // Keeps one RedeliveryTick scheduled while messages are unconfirmed.
+  fn schedule(&mut self, context: &mut Context) {
+    if self.scheduled || self.unconfirmed.is_empty() {
+      return;
+    }
+    self.scheduled = true;
+    context.schedule_once(self.redeliver_interval, &context.agent(), box RedeliveryTick);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use serialize::json;
+
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_persistence::Persistent;
+  use actor_persistence::PersistentActor;
+  use actor_stage::Stage;
+  use actor_testkit::TestActorRef;
+  use super::AtLeastOnceDelivery;
+  use super::Confirmed;
+  use super::DeliveryEvent;
+  use super::DeliverySnapshot;
+  use super::Sent;
+
+  #[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+  struct Job {
+    id: u64,
+    work: String
+  }
+  impl Message for Job {}
+
+  #[deriving(Clone)]
+  struct Dispatch(String);
+  impl Message for Dispatch {}
+
+  #[deriving(Clone)]
+  struct Ack(u64);
+  impl Message for Ack {}
+
+  struct Dispatcher {
+    delivery: AtLeastOnceDelivery<Job>
+  }
+
+  impl PersistentActor<DeliveryEvent<Job>> for Dispatcher {
+    fn new() -> Dispatcher {
+      Dispatcher { delivery: AtLeastOnceDelivery::new(1000, 2).recovering() }
+    }
+
+    fn persistence_id(&self, context: &Context) -> String {
+      "dispatcher".to_string()
+    }
+
+    fn receive_command(&mut self,
+                       context: &mut Context,
+                       msg: Box<Message>,
+                       sender: Agent) -> Vec<DeliveryEvent<Job>> {
+      match (msg.as_ref::<Dispatch>(), msg.as_ref::<Ack>()) {
+        (Some(&Dispatch(ref work)), _) =>
+          self.delivery.sent("/workers".to_string(), |id| Job { id: id, work: work.clone() })
+                       .move_iter().collect(),
+        (_, Some(&Ack(id))) => self.delivery.confirmed(id).move_iter().collect(),
+        _ => vec!()
+      }
+    }
+
+    fn apply(&mut self, context: &mut Context, event: &DeliveryEvent<Job>) {
+      self.delivery.apply(context, event);
+    }
+
+    fn recovery_completed(&mut self, context: &mut Context) {
+      self.delivery.recovery_completed(context);
+    }
+  }
+
+  fn dispatcher(stage: &mut Stage, name: &str) -> TestActorRef<Persistent<DeliveryEvent<Job>, Dispatcher>> {
+    TestActorRef::new(stage, name.to_string())
+  }
+
+  fn job(id: u64, work: &str) -> Job {
+    Job { id: id, work: work.to_string() }
+  }
+
+  #[test]
+  fn recovers_unconfirmed_deliveries_from_the_journal() {
+    let mut stage = Stage::deterministic();
+    let mut first = dispatcher(&mut stage, "first");
+    first.receive(box Dispatch("a".to_string()));
+    first.receive(box Dispatch("b".to_string()));
+    // At the limit, nothing is persisted.
+    first.receive(box Dispatch("c".to_string()));
+    assert_eq!(first.actor().last_sequence_nr(), 2);
+    first.receive(box Ack(1));
+    first.receive(box Ack(1));
+    assert_eq!(first.actor().last_sequence_nr(), 3);
+
+    let mut second = dispatcher(&mut stage, "second");
+    let snapshot = second.actor().actor().delivery.snapshot();
+    assert_eq!(snapshot.next_id, 3);
+    assert_eq!(snapshot.unconfirmed.len(), 1);
+    assert_eq!(snapshot.unconfirmed[0].id, 2);
+    assert_eq!(snapshot.unconfirmed[0].msg, job(2, "b"));
+  }
+
+  #[test]
+  fn ignores_the_limit_while_recovering() {
+    let mut stage = Stage::deterministic();
+    let mut first = dispatcher(&mut stage, "first");
+    let mut delivery = AtLeastOnceDelivery::new(1000, 1).recovering();
+    for id in range(1u64, 4) {
+      delivery.apply(first.context(), &Sent(id, "/workers".to_string(), job(id, "a")));
+    }
+    assert!(delivery.deliver(first.context(), "/workers".to_string(), |id| job(id, "b")).is_some());
+    assert_eq!(delivery.unconfirmed_count(), 4);
+
+    delivery.recovery_completed(first.context());
+    assert!(delivery.deliver(first.context(), "/workers".to_string(), |id| job(id, "c")).is_none());
+  }
+
+  #[test]
+  fn restores_a_snapshot_of_unconfirmed_deliveries() {
+    let mut stage = Stage::deterministic();
+    let mut first = dispatcher(&mut stage, "first");
+    let mut delivery = AtLeastOnceDelivery::new(1000, 10).recovering();
+    delivery.apply(first.context(), &Sent(1, "/workers".to_string(), job(1, "a")));
+    delivery.apply(first.context(), &Sent(2, "/workers".to_string(), job(2, "b")));
+    delivery.apply(first.context(), &Confirmed(1));
+
+    let encoded = json::Encoder::str_encode(&delivery.snapshot());
+    let snapshot: DeliverySnapshot<Job> = json::decode(encoded.as_slice()).unwrap();
+    let mut restored = AtLeastOnceDelivery::new(1000, 10).recovering();
+    restored.restore(first.context(), snapshot);
+    assert_eq!(restored.snapshot(), delivery.snapshot());
+    assert_eq!(restored.sent("/workers".to_string(), |id| job(id, "c")),
+               Some(Sent(3, "/workers".to_string(), job(3, "c"))));
+  }
+}
@@ -0,0 +1,348 @@
+/*
+ * Dispatchers decide where Actors run and how scheduled messages
+ * wait for their time.
+ *
+ * By default each Actor runs in its own task, blocking on its
+ * mailbox, and scheduled messages sleep in tasks of their own. That
+ * makes tests timing dependent, so a Stage made with
+ *   let mut stage = Stage::deterministic();
+ * instead runs every Actor in the task that made it, only when the
+ * test asks:
+ *   stage.step()             handles one message, if any is waiting
+ *   stage.run_until_idle()   handles messages until none are waiting
+ *   stage.advance(1000)      moves a virtual clock on by 1000ms,
+ *                            delivering scheduled messages as it goes
+ * Actors take turns in the order they were started, one message at a
+ * time, so the same test always sees the same order of events.
+ *
+ * Deterministic Stages are driven from the task that made them, and
+ * Stages made in the same task share one dispatcher and clock.
+ * Nothing happens between calls, so tests wait for replies with a
+ * TestProbe rather than Agent::request.
+ *
+ * An exploring dispatcher, made by Stage::exploring(seed), instead
+ * lets a random Actor with a waiting message go at each step, so
+ * running a test under many seeds tries many delivery orders across
+ * mailboxes. The same seed always makes the same choices, so a
+ * failing order is replayed by running with its seed again, on a
+ * task of its own. Only the choice of Actor is random: each mailbox
+ * is still taken in order, and scheduled messages in due order.
+ */
+use std::cell::RefCell;
+use std::io::timer;
+use std::rand::Rng;
+use std::rand::SeedableRng;
+use std::rand::StdRng;
+use std::rc::Rc;
+
+use actor_agent::Agent;
+use cage_message::CageMessage;
+
+// The deterministic dispatcher of the current task, if any.
+local_data_key!(DETERMINISTIC: Rc<RefCell<Deterministic>>)
+
+// What an Actor did when asked to handle a message.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Step {
+  Handled,
+  Idle,
+  Stopped
+}
+
+// An Actor with its mailbox, as run by a Dispatcher.
+pub trait Runnable {
+  // Handles the next message in the mailbox, first waiting for one
+  // if block is true. The first step starts the Actor.
+  fn step(&mut self, block: bool) -> Step;
+}
+
+// A message waiting on the virtual clock.
+struct Scheduled {
+  due: u64,
+  seq: u64,
+  target: Agent,
+  msg: CageMessage
+}
+
+struct Deterministic {
+  runnables: Vec<Box<Runnable>>,
+  // Where the next step starts looking for a message.
+  next: uint,
+  now: u64,
+  scheduled: Vec<Scheduled>,
+  seq: u64,
+  // Picks the Actor for each step when exploring.
+  rng: Option<StdRng>
+}
+
+#[deriving(Clone)]
+pub struct Dispatcher {
+  deterministic: bool
+}
+
+impl Dispatcher {
+  // A task per Actor.
+  pub fn threaded() -> Dispatcher {
+    Dispatcher { deterministic: false }
+  }
+
+  // Every Actor in the current task, stepped by the test.
+  pub fn deterministic() -> Dispatcher {
+    if DETERMINISTIC.get().is_none() {
+      DETERMINISTIC.replace(Some(Rc::new(RefCell::new(Deterministic {
+        runnables: Vec::new(),
+        next: 0,
+        now: 0,
+        scheduled: Vec::new(),
+        seq: 0,
+        rng: None
+      }))));
+    }
+    Dispatcher { deterministic: true }
+  }
+
+  // Deterministic, but each step goes to a random waiting Actor,
+  // chosen by a generator seeded with seed.
+  pub fn exploring(seed: uint) -> Dispatcher {
+    let dispatcher = Dispatcher::deterministic();
+    let rng: StdRng = SeedableRng::from_seed(&[seed]);
+    Dispatcher::state().borrow_mut().rng = Some(rng);
+    dispatcher
+  }
+
+  pub fn is_deterministic(&self) -> bool {
+    self.deterministic
+  }
+
+  // Runs the Actor made by make until it stops.
+  pub fn run(&self, make: proc():Send -> Box<Runnable>) {
+    if self.deterministic {
+      let runnable = make();
+      Dispatcher::state().borrow_mut().runnables.push(runnable);
+    } else {
+      spawn(proc() {
+        let mut runnable = make();
+        while runnable.step(true) != Stopped {}
+      });
+    }
+  }
+
+  // Delivers the message to target after delay milliseconds.
+  pub fn schedule_once(&self, delay: u64, target: Agent, msg: CageMessage) {
+    if self.deterministic {
+      let state = Dispatcher::state();
+      let mut state = state.borrow_mut();
+      let scheduled = Scheduled { due: state.now + delay, seq: state.seq, target: target, msg: msg };
+      state.seq += 1;
+      state.scheduled.push(scheduled);
+    } else {
+      spawn(proc() {
+        timer::sleep(delay);
+        target.deliver(msg);
+      });
+    }
+  }
+
+  /*
+   * Driving a deterministic dispatcher.
+   */
+
+  // Lets the next Actor with a waiting message handle it. Returns
+  // false if no Actor had one.
+  pub fn step(&self) -> bool {
+    let state = self.expect_deterministic();
+    let count = state.borrow().runnables.len();
+    let start = state.borrow().next;
+    let mut order: Vec<uint> = range(0, count).map(|k| (start + k) % count).collect();
+    match state.borrow_mut().rng {
+      Some(ref mut rng) => rng.shuffle(order.as_mut_slice()),
+      None => ()
+    }
+    for i in order.move_iter() {
+      // The Actor runs outside the borrow, so it can start others.
+      let mut runnable = state.borrow_mut().runnables.remove(i).unwrap();
+      let step = runnable.step(false);
+      let mut state = state.borrow_mut();
+      match step {
+        Handled => {
+          state.runnables.insert(i, runnable);
+          state.next = i + 1;
+          return true;
+        },
+        Stopped => {
+          state.next = i;
+          return true;
+        },
+        Idle => state.runnables.insert(i, runnable)
+      }
+    }
+    false
+  }
+
+  // Steps until no Actor has a waiting message, returning how many
+  // steps were taken.
+  pub fn run_until_idle(&self) -> uint {
+    let mut steps = 0;
+    while self.step() {
+      steps += 1;
+    }
+    steps
+  }
+
+  // Moves the virtual clock on by millis, delivering each scheduled
+  // message when its time comes and running until idle after each.
+  pub fn advance(&self, millis: u64) {
+    let state = self.expect_deterministic();
+    let until = state.borrow().now + millis;
+    self.run_until_idle();
+    loop {
+      let next = {
+        let mut state = state.borrow_mut();
+        let due = state.scheduled.iter()
+                                 .enumerate()
+                                 .filter(|&(_, s)| s.due <= until)
+                                 .min_by(|&(_, s)| (s.due, s.seq))
+                                 .map(|(i, _)| i);
+        match due {
+          Some(i) => {
+            let scheduled = state.scheduled.swap_remove(i).unwrap();
+            state.now = scheduled.due;
+            Some(scheduled)
+          },
+          None => None
+        }
+      };
+      match next {
+        Some(scheduled) => {
+          scheduled.target.deliver(scheduled.msg);
+          self.run_until_idle();
+        },
+        None => break
+      }
+    }
+    state.borrow_mut().now = until;
+  }
+
+  // Milliseconds on the virtual clock since the dispatcher was made.
+  pub fn now(&self) -> u64 {
+    self.expect_deterministic().borrow().now
+  }
+
+  fn state() -> Rc<RefCell<Deterministic>> {
+    DETERMINISTIC.get().map(|state| state.clone())
+                       .expect("deterministic Stage driven from another task")
+  }
+
+  fn expect_deterministic(&self) -> Rc<RefCell<Deterministic>> {
+    if !self.deterministic {
+      fail!("only a deterministic Stage can be stepped");
+    }
+    Dispatcher::state()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use std::task;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestProbe;
+  use cage_message::UserMessage;
+
+  #[deriving(Clone, PartialEq, Show)]
+  struct Tick(uint);
+  impl Message for Tick {}
+
+  #[deriving(Clone)]
+  struct Hello;
+  impl Message for Hello {}
+
+  // Answers each Hello.
+  struct Greeter;
+
+  impl Actor for Greeter {
+    fn new() -> Greeter {
+      Greeter
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      if msg.is::<Hello>() {
+        sender.deliver(context.send(box Hello));
+      }
+    }
+  }
+
+  // The paths of the Greeters answering three Hellos each, in the
+  // order they answered, on a fresh Stage in a task of its own.
+  fn answers(seed: Option<uint>) -> Vec<String> {
+    task::try(proc() {
+      let mut stage = match seed {
+        Some(seed) => Stage::exploring(seed),
+        None => Stage::deterministic()
+      };
+      let greeters: Vec<Agent> = ["a", "b", "c"].iter().map(|name| {
+        stage.start_name::<Greeter>(name.to_string())
+      }).collect();
+      let mut probe = TestProbe::new(&stage);
+      for _ in range(0u, 3) {
+        for greeter in greeters.iter() {
+          probe.send(greeter, box Hello);
+        }
+      }
+      stage.run_until_idle();
+      range(0u, 9).map(|_| {
+        probe.expect_msg::<Hello>(0);
+        probe.sender().unwrap().path()
+      }).collect()
+    }).unwrap()
+  }
+
+  #[test]
+  fn delivers_scheduled_messages_in_virtual_time() {
+    let stage = Stage::deterministic();
+    let mut probe = TestProbe::new(&stage);
+    let dispatcher = stage.dispatcher();
+    dispatcher.schedule_once(200, probe.agent(), UserMessage(box Tick(2), stage.dead_letters()));
+    dispatcher.schedule_once(100, probe.agent(), UserMessage(box Tick(1), stage.dead_letters()));
+
+    stage.advance(150);
+    assert_eq!(stage.now(), 150);
+    assert_eq!(probe.expect_msg::<Tick>(0), Tick(1));
+    probe.expect_no_msg(0);
+
+    stage.advance(50);
+    assert_eq!(probe.expect_msg::<Tick>(0), Tick(2));
+  }
+
+  #[test]
+  fn steps_only_when_asked() {
+    let mut stage = Stage::deterministic();
+    stage.run_until_idle();
+    assert!(!stage.step());
+
+    let greeter = stage.start_name::<Greeter>("greeter".to_string());
+    let mut probe = TestProbe::new(&stage);
+    probe.send(&greeter, box Hello);
+    assert!(probe.received().is_empty());
+    stage.run_until_idle();
+    assert!(!stage.step());
+    probe.expect_msg::<Hello>(0);
+  }
+
+  #[test]
+  fn repeats_the_same_order() {
+    assert_eq!(answers(None), answers(None));
+    assert_eq!(answers(Some(7)), answers(Some(7)));
+  }
+
+  #[test]
+  fn explores_other_orders_under_other_seeds() {
+    let first = answers(Some(0));
+    assert!(range(1u, 20).any(|seed| answers(Some(seed)) != first));
+  }
+}
@@ -0,0 +1,188 @@
+/*
+ * The EventStream is the Stage-wide publish/subscribe bus. Actors
+ * subscribe to a message type (or a class of messages) and receive
+ * every matching event published anywhere on the Stage, without
+ * publishers knowing who is listening.
+ */
+use std::any::Any;
+use std::intrinsics::TypeId;
+use sync::Arc;
+use sync::Mutex;
+
+use actor::Message;
+use actor_agent::Agent;
+use cage_message::UserMessage;
+
+/*
+ * Events published by the Cage system itself.
+ */
+
+// Published once an Actor has run pre_start and begins receiving messages.
+#[deriving(Clone)]
+pub struct ActorStarted {
+  pub agent: Agent
+}
+impl Message for ActorStarted {}
+
+// Published once an Actor has permanently ceased receiving messages.
+#[deriving(Clone)]
+pub struct ActorStopped {
+  pub agent: Agent
+}
+impl Message for ActorStopped {}
+
+/*
+ * Subscriptions.
+ */
+
+// A class of events wider than a single message type,
+// ex. every event whose type implements some trait.
+pub trait EventClass {
+  fn includes(&self, event: &Message) -> bool;
+}
+
+pub enum Classifier {
+  ByType(TypeId),
+  ByClass(Box<EventClass:Send>)
+}
+
+impl Classifier {
+  fn includes(&self, event: &Message) -> bool {
+    match *self {
+      ByType(type_id) => event.get_type_id() == type_id,
+      ByClass(ref class) => class.includes(event)
+    }
+  }
+}
+
+struct Subscription {
+  subscriber: Agent,
+  classifier: Classifier
+}
+
+#[deriving(Clone)]
+pub struct EventStream {
+  subscriptions: Arc<Mutex<Vec<Subscription>>>
+}
+
+impl EventStream {
+  pub fn new() -> EventStream {
+    EventStream { subscriptions: Arc::new(Mutex::new(Vec::new())) }
+  }
+
+  // Subscribes the Actor to every published event of the given type.
+  // Returns false if it was already subscribed to that type.
+  pub fn subscribe(&self, subscriber: &Agent, type_id: TypeId) -> bool {
+    let mut subscriptions = self.subscriptions.lock();
+    let exists = subscriptions.iter().any(|s| {
+      s.subscriber == *subscriber &&
+      match s.classifier { ByType(t) => t == type_id, _ => false }
+    });
+    if !exists {
+      subscriptions.push(Subscription {
+        subscriber: subscriber.clone(),
+        classifier: ByType(type_id)
+      });
+    }
+    !exists
+  }
+
+  // Subscribes the Actor to every published event in the given class.
+  pub fn subscribe_class(&self, subscriber: &Agent, class: Box<EventClass:Send>) {
+    self.subscriptions.lock().push(Subscription {
+      subscriber: subscriber.clone(),
+      classifier: ByClass(class)
+    });
+  }
+
+  // Removes the Actor's subscription to the given type.
+  pub fn unsubscribe(&self, subscriber: &Agent, type_id: TypeId) {
+    self.subscriptions.lock().retain(|s| {
+      s.subscriber != *subscriber ||
+      match s.classifier { ByType(t) => t != type_id, _ => true }
+    });
+  }
+
+  // Removes every subscription held by the Actor. Called by the
+  // Cage system when an Actor stops.
+  pub fn unsubscribe_all(&self, subscriber: &Agent) {
+    self.subscriptions.lock().retain(|s| s.subscriber != *subscriber);
+  }
+
+  // Delivers the event to each matching subscriber, once per subscriber,
+  // appearing to come from the publisher. Subscribers found to have
+  // stopped, ex. because their task failed, are unsubscribed.
+  pub fn publish(&self, event: Box<Message:Send>, publisher: &Agent) {
+    let mut subscriptions = self.subscriptions.lock();
+    let mut delivered: Vec<Agent> = Vec::new();
+    let mut stopped: Vec<Agent> = Vec::new();
+    for s in subscriptions.iter() {
+      if s.classifier.includes(&*event) && !delivered.contains(&s.subscriber) {
+        if !s.subscriber.try_deliver(UserMessage(event.clone_me(), publisher.clone())) {
+          stopped.push(s.subscriber.clone());
+        }
+        delivered.push(s.subscriber.clone());
+      }
+    }
+    subscriptions.retain(|s| !stopped.contains(&s.subscriber));
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use std::intrinsics::TypeId;
+
+  use actor::Message;
+  use actor_agent::Agent;
+  use cage_message::Envelope;
+    use cage_message::UserMessage;
+  use super::EventStream;
+
+  #[deriving(Clone)]
+  struct Ping;
+  impl Message for Ping {}
+
+  #[deriving(Clone)]
+  struct Pong;
+  impl Message for Pong {}
+
+  fn agent(name: &str) -> (Agent, Receiver<Envelope>) {
+    let (send, recv) = channel();
+    (Agent::new(send, "/".to_string(), name.to_string()), recv)
+  }
+
+  #[test]
+  fn publishes_to_subscribers_of_the_type_once() {
+    let stream = EventStream::new();
+    let (publisher, _publisher_inbox) = agent("publisher");
+    let (subscriber, inbox) = agent("subscriber");
+    assert!(stream.subscribe(&subscriber, TypeId::of::<Ping>()));
+    assert!(!stream.subscribe(&subscriber, TypeId::of::<Ping>()));
+
+    stream.publish(box Pong, &publisher);
+    stream.publish(box Ping, &publisher);
+    match inbox.try_recv().unwrap().msg {
+      UserMessage(msg, sender) => {
+        assert!(msg.is::<Ping>());
+        assert!(sender == publisher);
+      },
+      _ => fail!("expected a UserMessage")
+    }
+    assert!(inbox.try_recv().is_err());
+  }
+
+  #[test]
+  fn unsubscribes_subscribers_that_have_stopped() {
+    let stream = EventStream::new();
+    let (publisher, _publisher_inbox) = agent("publisher");
+    let (subscriber, inbox) = agent("subscriber");
+    stream.subscribe(&subscriber, TypeId::of::<Ping>());
+    drop(inbox);
+
+    stream.publish(box Ping, &publisher);
+
+    // Subscribing again succeeds only if the old subscription is gone.
+    assert!(stream.subscribe(&subscriber, TypeId::of::<Ping>()));
+  }
+}
@@ -0,0 +1,408 @@
+/*
+ * Finite-state-machine Actors. An FsmActor declares its states S and
+ * data D, handles each event according to the state it is in, and
+ * answers with the Transition to take:
+ *
+ *   goto(Adding).using(data)   change state (and data)
+ *   stay().for_max(1000)       keep the state, with a timeout
+ *   stop()                     kill this Actor; events already waiting
+ *                              become dead letters
+ *   unhandled()                the event means nothing in this state
+ *
+ * An FsmActor runs inside the Fsm Actor, ex.
+ *   context.start_child::<Fsm<CalcState, CalcData, Calculator>>()
+ */
+use std::any::AnyRefExt;
+use std::fmt::Show;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_context::Context;
+use actor_dead_letters::RecipientStopped;
+
+/*
+ * Transitions.
+ */
+pub enum Next<S> {
+  Goto(S),
+  Stay,
+  Stop,
+  Unhandled
+}
+
+pub struct Transition<S, D> {
+  next: Next<S>,
+  data: Option<D>,
+  timeout: Option<u64>
+}
+
+pub fn goto<S, D>(state: S) -> Transition<S, D> {
+  Transition { next: Goto(state), data: None, timeout: None }
+}
+
+pub fn stay<S, D>() -> Transition<S, D> {
+  Transition { next: Stay, data: None, timeout: None }
+}
+
+pub fn stop<S, D>() -> Transition<S, D> {
+  Transition { next: Stop, data: None, timeout: None }
+}
+
+pub fn unhandled<S, D>() -> Transition<S, D> {
+  Transition { next: Unhandled, data: None, timeout: None }
+}
+
+impl<S, D> Transition<S, D> {
+  // Replaces the machine's data.
+  pub fn using(self, data: D) -> Transition<S, D> {
+    Transition { data: Some(data), ..self }
+  }
+
+  // Sends StateTimeout if no other event arrives within timeout
+  // milliseconds, overriding state_timeout for this transition.
+  pub fn for_max(self, timeout: u64) -> Transition<S, D> {
+    Transition { timeout: Some(timeout), ..self }
+  }
+}
+
+/*
+ * Events the Fsm Actor sends on its own.
+ */
+
+// Handed to when() once a state's timeout passes with no other event.
+#[deriving(Clone)]
+pub struct StateTimeout;
+impl Message for StateTimeout {}
+
+// Scheduled by the Fsm Actor; stale ticks are ignored.
+#[deriving(Clone)]
+struct TimeoutTick {
+  generation: uint
+}
+impl Message for TimeoutTick {}
+
+/*
+ * The trait implemented by state machines.
+ */
+pub trait FsmActor<S: Clone + PartialEq + Show + Send, D: Send> {
+  fn new() -> Self;
+
+  // The state and data the machine starts in.
+  fn start_with(&mut self, context: &mut Context) -> (S, D);
+
+  // Handles an event in the given state.
+  fn when(&mut self,
+          context: &mut Context,
+          state: &S,
+          data: &D,
+          event: &Box<Message>,
+          sender: &Agent) -> Transition<S, D>;
+
+  // Called on every goto, after the state and data have changed.
+  fn on_transition(&mut self,
+                   context: &mut Context,
+                   from: &S,
+                   to: &S,
+                   data: &D) {}
+
+  // Called when when() leaves an event unhandled.
+  fn unhandled(&mut self,
+               context: &mut Context,
+               state: &S,
+               event: &Box<Message>,
+               sender: &Agent) {
+    context.log().warn(format!("unhandled event from {} in state {}",
+                               sender.path(), state).as_slice());
+  }
+
+  // Milliseconds the machine may sit in a state without an event
+  // before receiving StateTimeout, if the state has a timeout.
+  fn state_timeout(&self, state: &S) -> Option<u64> {
+    None
+  }
+
+  // Called after this Actor permanently ceases receiving messages.
+  fn post_stop(&mut self, context: &mut Context, state: &S, data: &D) {}
+}
+
+/*
+ * The Actor running an FsmActor.
+ */
+pub struct Fsm<S, D, T> {
+  machine: T,
+  state: Option<S>,
+  data: Option<D>,
+  generation: uint,
+  // Set by a stop transition; the machine sees no more events.
+  stopped: bool
+}
+
+impl<S: Clone + PartialEq + Show + Send, D: Send, T: FsmActor<S, D>> Fsm<S, D, T> {
+  // Schedules StateTimeout for the current state, if it has one.
+  fn schedule_timeout(&mut self, context: &mut Context, timeout: Option<u64>) {
+    self.generation += 1;
+    let timeout = match timeout {
+      Some(t) => Some(t),
+      None => self.state.as_ref().and_then(|s| self.machine.state_timeout(s))
+    };
+    match timeout {
+      Some(t) => context.schedule_once(t, &context.agent(), box TimeoutTick { generation: self.generation }),
+      None => ()
+    }
+  }
+
+  // Runs the event through the machine and applies the Transition.
+  fn handle(&mut self, context: &mut Context, event: &Box<Message>, sender: &Agent) {
+    let state = self.state.clone().unwrap();
+    let transition = self.machine.when(context, &state, self.data.get_ref(), event, sender);
+
+    match transition.data {
+      Some(data) => self.data = Some(data),
+      None => ()
+    }
+
+    match transition.next {
+      Goto(next) => {
+        self.state = Some(next.clone());
+        self.machine.on_transition(context, &state, &next, self.data.get_ref());
+        self.schedule_timeout(context, transition.timeout);
+      },
+      Stay => self.schedule_timeout(context, transition.timeout),
+      Stop => {
+        self.stopped = true;
+        context.agent().deliver(context.kill());
+      },
+      Unhandled => self.machine.unhandled(context, &state, event, sender)
+    }
+  }
+}
+
+impl<S: Clone + PartialEq + Show + Send, D: Send, T: FsmActor<S, D>> Actor for Fsm<S, D, T> {
+  fn new() -> Fsm<S, D, T> {
+    Fsm {
+      machine: FsmActor::new(),
+      state: None,
+      data: None,
+      generation: 0,
+      stopped: false
+    }
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    match msg.as_ref::<TimeoutTick>() {
+      Some(tick) => {
+        if tick.generation == self.generation && !self.stopped {
+          let timeout: Box<Message> = box StateTimeout;
+          self.handle(context, &timeout, &sender);
+        }
+      },
+      None if self.stopped => {
+        let agent = context.agent();
+        agent.dead_letter(msg.clone_me(), &agent, &sender, RecipientStopped);
+      },
+      None => self.handle(context, &msg, &sender)
+    }
+  }
+
+  fn pre_start(&mut self, context: &mut Context) {
+    let (state, data) = self.machine.start_with(context);
+    self.state = Some(state);
+    self.data = Some(data);
+    self.schedule_timeout(context, None);
+  }
+
+  fn post_stop(&mut self, context: &mut Context) {
+    match (&self.state, &self.data) {
+      (&Some(ref state), &Some(ref data)) => self.machine.post_stop(context, state, data),
+      _ => ()
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestActorRef;
+  use super::Fsm;
+  use super::FsmActor;
+  use super::StateTimeout;
+  use super::Transition;
+  use super::goto;
+  use super::stay;
+  use super::stop;
+
+  #[deriving(Clone, PartialEq, Show)]
+  enum Light {
+    Green,
+    Red
+  }
+
+  #[deriving(Clone)]
+  struct Switch;
+  impl Message for Switch {}
+
+  #[deriving(Clone)]
+  struct Off;
+  impl Message for Off {}
+
+  struct Lights {
+    events: uint,
+    transitions: uint,
+    stops: uint
+  }
+
+  impl FsmActor<Light, ()> for Lights {
+    fn new() -> Lights {
+      Lights { events: 0, transitions: 0, stops: 0 }
+    }
+
+    fn start_with(&mut self, context: &mut Context) -> (Light, ()) {
+      (Green, ())
+    }
+
+    fn when(&mut self,
+            context: &mut Context,
+            state: &Light,
+            data: &(),
+            event: &Box<Message>,
+            sender: &Agent) -> Transition<Light, ()> {
+      self.events += 1;
+      if event.is::<Off>() {
+        stop()
+      } else if *state == Green {
+        goto(Red)
+      } else {
+        goto(Green)
+      }
+    }
+
+    fn on_transition(&mut self, context: &mut Context, from: &Light, to: &Light, data: &()) {
+      self.transitions += 1;
+    }
+
+    fn post_stop(&mut self, context: &mut Context, state: &Light, data: &()) {
+      self.stops += 1;
+    }
+  }
+
+  fn lights(stage: &mut Stage) -> TestActorRef<Fsm<Light, (), Lights>> {
+    TestActorRef::new(stage, "lights".to_string())
+  }
+
+  #[test]
+  fn goes_to_the_state_when_returns() {
+    let mut stage = Stage::deterministic();
+    let mut fsm = lights(&mut stage);
+    fsm.receive(box Switch);
+    assert!(fsm.actor().state == Some(Red));
+    fsm.receive(box Switch);
+    assert!(fsm.actor().state == Some(Green));
+    assert_eq!(fsm.actor().machine.transitions, 2);
+  }
+
+  #[test]
+  fn ignores_events_after_stopping() {
+    let mut stage = Stage::deterministic();
+    let mut fsm = lights(&mut stage);
+    fsm.receive(box Off);
+    // As if queued ahead of the Kill the stop transition sent.
+    fsm.receive(box Switch);
+    assert_eq!(fsm.actor().machine.events, 1);
+    assert!(fsm.actor().state == Some(Green));
+
+    fsm.run_mailbox();
+    assert!(fsm.is_stopped());
+    assert_eq!(fsm.actor().machine.stops, 1);
+  }
+
+  #[deriving(Clone, PartialEq, Show)]
+  enum Door {
+    Shut,
+    Open
+  }
+
+  #[deriving(Clone)]
+  struct Push;
+  impl Message for Push {}
+
+  // Opens on a Push, and shuts a second after the last one.
+  struct Doorman {
+    timeouts: uint
+  }
+
+  impl FsmActor<Door, ()> for Doorman {
+    fn new() -> Doorman {
+      Doorman { timeouts: 0 }
+    }
+
+    fn start_with(&mut self, context: &mut Context) -> (Door, ()) {
+      (Shut, ())
+    }
+
+    fn when(&mut self,
+            context: &mut Context,
+            state: &Door,
+            data: &(),
+            event: &Box<Message>,
+            sender: &Agent) -> Transition<Door, ()> {
+      if event.is::<StateTimeout>() {
+        self.timeouts += 1;
+        goto(Shut)
+      } else if *state == Shut {
+        goto(Open).for_max(1000)
+      } else {
+        stay().for_max(1000)
+      }
+    }
+  }
+
+  fn doorman(stage: &mut Stage) -> TestActorRef<Fsm<Door, (), Doorman>> {
+    TestActorRef::new(stage, "doorman".to_string())
+  }
+
+  #[test]
+  fn times_out_of_a_state_on_the_virtual_clock() {
+    let mut stage = Stage::deterministic();
+    let mut fsm = doorman(&mut stage);
+    fsm.receive(box Push);
+    assert!(fsm.actor().state == Some(Open));
+
+    stage.advance(999);
+    fsm.run_mailbox();
+    assert!(fsm.actor().state == Some(Open));
+
+    stage.advance(1);
+    fsm.run_mailbox();
+    assert!(fsm.actor().state == Some(Shut));
+    assert_eq!(fsm.actor().machine.timeouts, 1);
+  }
+
+  #[test]
+  fn ignores_a_timeout_superseded_by_a_later_transition() {
+    let mut stage = Stage::deterministic();
+    let mut fsm = doorman(&mut stage);
+    fsm.receive(box Push);
+    stage.advance(600);
+    fsm.receive(box Push);
+
+    // The first timeout comes due, but the second Push replaced it.
+    stage.advance(500);
+    assert_eq!(fsm.run_mailbox(), 1);
+    assert!(fsm.actor().state == Some(Open));
+    assert_eq!(fsm.actor().machine.timeouts, 0);
+
+    stage.advance(500);
+    fsm.run_mailbox();
+    assert!(fsm.actor().state == Some(Shut));
+    assert_eq!(fsm.actor().machine.timeouts, 1);
+  }
+}
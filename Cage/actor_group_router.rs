@@ -0,0 +1,276 @@
+/*
+ * Group Routers route over Actors that already exist elsewhere in
+ * the hierarchy, selected by absolute path. A path may contain *
+ * to select several Actors (ex. /workers/*). Members are resolved
+ * with Identify, and every path is resolved again whenever a
+ * member stops, picking up any Actor that has taken its place.
+ */
+use std::any::AnyRefExt;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_context::Context;
+use actor_context::Identify;
+use actor_context::ActorIdentity;
+use actor_router::Routing;
+use actor_router::RouteeSet;
+use actor_router::BroadcastMessage;
+use actor_router::GetRoutees;
+use actor_router::Routees;
+
+/*
+ * Messages handled by group Routers.
+ */
+
+// Sent by Context::start_group_router as a group Router's first message.
+#[deriving(Clone)]
+pub struct GroupConfig {
+  pub routing: Routing,
+  pub paths: Vec<String>
+}
+impl Message for GroupConfig {}
+
+// Adds the Actors at the path to the group.
+#[deriving(Clone)]
+pub struct AddRoutee {
+  pub path: String
+}
+impl Message for AddRoutee {}
+
+// Removes the path from the group, along with the routees it selected.
+#[deriving(Clone)]
+pub struct RemoveRoutee {
+  pub path: String
+}
+impl Message for RemoveRoutee {}
+
+// Asks the group to resolve all of its paths again.
+#[deriving(Clone)]
+pub struct ResolveRoutees;
+impl Message for ResolveRoutees {}
+
+/*
+ * The group Router itself.
+ */
+pub struct GroupRouter {
+  paths: Vec<String>,
+  routees: RouteeSet
+}
+
+impl GroupRouter {
+  // Asks every Actor at the path to identify itself.
+  fn resolve(&self, context: &Context, path: &String) {
+    context.find(path.clone(), box Identify);
+  }
+
+  fn resolve_all(&self, context: &Context) {
+    for path in self.paths.iter() {
+      self.resolve(context, path);
+    }
+  }
+
+  // Whether any of the group's paths selects the Actor at path.
+  fn selects(&self, path: &str) -> bool {
+    self.paths.iter().any(|pattern| GroupRouter::matches(pattern.as_slice(), path))
+  }
+
+  // Compares path to pattern a segment at a time, where * matches
+  // any one segment.
+  fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    pattern.len() == path.len() &&
+    pattern.iter().zip(path.iter()).all(|(p, s)| *p == "*" || p == s)
+  }
+}
+
+impl Actor for GroupRouter {
+  fn new() -> GroupRouter {
+    GroupRouter {
+      paths: Vec::new(),
+      routees: RouteeSet::new()
+    }
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    if msg.is::<GroupConfig>() {
+      let config = msg.as_ref::<GroupConfig>().unwrap();
+      self.routees.set_routing(config.routing);
+      self.paths = config.paths.clone();
+      self.resolve_all(context);
+    } else if msg.is::<ActorIdentity>() {
+      // Identities for paths removed in the meantime are ignored.
+      let agent = msg.as_ref::<ActorIdentity>().unwrap().agent.clone();
+      if self.selects(agent.path().as_slice()) && self.routees.add(agent.clone()) {
+        agent.deliver(context.watch());
+      }
+    } else if msg.is::<AddRoutee>() {
+      let path = msg.as_ref::<AddRoutee>().unwrap().path.clone();
+      if !self.paths.contains(&path) {
+        self.resolve(context, &path);
+        self.paths.push(path);
+      }
+    } else if msg.is::<RemoveRoutee>() {
+      let path = msg.as_ref::<RemoveRoutee>().unwrap().path.clone();
+      self.paths.retain(|p| *p != path);
+      for routee in self.routees.routees().iter() {
+        if !self.selects(routee.path().as_slice()) {
+          self.routees.remove(routee);
+          routee.deliver(context.unwatch());
+        }
+      }
+    } else if msg.is::<ResolveRoutees>() {
+      self.resolve_all(context);
+    } else if msg.is::<BroadcastMessage>() {
+      let broadcast = msg.as_ref::<BroadcastMessage>().unwrap();
+      self.routees.broadcast(context, &broadcast.msg, &sender);
+    } else if msg.is::<GetRoutees>() {
+      sender.deliver(context.send(box Routees { routees: self.routees.routees() }));
+    } else {
+      self.routees.route(context, msg.clone_me(), sender);
+    }
+  }
+
+  // A member stopped; look for whatever now lives at the group's paths.
+  fn terminated(&mut self,
+                context: &mut Context,
+                terminated: Agent) {
+    if self.routees.remove(&terminated) {
+      self.resolve_all(context);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use std::intrinsics::TypeId;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_dead_letters::DeadLetter;
+    use actor_dead_letters::NoRecipient;
+  use actor_router::GetRoutees;
+  use actor_router::Routees;
+    use actor_router::RoundRobin;
+  use actor_stage::Stage;
+  use actor_testkit::TestProbe;
+  use cage_message::Kill;
+  use super::AddRoutee;
+  use super::RemoveRoutee;
+
+  #[deriving(Clone)]
+  struct Work;
+  impl Message for Work {}
+
+  #[deriving(Clone)]
+  struct AddWorker(&'static str);
+  impl Message for AddWorker {}
+
+  struct Worker;
+
+  impl Actor for Worker {
+    fn new() -> Worker {
+      Worker
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      sender.deliver(context.send(box Work));
+    }
+  }
+
+  // Starts workers a and b, and more when asked.
+  struct Workers;
+
+  impl Actor for Workers {
+    fn new() -> Workers {
+      Workers
+    }
+
+    fn pre_start(&mut self, context: &mut Context) {
+      context.start_child_name::<Worker>("a".to_string());
+      context.start_child_name::<Worker>("b".to_string());
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      match msg.as_ref::<AddWorker>() {
+        Some(&AddWorker(name)) => { context.start_child_name::<Worker>(name.to_string()); },
+        None => ()
+      }
+    }
+  }
+
+  fn routee_paths(router: &Agent, probe: &mut TestProbe) -> Vec<String> {
+    probe.send(router, box GetRoutees);
+    let mut paths: Vec<String> = probe.expect_msg::<Routees>(0).routees.iter().map(|r| r.path()).collect();
+    paths.sort();
+    paths
+  }
+
+  #[test]
+  fn resolves_routees_by_path_through_identify() {
+    let mut stage = Stage::deterministic();
+    stage.start_name::<Workers>("workers".to_string());
+    let router = stage.start_group_router(RoundRobin, vec!("/workers/*".to_string()));
+    let mut probe = TestProbe::new(&stage);
+    assert_eq!(routee_paths(&router, &mut probe), vec!("/workers/a".to_string(), "/workers/b".to_string()));
+
+    probe.send(&router, box Work);
+    probe.expect_msg::<Work>(0);
+  }
+
+  #[test]
+  fn resolves_its_paths_again_when_a_routee_stops() {
+    let mut stage = Stage::deterministic();
+    let workers = stage.start_name::<Workers>("workers".to_string());
+    let router = stage.start_group_router(RoundRobin, vec!("/workers/*".to_string()));
+    let mut probe = TestProbe::new(&stage);
+    let routees = { probe.send(&router, box GetRoutees); probe.expect_msg::<Routees>(0).routees };
+
+    let a = routees.iter().find(|r| r.name().as_slice() == "a").unwrap();
+    workers.fire_and_forget(box AddWorker("c"));
+    a.deliver(Kill(probe.agent()));
+    stage.run_until_idle();
+    assert_eq!(routee_paths(&router, &mut probe), vec!("/workers/b".to_string(), "/workers/c".to_string()));
+  }
+
+  #[test]
+  fn adds_and_removes_routees_by_path() {
+    let mut stage = Stage::deterministic();
+    stage.start_name::<Workers>("workers".to_string());
+    let router = stage.start_group_router(RoundRobin, vec!("/workers/a".to_string()));
+    let mut probe = TestProbe::new(&stage);
+    assert_eq!(routee_paths(&router, &mut probe), vec!("/workers/a".to_string()));
+
+    probe.send(&router, box AddRoutee { path: "/workers/b".to_string() });
+    assert_eq!(routee_paths(&router, &mut probe), vec!("/workers/a".to_string(), "/workers/b".to_string()));
+
+    probe.send(&router, box RemoveRoutee { path: "/workers/a".to_string() });
+    assert_eq!(routee_paths(&router, &mut probe), vec!("/workers/b".to_string()));
+  }
+
+  #[test]
+  fn dead_letters_messages_sent_before_any_routee_resolves() {
+    let mut stage = Stage::deterministic();
+    stage.start_name::<Workers>("workers".to_string());
+    let mut probe = TestProbe::new(&stage);
+    stage.event_stream().subscribe(&probe.agent(), TypeId::of::<DeadLetter>());
+    let router = stage.start_group_router(RoundRobin, vec!("/workers/*".to_string()));
+    probe.send(&router, box Work);
+
+    let letter = probe.expect_msg::<DeadLetter>(0);
+    assert!(letter.msg.is::<Work>());
+    assert!(letter.reason == NoRecipient);
+    assert_eq!(letter.recipient.path(), router.path());
+
+    // Once resolved, the group takes messages.
+    probe.send(&router, box Work);
+    probe.expect_msg::<Work>(0);
+  }
+}
@@ -0,0 +1,293 @@
+/*
+ * Journals store the events of persistent Actors, in order, under
+ * each Actor's persistence id. Events are stored already encoded.
+ */
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::io::BufferedReader;
+use std::io::File;
+use std::io::IoResult;
+use std::io::fs;
+
+pub trait Journal {
+  // Appends the event as persistence_id's sequence_nr'th.
+  fn write(&mut self, persistence_id: &str, sequence_nr: u64, event: &str) -> IoResult<()>;
+
+  // Calls replay with each event numbered from_sequence_nr or
+  // higher, in order.
+  fn replay(&mut self,
+            persistence_id: &str,
+            from_sequence_nr: u64,
+            replay: |u64, &str|) -> IoResult<()>;
+
+  // Returns the number of the last event written, or 0 if none. It
+  // counts deleted events, so numbers aren't used twice.
+  fn highest_sequence_nr(&mut self, persistence_id: &str) -> IoResult<u64>;
+
+  // Deletes every event numbered to_sequence_nr or lower, ex. those
+  // already covered by a snapshot.
+  fn delete_to(&mut self, persistence_id: &str, to_sequence_nr: u64) -> IoResult<()>;
+}
+
+/*
+ * A Journal kept in memory, lost with the Stage. Useful in tests.
+ */
+pub struct InMemoryJournal {
+  events: HashMap<String, Vec<(u64, String)>>,
+  // The highest number deleted, by persistence id.
+  deleted_to: HashMap<String, u64>
+}
+
+impl InMemoryJournal {
+  pub fn new() -> InMemoryJournal {
+    InMemoryJournal { events: HashMap::new(), deleted_to: HashMap::new() }
+  }
+}
+
+impl Journal for InMemoryJournal {
+  fn write(&mut self, persistence_id: &str, sequence_nr: u64, event: &str) -> IoResult<()> {
+    self.events.find_or_insert(persistence_id.to_string(), Vec::new())
+               .push((sequence_nr, event.to_string()));
+    Ok(())
+  }
+
+  fn replay(&mut self,
+            persistence_id: &str,
+            from_sequence_nr: u64,
+            replay: |u64, &str|) -> IoResult<()> {
+    match self.events.find_equiv(&persistence_id) {
+      Some(events) => {
+        for &(seq, ref event) in events.iter() {
+          if seq >= from_sequence_nr {
+            replay(seq, event.as_slice());
+          }
+        }
+      },
+      None => ()
+    }
+    Ok(())
+  }
+
+  fn highest_sequence_nr(&mut self, persistence_id: &str) -> IoResult<u64> {
+    let last = match self.events.find_equiv(&persistence_id) {
+      Some(events) => events.last().map_or(0, |&(seq, _)| seq),
+      None => 0
+    };
+    let deleted_to = self.deleted_to.find_equiv(&persistence_id).map_or(0, |&seq| seq);
+    Ok(cmp::max(last, deleted_to))
+  }
+
+  fn delete_to(&mut self, persistence_id: &str, to_sequence_nr: u64) -> IoResult<()> {
+    let mut deleted = 0;
+    match self.events.find_mut_equiv(&persistence_id) {
+      Some(events) => {
+        for &(seq, _) in events.iter() {
+          if seq <= to_sequence_nr {
+            deleted = cmp::max(deleted, seq);
+          }
+        }
+        events.retain(|&(seq, _)| seq > to_sequence_nr);
+      },
+      None => ()
+    }
+    let deleted_to = self.deleted_to.find_or_insert(persistence_id.to_string(), 0);
+    *deleted_to = cmp::max(*deleted_to, deleted);
+    Ok(())
+  }
+}
+
+// The persistence id as a file name. Persistence ids may hold /, as
+// paths do, and file names can't, so every byte other than an ASCII
+// letter, digit or _ is written %XX; distinct ids stay distinct.
+pub fn file_name_of(persistence_id: &str) -> String {
+  let mut name = String::new();
+  for &b in persistence_id.as_bytes().iter() {
+    let c = b as char;
+    if b < 0x80 && (c.is_alphanumeric() || c == '_') {
+      name.push_char(c);
+    } else {
+      name.push_str(format!("%{:02X}", b).as_slice());
+    }
+  }
+  name
+}
+
+// Starts the line keeping the highest number deleted.
+static DELETED: &'static str = "deleted";
+
+/*
+ * A Journal kept on disk, one file per persistence id under a
+ * directory. Each line holds a sequence number and an event, with
+ * backslashes and newlines in the event escaped. Once events are
+ * deleted, a first line "deleted<tab><n>" keeps the highest number
+ * deleted.
+ */
+pub struct FileJournal {
+  dir: Path
+}
+
+impl FileJournal {
+  // Uses (and creates, if needed) the given directory.
+  pub fn new(dir: Path) -> IoResult<FileJournal> {
+    if !dir.exists() {
+      try!(fs::mkdir_recursive(&dir, io::UserRWX));
+    }
+    Ok(FileJournal { dir: dir })
+  }
+
+  fn file(&self, persistence_id: &str) -> Path {
+    self.dir.join(file_name_of(persistence_id).append(".journal"))
+  }
+
+  fn escape(event: &str) -> String {
+    event.replace("\\", "\\\\").replace("\n", "\\n")
+  }
+
+  fn unescape(line: &str) -> String {
+    let mut out = String::new();
+    let mut escaped = false;
+    for c in line.chars() {
+      if escaped {
+        out.push_char(if c == 'n' { '\n' } else { c });
+        escaped = false;
+      } else if c == '\\' {
+        escaped = true;
+      } else {
+        out.push_char(c);
+      }
+    }
+    out
+  }
+
+  // Calls f with each sequence number and event in the file,
+  // returning the highest number deleted, or 0 if none.
+  fn each_line(&self, persistence_id: &str, f: |u64, String|) -> IoResult<u64> {
+    let path = self.file(persistence_id);
+    if !path.exists() {
+      return Ok(0);
+    }
+    let mut deleted_to = 0;
+    let mut reader = BufferedReader::new(try!(File::open(&path)));
+    for line in reader.lines() {
+      let line = try!(line);
+      let line = line.as_slice().trim_right_chars('\n');
+      match line.find('\t') {
+        Some(tab) if line.slice_to(tab) == DELETED =>
+          deleted_to = from_str::<u64>(line.slice_from(tab + 1)).unwrap_or(deleted_to),
+        Some(tab) => match from_str::<u64>(line.slice_to(tab)) {
+          Some(seq) => f(seq, FileJournal::unescape(line.slice_from(tab + 1))),
+          None => ()
+        },
+        None => ()
+      }
+    }
+    Ok(deleted_to)
+  }
+}
+
+impl Journal for FileJournal {
+  fn write(&mut self, persistence_id: &str, sequence_nr: u64, event: &str) -> IoResult<()> {
+    let mut file = try!(File::open_mode(&self.file(persistence_id), io::Append, io::Write));
+    try!(write!(&mut file, "{}\t{}\n", sequence_nr, FileJournal::escape(event)));
+    file.fsync()
+  }
+
+  fn replay(&mut self,
+            persistence_id: &str,
+            from_sequence_nr: u64,
+            replay: |u64, &str|) -> IoResult<()> {
+    try!(self.each_line(persistence_id, |seq, event| {
+      if seq >= from_sequence_nr {
+        replay(seq, event.as_slice());
+      }
+    }));
+    Ok(())
+  }
+
+  fn highest_sequence_nr(&mut self, persistence_id: &str) -> IoResult<u64> {
+    let mut highest = 0;
+    let deleted_to = try!(self.each_line(persistence_id, |seq, _| highest = seq));
+    Ok(cmp::max(highest, deleted_to))
+  }
+
+  // Writes the events kept to a new file, then renames it over the
+  // old one, so a crash midway loses nothing.
+  fn delete_to(&mut self, persistence_id: &str, to_sequence_nr: u64) -> IoResult<()> {
+    let mut kept = String::new();
+    let mut deleted = 0;
+    let deleted_to = try!(self.each_line(persistence_id, |seq, event| {
+      if seq > to_sequence_nr {
+        kept.push_str(format!("{}\t{}\n", seq, FileJournal::escape(event.as_slice())).as_slice());
+      } else {
+        deleted = cmp::max(deleted, seq);
+      }
+    }));
+    let path = self.file(persistence_id);
+    let tmp = path.with_extension("tmp");
+    {
+      let mut file = try!(File::create(&tmp));
+      try!(write!(&mut file, "{}\t{}\n", DELETED, cmp::max(deleted, deleted_to)));
+      try!(file.write_str(kept.as_slice()));
+      try!(file.fsync());
+    }
+    fs::rename(&tmp, &path)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::TempDir;
+
+  use super::FileJournal;
+  use super::InMemoryJournal;
+  use super::Journal;
+  use super::file_name_of;
+
+  fn events_of(journal: &mut Journal, persistence_id: &str) -> Vec<(u64, String)> {
+    let mut events = Vec::new();
+    journal.replay(persistence_id, 1, |seq, event| events.push((seq, event.to_string()))).unwrap();
+    events
+  }
+
+  // Writes three events, deletes the first two, then all of them.
+  fn keeps_the_highest_number_deleted(journal: &mut Journal) {
+    journal.write("a", 1, "one").unwrap();
+    journal.write("a", 2, "two\nlines").unwrap();
+    journal.write("a", 3, "three").unwrap();
+
+    journal.delete_to("a", 2).unwrap();
+    assert_eq!(events_of(journal, "a"), vec!((3, "three".to_string())));
+    assert_eq!(journal.highest_sequence_nr("a").unwrap(), 3);
+
+    journal.delete_to("a", 3).unwrap();
+    assert!(events_of(journal, "a").is_empty());
+    assert_eq!(journal.highest_sequence_nr("a").unwrap(), 3);
+
+    journal.write("a", 4, "four").unwrap();
+    assert_eq!(events_of(journal, "a"), vec!((4, "four".to_string())));
+    assert_eq!(journal.highest_sequence_nr("a").unwrap(), 4);
+  }
+
+  #[test]
+  fn in_memory_journal_keeps_the_highest_number_deleted() {
+    keeps_the_highest_number_deleted(&mut InMemoryJournal::new());
+  }
+
+  #[test]
+  fn file_journal_keeps_the_highest_number_deleted() {
+    let dir = TempDir::new("journal").unwrap();
+    let mut journal = FileJournal::new(dir.path().clone()).unwrap();
+    keeps_the_highest_number_deleted(&mut journal);
+    // Only the journal file is left behind.
+    assert_eq!(::std::io::fs::readdir(dir.path()).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn escapes_persistence_ids_reversibly() {
+    assert_eq!(file_name_of("/bank/audit_1").as_slice(), "%2Fbank%2Faudit_1");
+    assert_eq!(file_name_of("a-b%").as_slice(), "a%2Db%25");
+    assert!(file_name_of("/a/b") != file_name_of("/a_b"));
+    assert!(file_name_of("a%2Fb") != file_name_of("a/b"));
+  }
+}
@@ -0,0 +1,237 @@
+/*
+ * Asynchronous logging for the Cage system. Actors log through
+ * Context::log() (or the log crate's macros), and every record is
+ * sent to the logging Actor at /system/log, which alone writes to
+ * stdout. Levels can be filtered per subtree of the hierarchy.
+ */
+use std::any::AnyRefExt;
+use std::io::stdio;
+use log;
+use log::LogRecord;
+use sync::Arc;
+use sync::RWLock;
+use time;
+use time::Timespec;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_agent::ROOT_ADDRESS;
+use actor_context::Context;
+
+// Level used for subtrees without a level of their own.
+pub static DEFAULT_LEVEL: u32 = log::INFO;
+
+/*
+ * Messages handled by the logging Actor.
+ */
+
+// A single log record.
+#[deriving(Clone)]
+pub struct LogEntry {
+  pub level: u32,
+  pub path: String,
+  pub msg: String,
+  pub timestamp: Timespec
+}
+impl Message for LogEntry {}
+
+/*
+ * Per-subtree level filtering.
+ */
+#[deriving(Clone)]
+pub struct LogFilter {
+  levels: Arc<RWLock<Vec<(String, u32)>>>
+}
+
+impl LogFilter {
+  pub fn new() -> LogFilter {
+    LogFilter {
+      levels: Arc::new(RWLock::new(vec![(ROOT_ADDRESS.to_string(), DEFAULT_LEVEL)]))
+    }
+  }
+
+  // Sets the most verbose level logged for the Actor at path and
+  // every Actor beneath it, unless a deeper subtree sets its own.
+  pub fn set_level(&self, path: &str, level: u32) {
+    let mut levels = self.levels.write();
+    levels.retain(|&(ref p, _)| p.as_slice() != path);
+    levels.push((path.to_string(), level));
+  }
+
+  // Returns the level of the deepest subtree containing path.
+  pub fn level_for(&self, path: &str) -> u32 {
+    let levels = self.levels.read();
+    let mut best: Option<&(String, u32)> = None;
+    for entry in levels.iter() {
+      let &(ref subtree, _) = entry;
+      if LogFilter::contains(subtree.as_slice(), path) {
+        match best {
+          Some(&(ref b, _)) if b.len() >= subtree.len() => (),
+          _ => best = Some(entry)
+        }
+      }
+    }
+    match best {
+      Some(&(_, level)) => level,
+      None => DEFAULT_LEVEL
+    }
+  }
+
+  // Whether path is subtree itself or lies beneath it.
+  fn contains(subtree: &str, path: &str) -> bool {
+    path == subtree ||
+    (path.starts_with(subtree) &&
+     (subtree.ends_with("/") || path.char_at(subtree.len()) == '/'))
+  }
+}
+
+/*
+ * The handle Actors log through.
+ */
+#[deriving(Clone)]
+pub struct Logger {
+  path: String,
+  agent: Agent,
+  filter: LogFilter
+}
+
+impl Logger {
+  pub fn new(path: String, agent: Agent, filter: LogFilter) -> Logger {
+    Logger {
+      path: path,
+      agent: agent,
+      filter: filter
+    }
+  }
+
+  // Returns a Logger sharing this one's logging Actor and filter,
+  // recording under the given path.
+  pub fn for_path(&self, path: String) -> Logger {
+    Logger::new(path, self.agent.clone(), self.filter.clone())
+  }
+
+  // Returns an Agent to the logging Actor.
+  pub fn agent(&self) -> Agent {
+    self.agent.clone()
+  }
+
+  // Returns the Stage-wide level filter.
+  pub fn filter(&self) -> LogFilter {
+    self.filter.clone()
+  }
+
+  // Whether a record at level would pass this Actor's subtree filter.
+  pub fn enabled(&self, level: u32) -> bool {
+    level <= self.filter.level_for(self.path.as_slice())
+  }
+
+  // Sends a record to the logging Actor if its level is enabled.
+  pub fn log_at(&self, level: u32, msg: &str) {
+    if self.enabled(level) {
+      let entry = LogEntry {
+        level: level,
+        path: self.path.clone(),
+        msg: msg.to_string(),
+        timestamp: time::get_time()
+      };
+      self.agent.fire_and_forget(box entry);
+    }
+  }
+
+  pub fn error(&self, msg: &str) { self.log_at(log::ERROR, msg) }
+  pub fn warn(&self, msg: &str) { self.log_at(log::WARN, msg) }
+  pub fn info(&self, msg: &str) { self.log_at(log::INFO, msg) }
+  pub fn debug(&self, msg: &str) { self.log_at(log::DEBUG, msg) }
+}
+
+// Installed as the task-local logger for each Actor, so the log
+// crate's macros (error!, info!, ...) end up at /system/log.
+impl log::Logger for Logger {
+  fn log(&mut self, record: &LogRecord) {
+    let log::LogLevel(level) = record.level;
+    self.log_at(level, format!("{}", record.args).as_slice());
+  }
+}
+
+/*
+ * The logging Actor itself.
+ */
+pub struct LogActor;
+
+impl LogActor {
+  fn level_name(level: u32) -> &'static str {
+    match level {
+      log::ERROR => "ERROR",
+      log::WARN => "WARN",
+      log::INFO => "INFO",
+      log::DEBUG => "DEBUG",
+      _ => "TRACE"
+    }
+  }
+}
+
+impl Actor for LogActor {
+  fn new() -> LogActor {
+    LogActor
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    match msg.as_ref::<LogEntry>() {
+      Some(entry) => {
+        let stamp = time::at(entry.timestamp).rfc3339();
+        let _ = writeln!(&mut stdio::stdout(), "[{} {} {}] {}",
+                         stamp,
+                         LogActor::level_name(entry.level),
+                         entry.path,
+                         entry.msg);
+      },
+      None => ()
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use log;
+
+  use super::DEFAULT_LEVEL;
+  use super::LogFilter;
+
+  #[test]
+  fn uses_the_default_level_until_one_is_set() {
+    let filter = LogFilter::new();
+    assert_eq!(filter.level_for("/"), DEFAULT_LEVEL);
+    assert_eq!(filter.level_for("/bank/audit"), DEFAULT_LEVEL);
+  }
+
+  #[test]
+  fn uses_the_level_of_the_deepest_subtree() {
+    let filter = LogFilter::new();
+    filter.set_level("/bank", log::DEBUG);
+    filter.set_level("/bank/audit", log::ERROR);
+    assert_eq!(filter.level_for("/bank"), log::DEBUG);
+    assert_eq!(filter.level_for("/bank/teller"), log::DEBUG);
+    assert_eq!(filter.level_for("/bank/audit"), log::ERROR);
+    assert_eq!(filter.level_for("/bank/audit/ledger"), log::ERROR);
+    assert_eq!(filter.level_for("/shop"), DEFAULT_LEVEL);
+  }
+
+  #[test]
+  fn matches_whole_path_segments() {
+    let filter = LogFilter::new();
+    filter.set_level("/bank", log::DEBUG);
+    assert_eq!(filter.level_for("/banker"), DEFAULT_LEVEL);
+  }
+
+  #[test]
+  fn replaces_a_subtree_level_when_set_again() {
+    let filter = LogFilter::new();
+    filter.set_level("/bank", log::DEBUG);
+    filter.set_level("/bank", log::WARN);
+    assert_eq!(filter.level_for("/bank/teller"), log::WARN);
+  }
+}
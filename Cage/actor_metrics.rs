@@ -0,0 +1,268 @@
+/*
+ * Per-Actor metrics, kept by the Cage system as each Actor's mailbox
+ * fills and empties, and read for every running Actor on a Stage
+ * with stage.metrics(). to_prometheus formats them in the Prometheus
+ * text exposition format, for a scraper to collect.
+ *
+ * The Stage also keeps its most recent failures, read with
+ * stage.failures(), to see what went wrong without searching logs.
+ */
+use std::collections::RingBuf;
+use std::collections::Deque;
+use sync::Arc;
+use sync::Mutex;
+use time;
+
+use actor_agent::Agent;
+
+// How many failures a Stage keeps.
+pub static RECENT_FAILURES: uint = 100;
+
+// A snapshot of one Actor's metrics.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct ActorMetrics {
+  pub path: String,
+  // Messages waiting in the mailbox.
+  pub mailbox_size: uint,
+  // Messages taken out of the mailbox since the Actor started.
+  pub processed: uint,
+  // Total time spent handling messages, in nanoseconds.
+  pub receive_ns: u64,
+  // Total time handled messages waited in the mailbox, in nanoseconds.
+  pub wait_ns: u64,
+  // Times the Actor failed while handling a message, or a failure it
+  // reported with Context::failure was handled.
+  pub failures: uint
+}
+
+impl ActorMetrics {
+  // The mean time a message waited in the mailbox, in nanoseconds.
+  pub fn mean_wait_ns(&self) -> u64 {
+    if self.processed == 0 { 0 } else { self.wait_ns / self.processed as u64 }
+  }
+
+  pub fn mean_receive_ns(&self) -> u64 {
+    if self.processed == 0 { 0 } else { self.receive_ns / self.processed as u64 }
+  }
+}
+
+// Snapshots of the metrics of the Actors behind the Agents, by path.
+pub fn collect(agents: &[Agent]) -> Vec<ActorMetrics> {
+  let mut metrics: Vec<ActorMetrics> = agents.iter().map(|agent| agent.metrics()).collect();
+  metrics.sort_by(|a, b| a.path.cmp(&b.path));
+  metrics
+}
+
+// A label value with \, " and newlines escaped.
+fn escape(value: &str) -> String {
+  let mut escaped = String::new();
+  for c in value.chars() {
+    match c {
+      '\\' => escaped.push_str("\\\\"),
+      '"' => escaped.push_str("\\\""),
+      '\n' => escaped.push_str("\\n"),
+      c => escaped.push_char(c)
+    }
+  }
+  escaped
+}
+
+fn seconds(ns: u64) -> f64 {
+  ns as f64 / 1e9
+}
+
+// Adds a family of samples, one per Actor.
+fn write_family(out: &mut String,
+                metrics: &[ActorMetrics],
+                name: &str,
+                kind: &str,
+                help: &str,
+                value: |&ActorMetrics| -> String) {
+  out.push_str(format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind).as_slice());
+  for m in metrics.iter() {
+    out.push_str(name);
+    out.push_str("{path=\"");
+    out.push_str(escape(m.path.as_slice()).as_slice());
+    out.push_str("\"} ");
+    out.push_str(value(m).as_slice());
+    out.push_char('\n');
+  }
+}
+
+// The metrics in the Prometheus text format, one family per metric
+// with a sample per Actor labelled by its path.
+pub fn to_prometheus(metrics: &[ActorMetrics]) -> String {
+  let mut out = String::new();
+  write_family(&mut out, metrics, "cage_mailbox_size", "gauge",
+               "Messages waiting in the Actor's mailbox.",
+               |m| m.mailbox_size.to_string());
+  write_family(&mut out, metrics, "cage_messages_processed_total", "counter",
+               "Messages the Actor has taken out of its mailbox.",
+               |m| m.processed.to_string());
+  write_family(&mut out, metrics, "cage_receive_seconds_total", "counter",
+               "Time the Actor has spent handling messages.",
+               |m| seconds(m.receive_ns).to_string());
+  write_family(&mut out, metrics, "cage_mailbox_wait_seconds_total", "counter",
+               "Time handled messages waited in the Actor's mailbox.",
+               |m| seconds(m.wait_ns).to_string());
+  write_family(&mut out, metrics, "cage_failures_total", "counter",
+               "Times the Actor has failed.",
+               |m| m.failures.to_string());
+  out
+}
+
+/*
+ * Recent failures.
+ */
+
+// One Actor failing.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct FailureRecord {
+  pub path: String,
+  pub type_name: String,
+  // The error the Actor reported, or that its task failed.
+  pub reason: String,
+  // Seconds since the Unix epoch.
+  pub time: i64
+}
+
+// The Stage-wide record of the most recent failures.
+#[deriving(Clone)]
+pub struct FailureLog {
+  failures: Arc<Mutex<RingBuf<FailureRecord>>>
+}
+
+impl FailureLog {
+  pub fn new() -> FailureLog {
+    FailureLog {
+      failures: Arc::new(Mutex::new(RingBuf::new()))
+    }
+  }
+
+  // Called by the Cage system as the Actor behind agent fails.
+  pub fn record(&self, agent: &Agent, reason: String) {
+    let mut failures = self.failures.lock();
+    if failures.len() == RECENT_FAILURES {
+      failures.pop_front();
+    }
+    failures.push_back(FailureRecord {
+      path: agent.path(),
+      type_name: agent.type_name(),
+      reason: reason,
+      time: time::get_time().sec
+    });
+  }
+
+  // The kept failures, oldest first.
+  pub fn recent(&self) -> Vec<FailureRecord> {
+    self.failures.lock().iter().map(|failure| failure.clone()).collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestActorRef;
+  use super::ActorMetrics;
+  use super::to_prometheus;
+
+  fn metrics(path: &str) -> ActorMetrics {
+    ActorMetrics {
+      path: path.to_string(),
+      mailbox_size: 2,
+      processed: 4,
+      receive_ns: 1500000000,
+      wait_ns: 250000000,
+      failures: 1
+    }
+  }
+
+  #[test]
+  fn formats_a_family_per_metric_with_a_sample_per_actor() {
+    let text = to_prometheus([metrics("/bank"), metrics("/bank/audit")]);
+    let lines: Vec<&str> = text.as_slice().lines().collect();
+    assert_eq!(lines.len(), 5 * 4);
+    assert_eq!(lines.slice_to(4), [
+      "# HELP cage_mailbox_size Messages waiting in the Actor's mailbox.",
+      "# TYPE cage_mailbox_size gauge",
+      "cage_mailbox_size{path=\"/bank\"} 2",
+      "cage_mailbox_size{path=\"/bank/audit\"} 2"
+    ].as_slice());
+    assert!(lines.contains(&"# TYPE cage_messages_processed_total counter"));
+    assert!(lines.contains(&"cage_messages_processed_total{path=\"/bank\"} 4"));
+    assert!(lines.contains(&"cage_receive_seconds_total{path=\"/bank\"} 1.5"));
+    assert!(lines.contains(&"cage_mailbox_wait_seconds_total{path=\"/bank\"} 0.25"));
+    assert!(lines.contains(&"cage_failures_total{path=\"/bank\"} 1"));
+  }
+
+  #[test]
+  fn escapes_paths_in_labels() {
+    let text = to_prometheus([metrics("/a\"b\\c\nd")]);
+    assert!(text.as_slice().contains("cage_mailbox_size{path=\"/a\\\"b\\\\c\\nd\"} 2\n"));
+  }
+
+  #[test]
+  fn formats_only_headers_without_actors() {
+    let text = to_prometheus([]);
+    assert_eq!(text.as_slice().lines().count(), 5 * 2);
+    assert!(text.as_slice().lines().all(|line| line.starts_with("# ")));
+  }
+
+  #[deriving(Clone)]
+  struct Work;
+  impl Message for Work {}
+
+  #[deriving(Clone)]
+  struct Oops;
+  impl Message for Oops {}
+
+  // Reports a failure to its parent for each Work.
+  struct Worker;
+
+  impl Actor for Worker {
+    fn new() -> Worker {
+      Worker
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      context.parent().deliver(context.failure(box Oops));
+    }
+  }
+
+  struct Supervisor {
+    worker: Option<Agent>
+  }
+
+  impl Actor for Supervisor {
+    fn new() -> Supervisor {
+      Supervisor { worker: None }
+    }
+
+    fn pre_start(&mut self, context: &mut Context) {
+      self.worker = Some(context.start_child_name::<Worker>("worker".to_string()));
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {}
+  }
+
+  #[test]
+  fn counts_a_failure_once_it_is_handled() {
+    let mut stage = Stage::deterministic();
+    let mut supervisor = TestActorRef::<Supervisor>::new(&mut stage, "supervisor".to_string());
+    let worker = supervisor.actor().worker.clone().unwrap();
+    worker.fire_and_forget(box Work);
+    stage.run_until_idle();
+    assert_eq!(worker.metrics().failures, 0);
+    assert!(stage.failures().is_empty());
+
+    supervisor.run_mailbox();
+    assert_eq!(worker.metrics().failures, 1);
+    let failures = stage.failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures.get(0).path, worker.path());
+  }
+}
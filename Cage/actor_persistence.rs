@@ -0,0 +1,578 @@
+/*
+ * Event-sourced persistent Actors. A PersistentActor turns each
+ * command into events; each event is written to the Stage's Journal
+ * and only then applied to the Actor's state. When the Actor starts,
+ * its events are replayed from the Journal to rebuild that state
+ * before it receives any message.
+ *
+ * A PersistentActor runs inside the Persistent Actor, ex.
+ *   context.start_child_name::<Persistent<AccountEvent, Account>>(name)
+ * Name persistent Actors so their paths, and so their default
+ * persistence ids, are the same from one run to the next.
+ *
+ * Actors that can snapshot their state recover from the latest
+ * snapshot plus the events persisted after it.
+ *
+ * If recovery fails, or the Journal won't take an event, the Actor
+ * stops; commands that reach it meanwhile become dead letters rather
+ * than run against state missing events.
+ *
+ * Events may be any message registered with the Stage's
+ * Serialization, persisted as MessageEvents.
+ */
+use std::any::AnyRefExt;
+use std::cmp;
+use sync::Arc;
+use sync::Mutex;
+use time;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_context::Context;
+use actor_dead_letters::RecipientStopped;
+use actor_journal::Journal;
+use actor_journal::InMemoryJournal;
+use actor_serialization::Serialization;
+use actor_serialization::SerializationError;
+use actor_snapshot::SnapshotStore;
+use actor_snapshot::InMemorySnapshotStore;
+use actor_snapshot::SnapshotMetadata;
+use actor_snapshot::SnapshotCriteria;
+
+// Events are kept in the Journal in encoded form.
+pub trait Event : Send {
+  fn encode(&self) -> String;
+  fn decode(encoded: &str) -> Option<Self>;
+}
+
+// A message persisted through the Stage's Serialization.
+pub struct MessageEvent {
+  pub msg: Box<Message:Send>,
+  encoded: String
+}
+
+impl MessageEvent {
+  // Fails if the message's type is not registered.
+  pub fn new(msg: Box<Message:Send>) -> Result<MessageEvent, SerializationError> {
+    let serialization = Serialization::current().unwrap_or(Serialization::new());
+    let encoded = try!(serialization.to_text(&*msg));
+    Ok(MessageEvent { msg: msg, encoded: encoded })
+  }
+}
+
+impl Event for MessageEvent {
+  fn encode(&self) -> String {
+    self.encoded.clone()
+  }
+
+  fn decode(encoded: &str) -> Option<MessageEvent> {
+    Serialization::current().and_then(|serialization| {
+      serialization.from_text(encoded).ok().map(|msg| {
+        MessageEvent { msg: msg, encoded: encoded.to_string() }
+      })
+    })
+  }
+}
+
+pub trait PersistentActor<E: Event> {
+  fn new() -> Self;
+
+  // Identifies this Actor's events in the Journal; must be the same
+  // every time the Actor starts. Defaults to the Actor's path.
+  fn persistence_id(&self, context: &Context) -> String {
+    context.agent().path()
+  }
+
+  // Handles a command, returning the events to persist for it.
+  fn receive_command(&mut self,
+                     context: &mut Context,
+                     msg: Box<Message>,
+                     sender: Agent) -> Vec<E>;
+
+  // Applies an event to this Actor's state, both during recovery
+  // and once a new event has been persisted.
+  fn apply(&mut self, context: &mut Context, event: &E);
+
+  // Called once a new event is persisted and applied, with the
+  // sender of the command, ex. to acknowledge it.
+  fn persisted(&mut self, context: &mut Context, event: &E, sender: &Agent) {}
+
+  // Called once every stored event has been replayed.
+  fn recovery_completed(&mut self, context: &mut Context) {}
+
+  // Encodes this Actor's state for a snapshot, if it supports them.
+  fn take_snapshot(&self) -> Option<String> {
+    None
+  }
+
+  // Restores this Actor's state from a snapshot taken by take_snapshot.
+  fn apply_snapshot(&mut self, context: &mut Context, snapshot: &str) {}
+
+  // Takes a snapshot automatically after every so many events.
+  fn snapshot_every(&self) -> Option<u64> {
+    None
+  }
+
+  // Called after this Actor permanently ceases receiving messages.
+  fn post_stop(&mut self, context: &mut Context) {}
+}
+
+/*
+ * Messages handled by the Persistent Actor itself.
+ */
+
+// Saves a snapshot of the Actor's state as of its last event.
+#[deriving(Clone)]
+pub struct SaveSnapshot;
+impl Message for SaveSnapshot {}
+
+// Deletes the Actor's snapshots that match.
+#[deriving(Clone)]
+pub struct DeleteSnapshots {
+  pub criteria: SnapshotCriteria
+}
+impl Message for DeleteSnapshots {}
+
+// Deletes the Actor's events numbered to_sequence_nr or lower.
+#[deriving(Clone)]
+pub struct DeleteEvents {
+  pub to_sequence_nr: u64
+}
+impl Message for DeleteEvents {}
+
+/*
+ * The Stage-wide persistence plugins.
+ */
+#[deriving(Clone)]
+pub struct Persistence {
+  journal: Arc<Mutex<Box<Journal:Send>>>,
+  snapshot_store: Arc<Mutex<Box<SnapshotStore:Send>>>
+}
+
+impl Persistence {
+  // Starts with a Journal and SnapshotStore kept in memory.
+  pub fn new() -> Persistence {
+    Persistence {
+      journal: Arc::new(Mutex::new(box InMemoryJournal::new() as Box<Journal:Send>)),
+      snapshot_store: Arc::new(Mutex::new(box InMemorySnapshotStore::new() as Box<SnapshotStore:Send>))
+    }
+  }
+
+  // Replaces the SnapshotStore used by every persistent Actor on the Stage.
+  pub fn set_snapshot_store(&self, snapshot_store: Box<SnapshotStore:Send>) {
+    *self.snapshot_store.lock() = snapshot_store;
+  }
+
+  // Runs f with exclusive use of the SnapshotStore.
+  pub fn with_snapshot_store<R>(&self, f: |&mut SnapshotStore| -> R) -> R {
+    let mut snapshot_store = self.snapshot_store.lock();
+    f(&mut **snapshot_store)
+  }
+
+  // Replaces the Journal used by every persistent Actor on the Stage.
+  pub fn set_journal(&self, journal: Box<Journal:Send>) {
+    *self.journal.lock() = journal;
+  }
+
+  // Runs f with exclusive use of the Journal.
+  pub fn with_journal<R>(&self, f: |&mut Journal| -> R) -> R {
+    let mut journal = self.journal.lock();
+    f(&mut **journal)
+  }
+}
+
+/*
+ * The Actor running a PersistentActor.
+ */
+pub struct Persistent<E, T> {
+  actor: T,
+  persistence_id: String,
+  sequence_nr: u64,
+  // Set once recovery or a write fails; the Actor is then stopping.
+  failed: bool
+}
+
+impl<E: Event, T: PersistentActor<E>> Persistent<E, T> {
+  // Restores the latest snapshot, then replays the events after it.
+  fn recover(&mut self, context: &mut Context) {
+    let id = self.persistence_id.clone();
+    let latest = context.persistence().with_snapshot_store(|store| {
+      store.load_latest(id.as_slice(), &SnapshotCriteria::all())
+    });
+    match latest {
+      Ok(Some(selected)) => {
+        self.actor.apply_snapshot(context, selected.snapshot.as_slice());
+        self.sequence_nr = selected.metadata.sequence_nr;
+      },
+      Ok(None) => (),
+      Err(err) =>
+        context.log().warn(format!("loading snapshot of {} failed: {}", id, err).as_slice())
+    }
+
+    let from = self.sequence_nr + 1;
+    let mut events = Vec::new();
+    let result = context.persistence().with_journal(|journal| {
+      journal.replay(id.as_slice(), from, |seq, encoded| {
+        events.push((seq, Event::decode(encoded)));
+      })
+    });
+    match result {
+      Err(err) => {
+        self.fail(context, format!("recovery of {} failed: {}", id, err));
+        return;
+      },
+      Ok(_) => ()
+    }
+
+    // A history with an event missing isn't the Actor's history.
+    match events.iter().find(|&&(_, ref event)| event.is_none()) {
+      Some(&(seq, _)) => {
+        self.fail(context, format!("recovery of {} failed: could not decode event {}", id, seq));
+        return;
+      },
+      None => ()
+    }
+    for (seq, event) in events.move_iter() {
+      self.actor.apply(context, &event.unwrap());
+      self.sequence_nr = seq;
+    }
+
+    // Events may have been deleted past the last one replayed; new
+    // events mustn't reuse their numbers.
+    let highest = context.persistence().with_journal(|journal| {
+      journal.highest_sequence_nr(id.as_slice())
+    });
+    match highest {
+      Ok(highest) => self.sequence_nr = cmp::max(self.sequence_nr, highest),
+      Err(err) => {
+        self.fail(context, format!("recovery of {} failed: {}", id, err));
+        return;
+      }
+    }
+    self.actor.recovery_completed(context);
+  }
+
+  // Stops the Actor, which takes no more commands.
+  fn fail(&mut self, context: &mut Context, reason: String) {
+    context.log().error(reason.as_slice());
+    self.failed = true;
+    context.agent().deliver(context.kill());
+  }
+
+  // Writes the event, then applies it. The Actor stops if the Journal
+  // can't take the event, rather than run on with unsaved state.
+  fn persist(&mut self, context: &mut Context, event: E, sender: &Agent) -> bool {
+    let id = self.persistence_id.clone();
+    let seq = self.sequence_nr + 1;
+    let encoded = event.encode();
+    let result = context.persistence().with_journal(|journal| {
+      journal.write(id.as_slice(), seq, encoded.as_slice())
+    });
+    match result {
+      Ok(_) => {
+        self.sequence_nr = seq;
+        self.actor.apply(context, &event);
+        self.actor.persisted(context, &event, sender);
+        match self.actor.snapshot_every() {
+          Some(every) if every > 0 && seq % every == 0 => self.save_snapshot(context),
+          _ => ()
+        }
+        true
+      },
+      Err(err) => {
+        self.fail(context, format!("persisting event {} of {} failed: {}", seq, id, err));
+        false
+      }
+    }
+  }
+
+  // Saves the Actor's state as of the last event, if it supports snapshots.
+  fn save_snapshot(&mut self, context: &mut Context) {
+    let snapshot = match self.actor.take_snapshot() {
+      Some(snapshot) => snapshot,
+      None => return
+    };
+    let metadata = SnapshotMetadata {
+      persistence_id: self.persistence_id.clone(),
+      sequence_nr: self.sequence_nr,
+      timestamp: time::get_time().sec
+    };
+    let result = context.persistence().with_snapshot_store(|store| {
+      store.save(&metadata, snapshot.as_slice())
+    });
+    match result {
+      Ok(_) => (),
+      Err(err) =>
+        context.log().warn(format!("saving snapshot {} failed: {}", metadata, err).as_slice())
+    }
+  }
+
+  // Handles the messages persistence itself understands.
+  fn receive_persistence(&mut self, context: &mut Context, msg: &Box<Message>) -> bool {
+    let id = self.persistence_id.clone();
+    if msg.is::<SaveSnapshot>() {
+      self.save_snapshot(context);
+    } else if msg.is::<DeleteSnapshots>() {
+      let criteria = msg.as_ref::<DeleteSnapshots>().unwrap().criteria;
+      let result = context.persistence().with_snapshot_store(|store| {
+        store.delete(id.as_slice(), &criteria)
+      });
+      match result {
+        Ok(_) => (),
+        Err(err) =>
+          context.log().warn(format!("deleting snapshots of {} failed: {}", id, err).as_slice())
+      }
+    } else if msg.is::<DeleteEvents>() {
+      let to = msg.as_ref::<DeleteEvents>().unwrap().to_sequence_nr;
+      let result = context.persistence().with_journal(|journal| {
+        journal.delete_to(id.as_slice(), to)
+      });
+      match result {
+        Ok(_) => (),
+        Err(err) =>
+          context.log().warn(format!("deleting events of {} failed: {}", id, err).as_slice())
+      }
+    } else {
+      return false;
+    }
+    true
+  }
+
+  // Returns the number of the last event persisted or replayed.
+  pub fn last_sequence_nr(&self) -> u64 {
+    self.sequence_nr
+  }
+
+  // Returns the PersistentActor, ex. to look at its state in tests.
+  pub fn actor<'a>(&'a mut self) -> &'a mut T {
+    &mut self.actor
+  }
+}
+
+impl<E: Event, T: PersistentActor<E>> Actor for Persistent<E, T> {
+  fn new() -> Persistent<E, T> {
+    Persistent {
+      actor: PersistentActor::new(),
+      persistence_id: String::new(),
+      sequence_nr: 0,
+      failed: false
+    }
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    if self.failed {
+      let agent = context.agent();
+      agent.dead_letter(msg.clone_me(), &agent, &sender, RecipientStopped);
+      return;
+    }
+    if self.receive_persistence(context, &msg) {
+      return;
+    }
+    let events = self.actor.receive_command(context, msg, sender.clone());
+    for event in events.move_iter() {
+      if !self.persist(context, event, &sender) {
+        break;
+      }
+    }
+  }
+
+  fn pre_start(&mut self, context: &mut Context) {
+    self.persistence_id = self.actor.persistence_id(context);
+    self.recover(context);
+  }
+
+  fn post_stop(&mut self, context: &mut Context) {
+    self.actor.post_stop(context);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use std::io;
+  use std::io::IoError;
+  use std::io::IoResult;
+
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_journal::InMemoryJournal;
+  use actor_journal::Journal;
+  use actor_stage::Stage;
+  use actor_testkit::TestActorRef;
+  use super::DeleteEvents;
+  use super::Event;
+  use super::SaveSnapshot;
+  use super::Persistent;
+  use super::PersistentActor;
+
+  #[deriving(Clone)]
+  struct Deposit(uint);
+  impl Message for Deposit {}
+
+  struct Deposited(uint);
+
+  impl Event for Deposited {
+    fn encode(&self) -> String {
+      let Deposited(amount) = *self;
+      amount.to_string()
+    }
+
+    fn decode(encoded: &str) -> Option<Deposited> {
+      from_str::<uint>(encoded).map(Deposited)
+    }
+  }
+
+  struct Account {
+    balance: uint,
+    commands: uint,
+    // Events applied, and the balance a snapshot restored.
+    applied: uint,
+    restored: Option<uint>
+  }
+
+  impl PersistentActor<Deposited> for Account {
+    fn new() -> Account {
+      Account { balance: 0, commands: 0, applied: 0, restored: None }
+    }
+
+    fn persistence_id(&self, context: &Context) -> String {
+      "account".to_string()
+    }
+
+    fn receive_command(&mut self,
+                       context: &mut Context,
+                       msg: Box<Message>,
+                       sender: Agent) -> Vec<Deposited> {
+      self.commands += 1;
+      match msg.as_ref::<Deposit>() {
+        Some(&Deposit(amount)) => vec!(Deposited(amount)),
+        None => vec!()
+      }
+    }
+
+    fn apply(&mut self, context: &mut Context, event: &Deposited) {
+      let Deposited(amount) = *event;
+      self.balance += amount;
+      self.applied += 1;
+    }
+
+    fn take_snapshot(&self) -> Option<String> {
+      Some(self.balance.to_string())
+    }
+
+    fn apply_snapshot(&mut self, context: &mut Context, snapshot: &str) {
+      self.balance = from_str(snapshot).unwrap();
+      self.restored = Some(self.balance);
+    }
+  }
+
+  // Takes no writes.
+  struct FullJournal;
+
+  impl Journal for FullJournal {
+    fn write(&mut self, persistence_id: &str, sequence_nr: u64, event: &str) -> IoResult<()> {
+      Err(IoError { kind: io::OtherIoError, desc: "journal full", detail: None })
+    }
+
+    fn replay(&mut self,
+              persistence_id: &str,
+              from_sequence_nr: u64,
+              replay: |u64, &str|) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn highest_sequence_nr(&mut self, persistence_id: &str) -> IoResult<u64> {
+      Ok(0)
+    }
+
+    fn delete_to(&mut self, persistence_id: &str, to_sequence_nr: u64) -> IoResult<()> {
+      Ok(())
+    }
+  }
+
+  fn account(stage: &mut Stage, name: &str) -> TestActorRef<Persistent<Deposited, Account>> {
+    TestActorRef::new(stage, name.to_string())
+  }
+
+  #[test]
+  fn recovers_persisted_events() {
+    let mut stage = Stage::deterministic();
+    let mut first = account(&mut stage, "first");
+    first.receive(box Deposit(5));
+    first.receive(box Deposit(7));
+    assert_eq!(first.actor().actor.balance, 12);
+
+    let mut second = account(&mut stage, "second");
+    assert_eq!(second.actor().actor.balance, 12);
+    assert_eq!(second.actor().last_sequence_nr(), 2);
+  }
+
+  #[test]
+  fn recovers_from_a_snapshot_and_the_events_after_it() {
+    let mut stage = Stage::deterministic();
+    let mut first = account(&mut stage, "first");
+    first.receive(box Deposit(5));
+    first.receive(box Deposit(7));
+    first.receive(box SaveSnapshot);
+    first.receive(box Deposit(3));
+
+    let mut second = account(&mut stage, "second");
+    assert_eq!(second.actor().actor.restored, Some(12));
+    assert_eq!(second.actor().actor.applied, 1);
+    assert_eq!(second.actor().actor.balance, 15);
+    assert_eq!(second.actor().last_sequence_nr(), 3);
+  }
+
+  #[test]
+  fn numbers_events_after_those_deleted() {
+    let mut stage = Stage::deterministic();
+    let mut first = account(&mut stage, "first");
+    first.receive(box Deposit(5));
+    first.receive(box DeleteEvents { to_sequence_nr: 1 });
+
+    let mut second = account(&mut stage, "second");
+    assert_eq!(second.actor().last_sequence_nr(), 1);
+    second.receive(box Deposit(7));
+    assert_eq!(second.actor().last_sequence_nr(), 2);
+  }
+
+  #[test]
+  fn takes_no_commands_if_an_event_cant_be_decoded() {
+    let mut stage = Stage::deterministic();
+    let mut journal = InMemoryJournal::new();
+    journal.write("account", 1, "5").unwrap();
+    journal.write("account", 2, "not an amount").unwrap();
+    journal.write("account", 3, "7").unwrap();
+    stage.set_journal(box journal);
+
+    let mut account = account(&mut stage, "account");
+    // Nothing of a history with a gap is applied.
+    assert_eq!(account.actor().actor.balance, 0);
+    account.receive(box Deposit(1));
+    assert_eq!(account.actor().actor.commands, 0);
+    assert_eq!(account.actor().last_sequence_nr(), 0);
+
+    account.run_mailbox();
+    assert!(account.is_stopped());
+  }
+
+  #[test]
+  fn takes_no_commands_once_a_write_fails() {
+    let mut stage = Stage::deterministic();
+    stage.set_journal(box FullJournal);
+
+    let mut account = account(&mut stage, "account");
+    account.receive(box Deposit(5));
+    assert_eq!(account.actor().actor.balance, 0);
+    account.receive(box Deposit(1));
+    assert_eq!(account.actor().actor.commands, 1);
+
+    account.run_mailbox();
+    assert!(account.is_stopped());
+  }
+}
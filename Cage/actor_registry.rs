@@ -0,0 +1,41 @@
+/*
+ * The Registry maps the path of every running Actor on a Stage to
+ * its Agent, so the Cage system can reach a local Actor by path
+ * without a Find travelling the hierarchy.
+ */
+use std::collections::HashMap;
+use sync::Arc;
+use sync::RWLock;
+
+use actor_agent::Agent;
+
+#[deriving(Clone)]
+pub struct Registry {
+  agents: Arc<RWLock<HashMap<String, Agent>>>
+}
+
+impl Registry {
+  pub fn new() -> Registry {
+    Registry { agents: Arc::new(RWLock::new(HashMap::new())) }
+  }
+
+  // Called as an Actor starts.
+  pub fn register(&self, agent: &Agent) {
+    self.agents.write().insert(agent.path(), agent.clone());
+  }
+
+  // Called as an Actor stops.
+  pub fn unregister(&self, agent: &Agent) {
+    self.agents.write().remove(&agent.path());
+  }
+
+  // Returns the Agent of the running Actor at the absolute path.
+  pub fn lookup(&self, path: &str) -> Option<Agent> {
+    self.agents.read().find_equiv(&path).map(|agent| agent.clone())
+  }
+
+  // Returns the Agents of every running Actor.
+  pub fn agents(&self) -> Vec<Agent> {
+    self.agents.read().values().map(|agent| agent.clone()).collect()
+  }
+}
@@ -0,0 +1,895 @@
+/*
+ * Remoting lets Actors on Stages in different processes message one
+ * another over TCP. A Stage listening on host:port is addressed as
+ * cage://system@host:port, and the Actor at /path on it as
+ * cage://system@host:port/path.
+ *
+ * A remote Actor is reached through a local Agent whose path is its
+ * full address, ex. context.remote_agent(uri). What is delivered to
+ * that Agent is written to the remote Stage, which delivers it to
+ * the Actor at the path. Agents inside messages (senders, watchers)
+ * travel as addresses and arrive as Agents for them, so replies,
+ * Watch and Terminated work between Stages as they do within one.
+ *
+ * User messages crossing Stages are encoded by the Stage's
+ * Serialization, so both Stages register their types under the same
+ * manifest names.
+ *
+ * Delivery is at most once. When the connection to a Stage is lost,
+ * local Actors watching Actors there receive Terminated.
+ *
+ * Peers are not authenticated: anything that connects may deliver
+ * any message to any Actor, Kill and Watch included. listen therefore
+ * binds loopback hosts only; listen_unauthenticated binds any host,
+ * for networks where every peer is trusted. Frames longer than the
+ * maximum frame size are refused, and the connection closed, before
+ * anything is allocated for them.
+ */
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::Acceptor;
+use std::io::BufReader;
+use std::io::BufferedReader;
+use std::io::IoError;
+use std::io::IoResult;
+use std::io::Listener;
+use std::io::MemWriter;
+use std::io::net::tcp::TcpListener;
+use std::io::net::tcp::TcpStream;
+use sync::Arc;
+use sync::Mutex;
+use time;
+
+use actor::Message;
+use actor_agent::Agent;
+use actor_dead_letters::NotSerializable;
+use actor_log::Logger;
+use actor_registry::Registry;
+use actor_serialization::Serialization;
+use actor_serialization::SerializationError;
+use cage_message::CageMessage;
+use cage_message::Envelope;
+  use cage_message::UserMessage;
+  use cage_message::Find;
+  use cage_message::Terminated;
+  use cage_message::Failure;
+  use cage_message::Undelivered;
+  use cage_message::Watch;
+  use cage_message::Unwatch;
+  use cage_message::Kill;
+
+pub static SCHEME: &'static str = "cage://";
+
+// The kinds of frame, one per CageMessage variant.
+static USER: u8 = 0;
+static FIND: u8 = 1;
+static TERMINATED: u8 = 2;
+static FAILURE: u8 = 3;
+static UNDELIVERED: u8 = 4;
+static WATCH: u8 = 5;
+static UNWATCH: u8 = 6;
+static KILL: u8 = 7;
+
+// The longest frame read from a connection, unless set otherwise.
+pub static DEFAULT_MAX_FRAME_SIZE: uint = 1024 * 1024;
+// How long an Agent for a remote Actor is kept for reuse, unless set
+// otherwise.
+pub static DEFAULT_PROXY_IDLE_TIMEOUT: u64 = 60000;
+
+// The hosts listen binds.
+static LOOPBACK_HOSTS: [&'static str, ..3] = ["127.0.0.1", "localhost", "::1"];
+
+/*
+ * Addresses.
+ */
+#[deriving(Clone, PartialEq, Eq, Hash, Encodable, Decodable)]
+pub struct RemoteAddress {
+  pub system: String,
+  pub host: String,
+  pub port: u16
+}
+
+impl RemoteAddress {
+  // The address of the Actor at path on this Stage.
+  pub fn uri(&self, path: &str) -> String {
+    format!("{}{}", self, path)
+  }
+
+  // Splits cage://system@host:port/path into its Stage's address and path.
+  pub fn parse(uri: &str) -> Option<(RemoteAddress, String)> {
+    if !uri.starts_with(SCHEME) {
+      return None;
+    }
+    let rest = uri.slice_from(SCHEME.len());
+    let (authority, path) = match rest.find('/') {
+      Some(i) => (rest.slice_to(i), rest.slice_from(i)),
+      None => (rest, "/")
+    };
+    let at = match authority.find('@') {
+      Some(i) => i,
+      None => return None
+    };
+    let colon = match authority.rfind(':') {
+      Some(i) if i > at => i,
+      _ => return None
+    };
+    match from_str::<u16>(authority.slice_from(colon + 1)) {
+      Some(port) => Some((RemoteAddress {
+        system: authority.slice_to(at).to_string(),
+        host: authority.slice(at + 1, colon).to_string(),
+        port: port
+      }, path.to_string())),
+      None => None
+    }
+  }
+}
+
+impl fmt::Show for RemoteAddress {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}{}@{}:{}", SCHEME, self.system, self.host, self.port)
+  }
+}
+
+/*
+ * Frames are written to connections as a length then the bytes.
+ * Strings and encoded messages inside frames are written the same way.
+ */
+fn write_bytes<W: Writer>(w: &mut W, bytes: &[u8]) -> IoResult<()> {
+  try!(w.write_be_u32(bytes.len() as u32));
+  w.write(bytes)
+}
+
+// Reads the bytes, refusing more than max before reading them.
+fn read_bytes<R: Reader>(r: &mut R, max: uint) -> IoResult<Vec<u8>> {
+  let len = try!(r.read_be_u32()) as uint;
+  if len > max {
+    return Err(IoError {
+      kind: io::InvalidInput,
+      desc: "frame is too long",
+      detail: Some(format!("{} bytes, at most {}", len, max))
+    });
+  }
+  r.read_exact(len)
+}
+
+fn read_string<R: Reader>(r: &mut R, max: uint) -> IoResult<String> {
+  match String::from_utf8(try!(read_bytes(r, max))) {
+    Ok(s) => Ok(s),
+    Err(_) => Err(malformed("string is not UTF-8"))
+  }
+}
+
+fn malformed(desc: &'static str) -> IoError {
+  IoError { kind: io::InvalidInput, desc: desc, detail: None }
+}
+
+fn serialization_failed(err: SerializationError) -> IoError {
+  IoError { kind: io::InvalidInput, desc: "serialization failed", detail: Some(err.to_string()) }
+}
+
+/*
+ * The Stage-wide remoting machinery.
+ */
+struct RemotingState {
+  address: Option<RemoteAddress>,
+  // Agents for remote Actors, by address.
+  proxies: HashMap<String, Proxy>,
+  // Milliseconds an Agent in proxies is kept after it was last asked
+  // for, and the time proxies were last swept, in nanoseconds.
+  proxy_idle_timeout: u64,
+  swept: u64,
+  max_frame_size: uint,
+  // Writers to connected Stages, with an id for each connection.
+  connections: HashMap<RemoteAddress, (uint, Sender<Vec<u8>>)>,
+  next_connection: uint,
+  // Local Actors watching each remote Actor.
+  watchers: HashMap<String, Vec<Agent>>
+}
+
+// An Agent for a remote Actor, and when it was last asked for.
+struct Proxy {
+  agent: Agent,
+  used: u64
+}
+
+#[deriving(Clone)]
+pub struct Remoting {
+  state: Arc<Mutex<RemotingState>>,
+  registry: Registry,
+  serialization: Serialization,
+  dead_letters: Agent,
+  log: Logger
+}
+
+impl Remoting {
+  pub fn new(registry: Registry,
+             serialization: Serialization,
+             dead_letters: Agent,
+             log: Logger) -> Remoting {
+    Remoting {
+      state: Arc::new(Mutex::new(RemotingState {
+        address: None,
+        proxies: HashMap::new(),
+        proxy_idle_timeout: DEFAULT_PROXY_IDLE_TIMEOUT,
+        swept: time::precise_time_ns(),
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        connections: HashMap::new(),
+        next_connection: 0,
+        watchers: HashMap::new()
+      })),
+      registry: registry,
+      serialization: serialization,
+      dead_letters: dead_letters,
+      log: log
+    }
+  }
+
+  // Accepts connections from other Stages on host:port, under the
+  // given system name. Port 0 picks a free port; the address
+  // returned holds the one chosen. Only loopback hosts are accepted,
+  // as peers aren't authenticated.
+  pub fn listen(&self, system: &str, host: &str, port: u16) -> IoResult<RemoteAddress> {
+    if !LOOPBACK_HOSTS.iter().any(|&loopback| loopback == host) {
+      return Err(IoError {
+        kind: io::InvalidInput,
+        desc: "listen binds loopback hosts only; see listen_unauthenticated",
+        detail: Some(host.to_string())
+      });
+    }
+    self.listen_unauthenticated(system, host, port)
+  }
+
+  // Listens as listen does, on any host. Whatever can reach host:port
+  // can message, watch and kill every Actor on the Stage.
+  pub fn listen_unauthenticated(&self,
+                                system: &str,
+                                host: &str,
+                                port: u16) -> IoResult<RemoteAddress> {
+    let mut listener = try!(TcpListener::bind(host, port));
+    let bound = try!(listener.socket_name());
+    let mut acceptor = try!(listener.listen());
+    let address = RemoteAddress {
+      system: system.to_string(),
+      host: host.to_string(),
+      port: bound.port
+    };
+    self.state.lock().address = Some(address.clone());
+
+    let remoting = self.clone();
+    spawn(proc() {
+      for stream in acceptor.incoming() {
+        match stream {
+          Ok(stream) => {
+            let remoting = remoting.clone();
+            spawn(proc() remoting.read_from(stream));
+          },
+          Err(err) => remoting.log.warn(format!("accepting a connection failed: {}", err).as_slice())
+        }
+      }
+    });
+    Ok(address)
+  }
+
+  // Sets the longest frame read from another Stage, in bytes.
+  pub fn set_max_frame_size(&self, max_frame_size: uint) {
+    self.state.lock().max_frame_size = max_frame_size;
+  }
+
+  // Sets how long, in milliseconds, an Agent for a remote Actor is
+  // kept for reuse after it was last asked for. Agents already handed
+  // out keep working; the proxy behind one ends once they are dropped.
+  pub fn set_proxy_idle_timeout(&self, millis: u64) {
+    self.state.lock().proxy_idle_timeout = millis;
+  }
+
+  // Returns this Stage's address, once it listens.
+  pub fn address(&self) -> Option<RemoteAddress> {
+    self.state.lock().address.clone()
+  }
+
+  // Returns the address other Stages reach the Agent's Actor by.
+  pub fn uri_of(&self, agent: &Agent) -> Option<String> {
+    let path = agent.path();
+    if path.as_slice().starts_with(SCHEME) {
+      return Some(path);
+    }
+    if path.is_empty() {
+      return None;
+    }
+    self.address().map(|address| address.uri(path.as_slice()))
+  }
+
+  // Returns an Agent for the Actor at the address, or None if the
+  // address is malformed. Addresses on this Stage give local Agents.
+  pub fn agent_for(&self, uri: &str) -> Option<Agent> {
+    let (address, path) = match RemoteAddress::parse(uri) {
+      Some(parsed) => parsed,
+      None => return None
+    };
+    if self.address() == Some(address) {
+      return Some(self.local_agent(uri, path.as_slice()));
+    }
+    let now = time::precise_time_ns();
+    let mut state = self.state.lock();
+    Remoting::sweep(&mut *state, now);
+    match state.proxies.find_mut_equiv(&uri) {
+      Some(proxy) => {
+        proxy.used = now;
+        return Some(proxy.agent.clone());
+      },
+      None => ()
+    }
+    let proxy = self.start_proxy(uri);
+    state.proxies.insert(uri.to_string(), Proxy { agent: proxy.clone(), used: now });
+    Some(proxy)
+  }
+
+  // Forgets the Agents not asked for within the idle timeout, at most
+  // once per timeout.
+  fn sweep(state: &mut RemotingState, now: u64) {
+    let timeout = state.proxy_idle_timeout * 1000000;
+    if now - state.swept < timeout {
+      return;
+    }
+    state.swept = now;
+    let idle: Vec<String> = state.proxies.iter()
+                                 .filter(|&(_, proxy)| now - proxy.used >= timeout)
+                                 .map(|(uri, _)| uri.clone())
+                                 .collect();
+    for uri in idle.iter() {
+      state.proxies.remove(uri);
+    }
+  }
+
+  // Returns the running local Actor at path, or else an Agent that
+  // handles whatever is delivered to it as a stopped Actor would.
+  fn local_agent(&self, uri: &str, path: &str) -> Agent {
+    match self.registry.lookup(path) {
+      Some(agent) => agent,
+      None => self.stopped_agent(uri)
+    }
+  }
+
+  fn stopped_agent(&self, uri: &str) -> Agent {
+    let (send, _) = channel::<Envelope>();
+    let (dir, name) = Remoting::split(uri);
+    Agent::with_dead_letters(send, dir, name, Some(self.dead_letters.clone()))
+  }
+
+  // Splits an address into the directory and name of an Agent.
+  fn split(uri: &str) -> (String, String) {
+    match uri.rfind('/') {
+      Some(i) => (uri.slice_to(i + 1).to_string(), uri.slice_from(i + 1).to_string()),
+      None => (uri.to_string(), String::new())
+    }
+  }
+
+  // Starts the task that writes what is delivered to a remote Actor's
+  // Agent to its Stage. The task keeps no Agent to its own inbox, so
+  // it ends once every Agent to the remote Actor is dropped.
+  fn start_proxy(&self, uri: &str) -> Agent {
+    let (send, recv) = channel::<Envelope>();
+    let (dir, name) = Remoting::split(uri);
+    let proxy = Agent::with_dead_letters(send, dir, name, Some(self.dead_letters.clone()));
+    let remoting = self.clone();
+    let agent = proxy.detached();
+    spawn(proc() {
+      for envelope in recv.iter() {
+        agent.dequeued(envelope.enqueued);
+        remoting.send_remote(&agent, envelope.msg);
+      }
+    });
+    proxy
+  }
+
+  fn send_remote(&self, target: &Agent, cage_msg: CageMessage) {
+    let uri = target.path();
+    let address = match RemoteAddress::parse(uri.as_slice()) {
+      Some((address, _)) => address,
+      None => return
+    };
+    match cage_msg {
+      Watch(ref watcher) => self.add_watcher(uri.as_slice(), watcher),
+      Unwatch(ref unwatcher) => self.remove_watcher(uri.as_slice(), unwatcher),
+      _ => ()
+    }
+
+    let frame = match self.encode(uri.as_slice(), &cage_msg) {
+      Ok(frame) => frame,
+      Err(err) => {
+        self.log.error(format!("could not send to {}: {}", uri, err).as_slice());
+        Remoting::not_serializable(target, cage_msg);
+        return;
+      }
+    };
+    match self.connection(&address) {
+      Some(writer) =>
+        match writer.send_opt(frame) {
+          Ok(_) => return,
+          Err(_) => ()
+        },
+      None => ()
+    }
+
+    // The remote Stage can't be reached; the message is handled as
+    // if its recipient had stopped.
+    match cage_msg {
+      Watch(ref watcher) => self.remove_watcher(uri.as_slice(), watcher),
+      _ => ()
+    }
+    self.stopped_agent(uri.as_slice()).deliver(cage_msg);
+  }
+
+  fn not_serializable(target: &Agent, cage_msg: CageMessage) {
+    match cage_msg {
+      UserMessage(msg, sender) => target.dead_letter(msg, target, &sender, NotSerializable),
+      Find(_, msg, sender) => target.dead_letter(msg, target, &sender, NotSerializable),
+      Failure(err, failed) => target.dead_letter(err, target, &failed, NotSerializable),
+      Undelivered(attempted, orig) => target.dead_letter(orig, &attempted, target, NotSerializable),
+      _ => ()
+    }
+  }
+
+  /*
+   * Frames.
+   */
+  fn encode(&self, target: &str, cage_msg: &CageMessage) -> IoResult<Vec<u8>> {
+    let mut w = MemWriter::new();
+    match *cage_msg {
+      UserMessage(ref msg, ref sender) => {
+        try!(w.write_u8(USER));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(self.write_msg(&mut w, &**msg));
+        try!(self.write_agent(&mut w, sender));
+      },
+      Find(ref path, ref msg, ref sender) => {
+        try!(w.write_u8(FIND));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(w.write_be_u32(path.len() as u32));
+        for token in path.iter() {
+          try!(write_bytes(&mut w, token.as_bytes()));
+        }
+        try!(self.write_msg(&mut w, &**msg));
+        try!(self.write_agent(&mut w, sender));
+      },
+      Terminated(ref terminated) => {
+        try!(w.write_u8(TERMINATED));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(self.write_agent(&mut w, terminated));
+      },
+      Failure(ref err, ref failed) => {
+        try!(w.write_u8(FAILURE));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(self.write_msg(&mut w, &**err));
+        try!(self.write_agent(&mut w, failed));
+      },
+      Undelivered(ref attempted, ref orig) => {
+        try!(w.write_u8(UNDELIVERED));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(self.write_agent(&mut w, attempted));
+        try!(self.write_msg(&mut w, &**orig));
+      },
+      Watch(ref watcher) => {
+        try!(w.write_u8(WATCH));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(self.write_agent(&mut w, watcher));
+      },
+      Unwatch(ref unwatcher) => {
+        try!(w.write_u8(UNWATCH));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(self.write_agent(&mut w, unwatcher));
+      },
+      Kill(ref killer) => {
+        try!(w.write_u8(KILL));
+        try!(write_bytes(&mut w, target.as_bytes()));
+        try!(self.write_agent(&mut w, killer));
+      }
+    }
+    Ok(w.unwrap())
+  }
+
+  // Returns the address a frame is for and the message it carries.
+  fn decode(&self, frame: &[u8]) -> IoResult<(String, CageMessage)> {
+    // Strings and messages inside the frame are no longer than it.
+    let max = frame.len();
+    let mut r = BufReader::new(frame);
+    let kind = try!(r.read_u8());
+    let target = try!(read_string(&mut r, max));
+    let cage_msg = match kind {
+      USER => {
+        let msg = try!(self.read_msg(&mut r, max));
+        UserMessage(msg, try!(self.read_agent(&mut r, max)))
+      },
+      FIND => {
+        let len = try!(r.read_be_u32());
+        let mut path = Vec::new();
+        for _ in range(0, len) {
+          path.push(try!(read_string(&mut r, max)));
+        }
+        let msg = try!(self.read_msg(&mut r, max));
+        Find(path, msg, try!(self.read_agent(&mut r, max)))
+      },
+      TERMINATED => Terminated(try!(self.read_agent(&mut r, max))),
+      FAILURE => {
+        let err = try!(self.read_msg(&mut r, max));
+        Failure(err, try!(self.read_agent(&mut r, max)))
+      },
+      UNDELIVERED => {
+        let attempted = try!(self.read_agent(&mut r, max));
+        Undelivered(attempted, try!(self.read_msg(&mut r, max)))
+      },
+      WATCH => Watch(try!(self.read_agent(&mut r, max))),
+      UNWATCH => Unwatch(try!(self.read_agent(&mut r, max))),
+      KILL => Kill(try!(self.read_agent(&mut r, max))),
+      _ => return Err(malformed("unknown kind of frame"))
+    };
+    Ok((target, cage_msg))
+  }
+
+  fn write_msg(&self, w: &mut MemWriter, msg: &Message) -> IoResult<()> {
+    let serialized = match self.serialization.serialize(msg) {
+      Ok(serialized) => serialized,
+      Err(err) => return Err(serialization_failed(err))
+    };
+    try!(write_bytes(w, serialized.manifest.as_bytes()));
+    write_bytes(w, serialized.bytes.as_slice())
+  }
+
+  fn read_msg(&self, r: &mut BufReader, max: uint) -> IoResult<Box<Message:Send>> {
+    let manifest = try!(read_string(r, max));
+    let bytes = try!(read_bytes(r, max));
+    match self.serialization.deserialize(manifest.as_slice(), bytes.as_slice()) {
+      Ok(msg) => Ok(msg),
+      Err(err) => Err(serialization_failed(err))
+    }
+  }
+
+  // Agents without an address (ex. those of request()) travel as the
+  // empty string, and arrive as the dead letter office.
+  fn write_agent(&self, w: &mut MemWriter, agent: &Agent) -> IoResult<()> {
+    let uri = self.uri_of(agent).unwrap_or(String::new());
+    write_bytes(w, uri.as_bytes())
+  }
+
+  fn read_agent(&self, r: &mut BufReader, max: uint) -> IoResult<Agent> {
+    let uri = try!(read_string(r, max));
+    Ok(self.agent_for(uri.as_slice()).unwrap_or(self.dead_letters.clone()))
+  }
+
+  /*
+   * Connections.
+   */
+
+  // Reads frames from another Stage until the connection closes,
+  // delivering each to the local Actor it is addressed to. A frame
+  // too long to read closes the connection.
+  fn read_from(&self, stream: TcpStream) {
+    let mut reader = BufferedReader::new(stream);
+    loop {
+      let max = self.state.lock().max_frame_size;
+      let frame = match read_bytes(&mut reader, max) {
+        Ok(frame) => frame,
+        Err(err) => {
+          if err.kind != io::EndOfFile {
+            self.log.warn(format!("closed a connection from another Stage: {}", err).as_slice());
+          }
+          break;
+        }
+      };
+      match self.decode(frame.as_slice()) {
+        Ok((target, cage_msg)) => self.deliver_local(target.as_slice(), cage_msg),
+        Err(err) => self.log.warn(format!("dropped a message from another Stage: {}", err).as_slice())
+      }
+    }
+  }
+
+  fn deliver_local(&self, uri: &str, cage_msg: CageMessage) {
+    let agent = match RemoteAddress::parse(uri) {
+      Some((address, path)) =>
+        if self.address() == Some(address) {
+          self.local_agent(uri, path.as_slice())
+        } else {
+          self.stopped_agent(uri)
+        },
+      None => self.stopped_agent(uri)
+    };
+    match cage_msg {
+      Terminated(ref terminated) => self.remove_watcher(terminated.path().as_slice(), &agent),
+      _ => ()
+    }
+    agent.deliver(cage_msg);
+  }
+
+  // Returns the writer to the Stage at address, connecting if needed.
+  fn connection(&self, address: &RemoteAddress) -> Option<Sender<Vec<u8>>> {
+    match self.state.lock().connections.find(address) {
+      Some(&(_, ref writer)) => return Some(writer.clone()),
+      None => ()
+    }
+    // Connecting may take as long as the remote host takes to answer;
+    // the state isn't locked meanwhile, so other Stages can be reached.
+    let stream = match TcpStream::connect(address.host.as_slice(), address.port) {
+      Ok(stream) => stream,
+      Err(err) => {
+        self.log.warn(format!("could not connect to {}: {}", address, err).as_slice());
+        return None;
+      }
+    };
+    let mut state = self.state.lock();
+    // Another proxy may have connected first; its connection is kept,
+    // and this one closed.
+    match state.connections.find(address) {
+      Some(&(_, ref writer)) => return Some(writer.clone()),
+      None => ()
+    }
+    state.next_connection += 1;
+    let id = state.next_connection;
+    let (send, recv) = channel::<Vec<u8>>();
+    state.connections.insert(address.clone(), (id, send.clone()));
+    self.start_connection(address.clone(), id, stream, recv);
+    Some(send)
+  }
+
+  // Writes frames to the connection. The remote Stage never writes
+  // back on it, so a read returning marks the connection lost.
+  fn start_connection(&self,
+                      address: RemoteAddress,
+                      id: uint,
+                      stream: TcpStream,
+                      frames: Receiver<Vec<u8>>) {
+    let remoting = self.clone();
+    let mut reader = stream.clone();
+    let closed = address.clone();
+    spawn(proc() {
+      let mut buf = [0u8, ..1];
+      loop {
+        match reader.read(buf) {
+          Ok(_) => (),
+          Err(_) => break
+        }
+      }
+      remoting.disconnected(&closed, id);
+    });
+
+    let remoting = self.clone();
+    spawn(proc() {
+      let mut stream = stream;
+      for frame in frames.iter() {
+        match write_bytes(&mut stream, frame.as_slice()) {
+          Ok(_) => (),
+          Err(_) => break
+        }
+      }
+      remoting.disconnected(&address, id);
+    });
+  }
+
+  // Forgets the connection, if still current, and tells every local
+  // Actor watching an Actor on its Stage that the Actor terminated.
+  fn disconnected(&self, address: &RemoteAddress, id: uint) {
+    let prefix = address.uri("/");
+    let lost = {
+      let mut state = self.state.lock();
+      match state.connections.find(address) {
+        Some(&(current, _)) if current == id => (),
+        _ => return
+      }
+      state.connections.remove(address);
+      let uris: Vec<String> = state.watchers.keys()
+                                           .filter(|uri| uri.as_slice().starts_with(prefix.as_slice()))
+                                           .map(|uri| uri.clone())
+                                           .collect();
+      let mut lost = Vec::new();
+      for uri in uris.move_iter() {
+        let watchers = state.watchers.pop(&uri).unwrap_or(Vec::new());
+        lost.push((self.stopped_agent(uri.as_slice()), watchers));
+      }
+      // Agents to Actors on the Stage are forgotten too; those still
+      // held reconnect when next used.
+      let stale: Vec<String> = state.proxies.keys()
+                                    .filter(|uri| uri.as_slice().starts_with(prefix.as_slice()))
+                                    .map(|uri| uri.clone())
+                                    .collect();
+      for uri in stale.iter() {
+        state.proxies.remove(uri);
+      }
+      lost
+    };
+    self.log.warn(format!("lost connection to {}", address).as_slice());
+    for (proxy, watchers) in lost.move_iter() {
+      for watcher in watchers.iter() {
+        watcher.deliver(Terminated(proxy.clone()));
+      }
+    }
+  }
+
+  fn add_watcher(&self, uri: &str, watcher: &Agent) {
+    let mut state = self.state.lock();
+    let watchers = state.watchers.find_or_insert(uri.to_string(), Vec::new());
+    if !watchers.contains(watcher) {
+      watchers.push(watcher.clone());
+    }
+  }
+
+  fn remove_watcher(&self, uri: &str, watcher: &Agent) {
+    let mut state = self.state.lock();
+    match state.watchers.find_mut_equiv(&uri) {
+      Some(watchers) => watchers.retain(|w| w != watcher),
+      None => ()
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use std::io;
+  use std::io::BufReader;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestProbe;
+  use cage_message::UserMessage;
+  use super::RemoteAddress;
+  use super::read_bytes;
+
+  #[deriving(Clone, Encodable, Decodable)]
+  struct Ping {
+    n: int
+  }
+  impl Message for Ping {}
+
+  #[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+  struct Pong {
+    n: int
+  }
+  impl Message for Pong {}
+
+  // Asks the Actor at uri to ping, and hands the answer to probe.
+  #[deriving(Clone)]
+  struct Relay {
+    uri: String,
+    probe: Agent
+  }
+  impl Message for Relay {}
+
+  struct Relayer {
+    probe: Option<Agent>
+  }
+
+  impl Actor for Relayer {
+    fn new() -> Relayer {
+      Relayer { probe: None }
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      match msg.as_ref::<Relay>() {
+        Some(relay) => {
+          self.probe = Some(relay.probe.clone());
+          let ponger = context.remote_agent(relay.uri.as_slice()).unwrap();
+          ponger.deliver(context.send(box Ping { n: 1 }));
+        },
+        None => ()
+      }
+      match (msg.as_ref::<Pong>(), &self.probe) {
+        (Some(pong), &Some(ref probe)) => probe.deliver(UserMessage(box pong.clone(), sender)),
+        _ => ()
+      }
+    }
+  }
+
+  struct Ponger;
+
+  impl Actor for Ponger {
+    fn new() -> Ponger {
+      Ponger
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      match msg.as_ref::<Ping>() {
+        Some(&Ping { n }) => sender.deliver(context.send(box Pong { n: n + 1 })),
+        None => ()
+      }
+    }
+  }
+
+  #[test]
+  fn parses_addresses() {
+    let (address, path) = RemoteAddress::parse("cage://bank@10.0.0.5:2552/accounts/1").unwrap();
+    assert_eq!(address.system.as_slice(), "bank");
+    assert_eq!(address.host.as_slice(), "10.0.0.5");
+    assert_eq!(address.port, 2552);
+    assert_eq!(path.as_slice(), "/accounts/1");
+    assert_eq!(address.uri(path.as_slice()).as_slice(), "cage://bank@10.0.0.5:2552/accounts/1");
+
+    let (_, path) = RemoteAddress::parse("cage://bank@localhost:2552").unwrap();
+    assert_eq!(path.as_slice(), "/");
+  }
+
+  #[test]
+  fn rejects_malformed_addresses() {
+    assert!(RemoteAddress::parse("http://bank@localhost:2552/a").is_none());
+    assert!(RemoteAddress::parse("cage://localhost:2552/a").is_none());
+    assert!(RemoteAddress::parse("cage://bank@localhost/a").is_none());
+    assert!(RemoteAddress::parse("cage://bank@localhost:port/a").is_none());
+    assert!(RemoteAddress::parse("cage://bank@localhost:70000/a").is_none());
+  }
+
+  #[test]
+  fn messages_actors_on_another_stage() {
+    let mut pings = Stage::new();
+    let mut pongs = Stage::new();
+    for stage in [&pings, &pongs].iter() {
+      stage.serialization().register_json::<Ping>("ping");
+      stage.serialization().register_json::<Pong>("pong");
+    }
+    pings.listen("pings", "127.0.0.1", 0).unwrap();
+    let address = pongs.listen("pongs", "127.0.0.1", 0).unwrap();
+    pongs.start_name::<Ponger>("ponger".to_string());
+    let relayer = pings.start_name::<Relayer>("relayer".to_string());
+
+    let mut probe = TestProbe::new(&pings);
+    probe.send(&relayer, box Relay { uri: address.uri("/ponger"), probe: probe.agent() });
+    assert_eq!(probe.expect_msg::<Pong>(5000), Pong { n: 2 });
+    assert_eq!(probe.sender().unwrap().path(), address.uri("/ponger"));
+  }
+
+  #[test]
+  fn refuses_frames_longer_than_the_maximum() {
+    let frame = [0u8, 0, 0, 2, 7, 9];
+    assert_eq!(read_bytes(&mut BufReader::new(frame), 2).unwrap(), vec![7u8, 9]);
+
+    // Nothing is allocated for the 4 GiB the length claims.
+    let huge = [0xffu8, 0xff, 0xff, 0xff];
+    let err = read_bytes(&mut BufReader::new(huge), 1024).unwrap_err();
+    assert_eq!(err.kind, io::InvalidInput);
+  }
+
+  #[test]
+  fn listens_on_loopback_hosts_only() {
+    let stage = Stage::new();
+    let err = stage.listen("open", "0.0.0.0", 0).unwrap_err();
+    assert_eq!(err.kind, io::InvalidInput);
+    assert!(stage.remoting().address().is_none());
+  }
+
+  #[test]
+  fn forgets_agents_for_remote_actors_once_idle() {
+    let remoting = Stage::new().remoting();
+    remoting.set_proxy_idle_timeout(0);
+    remoting.agent_for("cage://far@127.0.0.1:2/a").unwrap();
+    remoting.agent_for("cage://far@127.0.0.1:2/b").unwrap();
+    let state = remoting.state.lock();
+    let proxies: Vec<&String> = state.proxies.keys().collect();
+    assert_eq!(proxies, vec![&"cage://far@127.0.0.1:2/b".to_string()]);
+  }
+
+  #[test]
+  fn keeps_forgotten_agents_to_remote_actors_working() {
+    let mut pings = Stage::new();
+    let mut pongs = Stage::new();
+    for stage in [&pings, &pongs].iter() {
+      stage.serialization().register_json::<Ping>("ping");
+      stage.serialization().register_json::<Pong>("pong");
+      stage.remoting().set_proxy_idle_timeout(0);
+    }
+    pings.listen("pings", "127.0.0.1", 0).unwrap();
+    let address = pongs.listen("pongs", "127.0.0.1", 0).unwrap();
+    pongs.start_name::<Ponger>("ponger".to_string());
+    let relayer = pings.start_name::<Relayer>("relayer".to_string());
+
+    let mut probe = TestProbe::new(&pings);
+    for _ in range(0u, 2) {
+      probe.send(&relayer, box Relay { uri: address.uri("/ponger"), probe: probe.agent() });
+      assert_eq!(probe.expect_msg::<Pong>(5000), Pong { n: 2 });
+    }
+    // The Ponger's Agent came back through a proxy since forgotten.
+    let ponger = probe.sender().unwrap();
+    probe.send(&ponger, box Ping { n: 5 });
+    assert_eq!(probe.expect_msg::<Pong>(5000), Pong { n: 6 });
+  }
+}
@@ -0,0 +1,698 @@
+/*
+ * Routers own a pool of routee Actors of a single type, started as
+ * their children, and pass each message they receive on to one or
+ * more routees according to a Routing strategy. Routees that stop
+ * are replaced, keeping the pool at its configured size, and an
+ * optional Resizer grows and shrinks the pool with load. A pool's
+ * optional init message is the first message every routee receives,
+ * replacements and routees the Resizer adds included.
+ *
+ * Messages with no routee to go to (ex. a message not wrapped in
+ * ConsistentHashable under ConsistentHashing) become dead letters.
+ */
+use std::any::AnyRefExt;
+use std::collections::TreeMap;
+use std::hash;
+use std::rand;
+use std::rand::Rng;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_context::Context;
+use actor_dead_letters::NoRecipient;
+
+// Virtual nodes per routee used by consistent hashing unless configured.
+pub static DEFAULT_VIRTUAL_NODES: uint = 10;
+
+/*
+ * How a Router picks routees for a message.
+ */
+#[deriving(Clone, PartialEq, Show)]
+pub enum Routing {
+  // Each routee in turn.
+  RoundRobin,
+  // A routee chosen at random.
+  Random,
+  // Every routee.
+  Broadcast,
+  // The routee with the fewest messages waiting.
+  SmallestMailbox,
+  // The routee owning the key of a ConsistentHashable message on a
+  // hash ring, with the given number of virtual nodes per routee.
+  ConsistentHashing(uint)
+}
+
+/*
+ * A hash ring of routees. Each routee owns several points (virtual
+ * nodes) on the ring, and a key belongs to the routee owning the
+ * first point at or after the key's hash. Adding or removing a
+ * routee only moves the keys next to its own points.
+ */
+pub struct ConsistentHashRing {
+  virtual_nodes: uint,
+  ring: TreeMap<u64, Agent>
+}
+
+impl ConsistentHashRing {
+  pub fn new(virtual_nodes: uint) -> ConsistentHashRing {
+    ConsistentHashRing {
+      virtual_nodes: if virtual_nodes == 0 { DEFAULT_VIRTUAL_NODES } else { virtual_nodes },
+      ring: TreeMap::new()
+    }
+  }
+
+  pub fn add(&mut self, routee: &Agent) {
+    for i in range(0, self.virtual_nodes) {
+      self.ring.insert(ConsistentHashRing::point(routee, i), routee.clone());
+    }
+  }
+
+  // Removes the routee's points. A point another routee's collided
+  // with belongs to whichever was added last, and is left to it.
+  pub fn remove(&mut self, routee: &Agent) {
+    for i in range(0, self.virtual_nodes) {
+      let point = ConsistentHashRing::point(routee, i);
+      if self.ring.find(&point) == Some(routee) {
+        self.ring.remove(&point);
+      }
+    }
+  }
+
+  // Returns the routee owning the key, if the ring has any routees.
+  pub fn lookup(&self, key: &str) -> Option<Agent> {
+    let h = hash::hash(&key);
+    match self.ring.lower_bound(&h).next() {
+      Some((_, routee)) => Some(routee.clone()),
+      None => self.ring.iter().next().map(|(_, routee)| routee.clone())
+    }
+  }
+
+  // Points depend only on the routee's path, so they stay put
+  // while other routees come and go.
+  fn point(routee: &Agent, i: uint) -> u64 {
+    hash::hash(&format!("{}#{}", routee.path(), i))
+  }
+}
+
+pub struct RoutingLogic {
+  routing: Routing,
+  next: uint,
+  ring: Option<ConsistentHashRing>
+}
+
+impl RoutingLogic {
+  pub fn new(routing: Routing) -> RoutingLogic {
+    RoutingLogic {
+      routing: routing,
+      next: 0,
+      ring: match routing {
+        ConsistentHashing(virtual_nodes) => Some(ConsistentHashRing::new(virtual_nodes)),
+        _ => None
+      }
+    }
+  }
+
+  // Called by the Router as routees join and leave.
+  pub fn add_routee(&mut self, routee: &Agent) {
+    match self.ring {
+      Some(ref mut ring) => ring.add(routee),
+      None => ()
+    }
+  }
+  pub fn remove_routee(&mut self, routee: &Agent) {
+    match self.ring {
+      Some(ref mut ring) => ring.remove(routee),
+      None => ()
+    }
+  }
+
+  // Returns the routees a message should go to, given its consistent
+  // hashing key, if it has one.
+  pub fn select(&mut self, key: Option<&str>, routees: &[Agent]) -> Vec<Agent> {
+    if routees.is_empty() {
+      return Vec::new();
+    }
+    match self.routing {
+      RoundRobin => {
+        let i = self.next % routees.len();
+        self.next = i + 1;
+        vec![routees[i].clone()]
+      },
+      Random => {
+        let i = rand::task_rng().gen_range(0, routees.len());
+        vec![routees[i].clone()]
+      },
+      Broadcast => routees.to_vec(),
+      SmallestMailbox => {
+        let mut smallest = &routees[0];
+        for routee in routees.iter() {
+          if routee.mailbox_size() < smallest.mailbox_size() {
+            smallest = routee;
+          }
+        }
+        vec![smallest.clone()]
+      },
+      ConsistentHashing(_) => {
+        let routee = match (key, &self.ring) {
+          (Some(key), &Some(ref ring)) => ring.lookup(key),
+          _ => None
+        };
+        routee.move_iter().collect()
+      }
+    }
+  }
+}
+
+/*
+ * Messages handled by Routers themselves.
+ */
+
+// Sent by Context::start_pool as a Router's first message.
+pub struct PoolConfig {
+  pub routing: Routing,
+  pub size: uint,
+  pub resizer: Option<Resizer>,
+  // Sent from the Router to each routee it starts, ex. settings the
+  // routees share.
+  pub init: Option<Box<Message:Send>>
+}
+impl Clone for PoolConfig {
+  fn clone(&self) -> PoolConfig {
+    PoolConfig {
+      routing: self.routing,
+      size: self.size,
+      resizer: self.resizer.clone(),
+      init: self.init.as_ref().map(|init| init.clone_me())
+    }
+  }
+}
+impl Message for PoolConfig {}
+
+// Wraps a message with the key a consistent-hashing Router picks a
+// routee by; messages with the same key always reach the same routee,
+// which receives the message unwrapped.
+pub struct ConsistentHashable {
+  pub key: String,
+  pub msg: Box<Message:Send>
+}
+impl Clone for ConsistentHashable {
+  fn clone(&self) -> ConsistentHashable {
+    ConsistentHashable { key: self.key.clone(), msg: self.msg.clone_me() }
+  }
+}
+impl Message for ConsistentHashable {}
+
+// Sent by a resizable Router to itself each time it checks its pool.
+#[deriving(Clone)]
+pub struct ResizeTick;
+impl Message for ResizeTick {}
+
+// Wraps a message that should go to every routee, whatever the Routing.
+pub struct BroadcastMessage {
+  pub msg: Box<Message:Send>
+}
+impl Clone for BroadcastMessage {
+  fn clone(&self) -> BroadcastMessage {
+    BroadcastMessage { msg: self.msg.clone_me() }
+  }
+}
+impl Message for BroadcastMessage {}
+
+// Asks a Router for its current routees, answered with Routees.
+#[deriving(Clone)]
+pub struct GetRoutees;
+impl Message for GetRoutees {}
+
+#[deriving(Clone)]
+pub struct Routees {
+  pub routees: Vec<Agent>
+}
+impl Message for Routees {}
+
+/*
+ * Grows and shrinks a Router's pool with load. Every interval the
+ * Router counts its busy routees (those with pressure_threshold or
+ * more messages pending, counting the one being handled) and the
+ * messages its routees processed.
+ * When every routee is busy the pool grows by rampup_rate; when few
+ * are busy and the routees processed fewer messages than there are
+ * routees, it shrinks by backoff_rate.
+ */
+#[deriving(Clone)]
+pub struct Resizer {
+  pub lower_bound: uint,
+  pub upper_bound: uint,
+  pub pressure_threshold: uint,
+  pub rampup_rate: f64,
+  pub backoff_threshold: f64,
+  pub backoff_rate: f64,
+  // Milliseconds between checks.
+  pub interval: u64
+}
+
+impl Resizer {
+  // A Resizer with the usual rates between the given bounds.
+  pub fn new(lower_bound: uint, upper_bound: uint) -> Resizer {
+    Resizer {
+      lower_bound: lower_bound,
+      upper_bound: upper_bound,
+      pressure_threshold: 1,
+      rampup_rate: 0.2,
+      backoff_threshold: 0.3,
+      backoff_rate: 0.1,
+      interval: 1000
+    }
+  }
+
+  // Returns the pool size to move to from current, given how many
+  // routees are busy and how many messages they processed lately.
+  pub fn resize(&self, current: uint, busy: uint, processed: uint) -> uint {
+    let size = current as f64;
+    let proposed = if current == 0 || busy == current {
+      current + (size * self.rampup_rate).ceil().max(1.0) as uint
+    } else if (busy as f64) < size * self.backoff_threshold && processed < current {
+      current - ((size * self.backoff_rate).floor().max(1.0) as uint).min(current)
+    } else {
+      current
+    };
+    proposed.max(self.lower_bound).min(self.upper_bound)
+  }
+}
+
+/*
+ * The routees of a Router and the logic choosing between them,
+ * shared by pool and group Routers.
+ */
+pub struct RouteeSet {
+  logic: Option<RoutingLogic>,
+  routees: Vec<Agent>
+}
+
+impl RouteeSet {
+  pub fn new() -> RouteeSet {
+    RouteeSet {
+      logic: None,
+      routees: Vec::new()
+    }
+  }
+
+  // Sets how routees are picked, carrying over current routees.
+  pub fn set_routing(&mut self, routing: Routing) {
+    let mut logic = RoutingLogic::new(routing);
+    for routee in self.routees.iter() {
+      logic.add_routee(routee);
+    }
+    self.logic = Some(logic);
+  }
+
+  pub fn routees(&self) -> Vec<Agent> {
+    self.routees.clone()
+  }
+
+  pub fn len(&self) -> uint {
+    self.routees.len()
+  }
+
+  pub fn contains(&self, routee: &Agent) -> bool {
+    self.routees.contains(routee)
+  }
+
+  // Adds a routee, returning false if it was already present.
+  pub fn add(&mut self, routee: Agent) -> bool {
+    if self.contains(&routee) {
+      return false;
+    }
+    match self.logic {
+      Some(ref mut logic) => logic.add_routee(&routee),
+      None => ()
+    }
+    self.routees.push(routee);
+    true
+  }
+
+  // Removes a routee, returning false if it wasn't present.
+  pub fn remove(&mut self, routee: &Agent) -> bool {
+    if !self.contains(routee) {
+      return false;
+    }
+    match self.logic {
+      Some(ref mut logic) => logic.remove_routee(routee),
+      None => ()
+    }
+    self.routees.retain(|r| r != routee);
+    true
+  }
+
+  // Forwards the message to the selected routees, keeping its sender.
+  // With no routee to take it, the message becomes a dead letter.
+  pub fn route(&mut self, context: &Context, msg: Box<Message:Send>, sender: Agent) {
+    let key = msg.as_ref::<ConsistentHashable>().map(|hashable| hashable.key.clone());
+    let msg = match key {
+      Some(_) => msg.as_ref::<ConsistentHashable>().unwrap().msg.clone_me(),
+      None => msg
+    };
+    let selected = match self.logic {
+      Some(ref mut logic) => logic.select(key.as_ref().map(|key| key.as_slice()), self.routees.as_slice()),
+      None => Vec::new()
+    };
+    if selected.is_empty() {
+      let agent = context.agent();
+      agent.dead_letter(msg, &agent, &sender, NoRecipient);
+      return;
+    }
+    for routee in selected.iter() {
+      routee.deliver(context.forward(msg.clone_me(), &sender));
+    }
+  }
+
+  // Forwards the message to every routee, keeping its sender.
+  pub fn broadcast(&self, context: &Context, msg: &Box<Message:Send>, sender: &Agent) {
+    for routee in self.routees.iter() {
+      routee.deliver(context.forward(msg.clone_me(), sender));
+    }
+  }
+}
+
+/*
+ * A pool Router over routees of type T.
+ */
+pub struct Router<T> {
+  size: uint,
+  routees: RouteeSet,
+  resizer: Option<Resizer>,
+  init: Option<Box<Message:Send>>,
+  // Messages received by current routees as of the last ResizeTick.
+  last_received: uint
+}
+
+impl<T: Actor> Router<T> {
+  // Starts a routee as a child, watches it for death and sends it
+  // the pool's init message.
+  fn start_routee(&mut self, context: &mut Context) {
+    let routee = context.start_child::<T>();
+    routee.deliver(context.watch());
+    match self.init {
+      Some(ref init) => routee.deliver(context.send(init.clone_me())),
+      None => ()
+    }
+    self.routees.add(routee);
+  }
+
+  // Takes the routee with the fewest messages waiting out of the
+  // pool, then kills it. Kill is queued behind the messages already
+  // routed to it, so those are still processed.
+  fn stop_routee(&mut self, context: &mut Context) {
+    let routees = self.routees.routees();
+    match routees.iter().min_by(|routee| routee.pending()) {
+      Some(routee) => {
+        self.routees.remove(routee);
+        context.stop_child(routee);
+      },
+      None => ()
+    }
+  }
+
+  fn received(&self) -> uint {
+    self.routees.routees().iter().fold(0, |sum, routee| sum + routee.received_count())
+  }
+
+  // Moves the pool toward the size the Resizer proposes.
+  fn resize(&mut self, context: &mut Context, resizer: &Resizer) {
+    let routees = self.routees.routees();
+    let busy = routees.iter()
+                      .filter(|routee| routee.pending() >= resizer.pressure_threshold)
+                      .count();
+    let received = self.received();
+    let processed = if received > self.last_received { received - self.last_received } else { 0 };
+
+    self.size = resizer.resize(routees.len(), busy, processed);
+    while self.routees.len() < self.size {
+      self.start_routee(context);
+    }
+    while self.routees.len() > self.size {
+      self.stop_routee(context);
+    }
+    self.last_received = self.received();
+  }
+}
+
+impl<T: Actor> Actor for Router<T> {
+  fn new() -> Router<T> {
+    Router {
+      size: 0,
+      routees: RouteeSet::new(),
+      resizer: None,
+      init: None,
+      last_received: 0
+    }
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    if msg.is::<PoolConfig>() {
+      let config = msg.as_ref::<PoolConfig>().unwrap();
+      self.routees.set_routing(config.routing);
+      self.init = config.init.as_ref().map(|init| init.clone_me());
+      self.size = config.size;
+      while self.routees.len() < self.size {
+        self.start_routee(context);
+      }
+      self.resizer = config.resizer.clone();
+      match self.resizer {
+        Some(ref resizer) =>
+          context.schedule_once(resizer.interval, &context.agent(), box ResizeTick),
+        None => ()
+      }
+    } else if msg.is::<ResizeTick>() {
+      match self.resizer.clone() {
+        Some(resizer) => {
+          self.resize(context, &resizer);
+          context.schedule_once(resizer.interval, &context.agent(), box ResizeTick);
+        },
+        None => ()
+      }
+    } else if msg.is::<BroadcastMessage>() {
+      let broadcast = msg.as_ref::<BroadcastMessage>().unwrap();
+      self.routees.broadcast(context, &broadcast.msg, &sender);
+    } else if msg.is::<GetRoutees>() {
+      sender.deliver(context.send(box Routees { routees: self.routees.routees() }));
+    } else {
+      self.routees.route(context, msg.clone_me(), sender);
+    }
+  }
+
+  // Supervision: a routee that stops is replaced by a fresh one.
+  // Routees stopped by the Resizer have already left the pool.
+  fn terminated(&mut self,
+                context: &mut Context,
+                terminated: Agent) {
+    if self.routees.remove(&terminated) {
+      self.start_routee(context);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestProbe;
+  use cage_message::Envelope;
+    use cage_message::Kill;
+  use super::BroadcastMessage;
+  use super::ConsistentHashRing;
+  use super::GetRoutees;
+  use super::PoolConfig;
+  use super::Resizer;
+  use super::Routees;
+  use super::RoutingLogic;
+    use super::RoundRobin;
+    use super::Broadcast;
+    use super::SmallestMailbox;
+    use super::ConsistentHashing;
+
+  fn routees(count: uint) -> (Vec<Agent>, Vec<Receiver<Envelope>>) {
+    let mut agents = Vec::new();
+    let mut inboxes = Vec::new();
+    for i in range(0, count) {
+      let (send, recv) = channel();
+      agents.push(Agent::new(send, "/router/".to_string(), format!("routee{}", i)));
+      inboxes.push(recv);
+    }
+    (agents, inboxes)
+  }
+
+  #[test]
+  fn round_robin_takes_each_routee_in_turn() {
+    let (agents, _inboxes) = routees(3);
+    let mut logic = RoutingLogic::new(RoundRobin);
+    let picked: Vec<Agent> = range(0u, 4).map(|_| logic.select(None, agents.as_slice())[0].clone()).collect();
+    assert!(picked == vec![agents[0].clone(), agents[1].clone(), agents[2].clone(), agents[0].clone()]);
+  }
+
+  #[test]
+  fn broadcast_takes_every_routee() {
+    let (agents, _inboxes) = routees(3);
+    let mut logic = RoutingLogic::new(Broadcast);
+    assert!(logic.select(None, agents.as_slice()) == agents);
+  }
+
+  #[test]
+  fn smallest_mailbox_takes_the_least_loaded_routee() {
+    let (agents, _inboxes) = routees(3);
+    for agent in agents.iter() {
+      if agent.name().as_slice() != "routee1" {
+        agent.deliver(Kill(agent.clone()));
+      }
+    }
+    let mut logic = RoutingLogic::new(SmallestMailbox);
+    assert!(logic.select(None, agents.as_slice()) == vec![agents[1].clone()]);
+  }
+
+  #[test]
+  fn selects_nothing_without_routees() {
+    let mut logic = RoutingLogic::new(RoundRobin);
+    assert!(logic.select(None, []).is_empty());
+  }
+
+  #[test]
+  fn consistent_hashing_keeps_a_key_on_one_routee() {
+    let (agents, _inboxes) = routees(3);
+    let mut ring = ConsistentHashRing::new(10);
+    for agent in agents.iter() {
+      ring.add(agent);
+    }
+    let owner = ring.lookup("account-7").unwrap();
+    for _ in range(0u, 5) {
+      assert!(ring.lookup("account-7").unwrap() == owner);
+    }
+  }
+
+  #[test]
+  fn removing_a_routee_only_moves_its_own_keys() {
+    let (agents, _inboxes) = routees(4);
+    let mut ring = ConsistentHashRing::new(10);
+    for agent in agents.iter() {
+      ring.add(agent);
+    }
+    let keys: Vec<String> = range(0u, 100).map(|i| format!("key{}", i)).collect();
+    let before: Vec<Agent> = keys.iter().map(|key| ring.lookup(key.as_slice()).unwrap()).collect();
+    ring.remove(&agents[0]);
+    for (key, owner) in keys.iter().zip(before.iter()) {
+      let after = ring.lookup(key.as_slice()).unwrap();
+      assert!(after != agents[0]);
+      if *owner != agents[0] {
+        assert!(after == *owner);
+      }
+    }
+  }
+
+  #[test]
+  fn removing_a_routee_keeps_points_owned_by_others() {
+    let (agents, _inboxes) = routees(2);
+    let mut ring = ConsistentHashRing::new(10);
+    ring.add(&agents[0]);
+    // As if the second routee's point collided with the first's.
+    let point = ConsistentHashRing::point(&agents[0], 0);
+    ring.ring.insert(point, agents[1].clone());
+    ring.remove(&agents[0]);
+    assert!(ring.ring.find(&point) == Some(&agents[1]));
+    assert_eq!(ring.ring.len(), 1);
+  }
+
+  #[test]
+  fn consistent_hashing_needs_a_key() {
+    let (agents, _inboxes) = routees(3);
+    let mut logic = RoutingLogic::new(ConsistentHashing(10));
+    for agent in agents.iter() {
+      logic.add_routee(agent);
+    }
+    assert!(logic.select(None, agents.as_slice()).is_empty());
+    assert_eq!(logic.select(Some("account-7"), agents.as_slice()).len(), 1);
+  }
+
+  #[test]
+  fn resizer_grows_when_every_routee_is_busy() {
+    let resizer = Resizer::new(1, 10);
+    assert_eq!(resizer.resize(5, 5, 100), 6);
+    assert_eq!(resizer.resize(0, 0, 0), 1);
+  }
+
+  #[test]
+  fn resizer_shrinks_when_few_routees_are_busy_and_work_is_light() {
+    let resizer = Resizer::new(1, 10);
+    assert_eq!(resizer.resize(10, 1, 3), 9);
+    // Busy enough, or enough work done, to stay.
+    assert_eq!(resizer.resize(10, 5, 3), 10);
+    assert_eq!(resizer.resize(10, 1, 50), 10);
+  }
+
+  #[test]
+  fn resizer_stays_within_its_bounds() {
+    let resizer = Resizer::new(2, 4);
+    assert_eq!(resizer.resize(4, 4, 100), 4);
+    assert_eq!(resizer.resize(2, 0, 0), 2);
+  }
+
+  #[deriving(Clone, PartialEq, Show)]
+  struct Settings(uint);
+  impl Message for Settings {}
+
+  #[deriving(Clone)]
+  struct GetSettings;
+  impl Message for GetSettings {}
+
+  // Keeps the Settings it is sent, and answers GetSettings with them.
+  struct Worker {
+    settings: Option<Settings>
+  }
+
+  impl Actor for Worker {
+    fn new() -> Worker {
+      Worker { settings: None }
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      match msg.as_ref::<Settings>() {
+        Some(settings) => self.settings = Some(settings.clone()),
+        None => if msg.is::<GetSettings>() {
+          sender.deliver(context.send(box self.settings.clone().unwrap_or(Settings(0))));
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn sends_the_init_message_to_every_routee_it_starts() {
+    let mut stage = Stage::deterministic();
+    let router = stage.start_pool::<Worker>(PoolConfig {
+      routing: RoundRobin,
+      size: 2,
+      resizer: None,
+      init: Some(box Settings(7) as Box<Message:Send>)
+    });
+    let mut probe = TestProbe::new(&stage);
+    probe.send(&router, box GetRoutees);
+    let routees = probe.expect_msg::<Routees>(0).routees;
+    assert_eq!(routees.len(), 2);
+
+    // The replacement of a stopped routee gets the settings too.
+    routees[0].deliver(Kill(probe.agent()));
+    stage.run_until_idle();
+    probe.send(&router, box GetRoutees);
+    let replaced = probe.expect_msg::<Routees>(0).routees;
+    assert!(!replaced.contains(&routees[0]));
+
+    probe.send(&router, box BroadcastMessage { msg: box GetSettings as Box<Message:Send> });
+    assert_eq!(probe.expect_msg::<Settings>(0), Settings(7));
+    assert_eq!(probe.expect_msg::<Settings>(0), Settings(7));
+  }
+}
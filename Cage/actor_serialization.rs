@@ -0,0 +1,329 @@
+/*
+ * Serialization turns messages into bytes and back, for remoting,
+ * persistence and logging. Each message type that leaves its task
+ * this way is registered once per Stage under a stable manifest
+ * name, with the Serializer that encodes it:
+ *
+ *   stage.serialization().register_json::<Deposited>("bank.deposited");
+ *   stage.serialization().register_binary::<Ping>("ping");
+ *
+ * Messages registered this way derive Encodable and Decodable.
+ * Stages exchanging or sharing stored messages must register each
+ * type under the same manifest.
+ */
+use std::any::Any;
+use std::any::AnyRefExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::intrinsics::TypeId;
+use std::io::IoError;
+use std::io::MemWriter;
+use std::str;
+use serialize::Decodable;
+use serialize::Encodable;
+use serialize::base64::FromBase64;
+use serialize::base64::ToBase64;
+use serialize::base64::STANDARD;
+use serialize::ebml;
+use serialize::json;
+use sync::Arc;
+use sync::RWLock;
+
+use actor::Message;
+
+// The Stage's Serialization, installed in each Actor's task.
+local_data_key!(CURRENT: Serialization)
+
+/*
+ * Errors.
+ */
+#[deriving(Clone, PartialEq)]
+pub enum SerializationError {
+  // The message's type, by name, was never registered.
+  Unregistered(String),
+  // No type is registered under the manifest.
+  UnknownManifest(String),
+  // The Serializer failed, with its reason.
+  EncodeFailed(String),
+  DecodeFailed(String)
+}
+
+impl fmt::Show for SerializationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Unregistered(ref type_name) =>
+        write!(f, "message type {} is not registered for serialization", type_name),
+      UnknownManifest(ref manifest) =>
+        write!(f, "no message type is registered as \"{}\"", manifest),
+      EncodeFailed(ref reason) => write!(f, "encoding failed: {}", reason),
+      DecodeFailed(ref reason) => write!(f, "decoding failed: {}", reason)
+    }
+  }
+}
+
+/*
+ * Serializers.
+ */
+pub trait Serializer {
+  fn to_bytes(&self, msg: &Message) -> Result<Vec<u8>, SerializationError>;
+  fn from_bytes(&self, bytes: &[u8]) -> Result<Box<Message:Send>, SerializationError>;
+}
+
+// Messages as JSON.
+pub struct JsonSerializer<T>;
+
+impl<'a, T: Message + Encodable<json::Encoder<'a>, IoError>
+                    + Decodable<json::Decoder, json::DecoderError>> Serializer for JsonSerializer<T> {
+  fn to_bytes(&self, msg: &Message) -> Result<Vec<u8>, SerializationError> {
+    match msg.as_ref::<T>() {
+      Some(msg) => Ok(json::Encoder::buffer_encode(msg)),
+      None => Err(Unregistered(msg.type_name()))
+    }
+  }
+
+  fn from_bytes(&self, bytes: &[u8]) -> Result<Box<Message:Send>, SerializationError> {
+    let text = match str::from_utf8(bytes) {
+      Some(text) => text,
+      None => return Err(DecodeFailed("JSON is not UTF-8".to_string()))
+    };
+    match json::decode::<T>(text) {
+      Ok(msg) => Ok(box msg as Box<Message:Send>),
+      Err(err) => Err(DecodeFailed(err.to_string()))
+    }
+  }
+}
+
+// Messages in the compact binary EBML format.
+pub struct BinarySerializer<T>;
+
+impl<'a, T: Message + Encodable<ebml::writer::Encoder<'a, MemWriter>, IoError>
+                    + Decodable<ebml::reader::Decoder<'a>, ebml::Error>> Serializer for BinarySerializer<T> {
+  fn to_bytes(&self, msg: &Message) -> Result<Vec<u8>, SerializationError> {
+    let msg = match msg.as_ref::<T>() {
+      Some(msg) => msg,
+      None => return Err(Unregistered(msg.type_name()))
+    };
+    let mut w = MemWriter::new();
+    {
+      let mut encoder = ebml::writer::Encoder::new(&mut w);
+      match msg.encode(&mut encoder) {
+        Ok(_) => (),
+        Err(err) => return Err(EncodeFailed(err.to_string()))
+      }
+    }
+    Ok(w.unwrap())
+  }
+
+  fn from_bytes(&self, bytes: &[u8]) -> Result<Box<Message:Send>, SerializationError> {
+    let mut decoder = ebml::reader::Decoder::new(ebml::Doc::new(bytes));
+    match Decodable::decode(&mut decoder) {
+      Ok(msg) => {
+        let msg: T = msg;
+        Ok(box msg as Box<Message:Send>)
+      },
+      Err(err) => Err(DecodeFailed(format!("{}", err)))
+    }
+  }
+}
+
+/*
+ * The registry.
+ */
+
+// A message in serialized form.
+#[deriving(Clone, PartialEq)]
+pub struct Serialized {
+  pub manifest: String,
+  pub bytes: Vec<u8>
+}
+
+struct Registration {
+  manifest: String,
+  serializer: Box<Serializer:Send+Share>
+}
+
+struct Registrations {
+  by_type: HashMap<TypeId, Registration>,
+  by_manifest: HashMap<String, TypeId>
+}
+
+#[deriving(Clone)]
+pub struct Serialization {
+  registrations: Arc<RWLock<Registrations>>
+}
+
+impl Serialization {
+  pub fn new() -> Serialization {
+    Serialization {
+      registrations: Arc::new(RWLock::new(Registrations {
+        by_type: HashMap::new(),
+        by_manifest: HashMap::new()
+      }))
+    }
+  }
+
+  // Returns the Serialization of the Stage the current task's Actor
+  // runs on, if any.
+  pub fn current() -> Option<Serialization> {
+    CURRENT.get().map(|current| current.clone())
+  }
+
+  // Makes this the Serialization returned by current() in this task.
+  pub fn set_current(&self) {
+    CURRENT.replace(Some(self.clone()));
+  }
+
+  // Registers messages of type T under the manifest, replacing any
+  // earlier registration of either.
+  pub fn register<T: Message>(&self, manifest: &str, serializer: Box<Serializer:Send+Share>) {
+    let mut registrations = self.registrations.write();
+    registrations.by_manifest.insert(manifest.to_string(), TypeId::of::<T>());
+    registrations.by_type.insert(TypeId::of::<T>(), Registration {
+      manifest: manifest.to_string(),
+      serializer: serializer
+    });
+  }
+
+  pub fn register_json<'a, T: Message + Encodable<json::Encoder<'a>, IoError>
+                                       + Decodable<json::Decoder, json::DecoderError>>(&self, manifest: &str) {
+    self.register::<T>(manifest, box JsonSerializer::<T> as Box<Serializer:Send+Share>);
+  }
+
+  pub fn register_binary<'a, T: Message + Encodable<ebml::writer::Encoder<'a, MemWriter>, IoError>
+                                         + Decodable<ebml::reader::Decoder<'a>, ebml::Error>>(&self, manifest: &str) {
+    self.register::<T>(manifest, box BinarySerializer::<T> as Box<Serializer:Send+Share>);
+  }
+
+  pub fn is_registered(&self, msg: &Message) -> bool {
+    self.registrations.read().by_type.contains_key(&msg.get_type_id())
+  }
+
+  // The manifest the message's type is registered under, if any.
+  pub fn manifest_of(&self, msg: &Message) -> Option<String> {
+    self.registrations.read().by_type.find(&msg.get_type_id()).map(|r| r.manifest.clone())
+  }
+
+  pub fn serialize(&self, msg: &Message) -> Result<Serialized, SerializationError> {
+    let registrations = self.registrations.read();
+    match registrations.by_type.find(&msg.get_type_id()) {
+      Some(registration) => {
+        let bytes = try!(registration.serializer.to_bytes(msg));
+        Ok(Serialized { manifest: registration.manifest.clone(), bytes: bytes })
+      },
+      None => Err(Unregistered(msg.type_name()))
+    }
+  }
+
+  pub fn deserialize(&self, manifest: &str, bytes: &[u8]) -> Result<Box<Message:Send>, SerializationError> {
+    let registrations = self.registrations.read();
+    let registration = registrations.by_manifest
+                                    .find_equiv(&manifest)
+                                    .and_then(|type_id| registrations.by_type.find(type_id));
+    match registration {
+      Some(registration) => registration.serializer.from_bytes(bytes),
+      None => Err(UnknownManifest(manifest.to_string()))
+    }
+  }
+
+  // The message as a single line of text, manifest:base64, for
+  // text stores such as Journals.
+  pub fn to_text(&self, msg: &Message) -> Result<String, SerializationError> {
+    let serialized = try!(self.serialize(msg));
+    Ok(format!("{}:{}", serialized.manifest, serialized.bytes.as_slice().to_base64(STANDARD)))
+  }
+
+  pub fn from_text(&self, text: &str) -> Result<Box<Message:Send>, SerializationError> {
+    let colon = match text.rfind(':') {
+      Some(i) => i,
+      None => return Err(DecodeFailed("text has no manifest".to_string()))
+    };
+    match text.slice_from(colon + 1).from_base64() {
+      Ok(bytes) => self.deserialize(text.slice_to(colon), bytes.as_slice()),
+      Err(err) => Err(DecodeFailed(err.to_string()))
+    }
+  }
+
+  // Describes the message for a log: its manifest, and its content
+  // if that is readable text (ex. JSON).
+  pub fn describe(&self, msg: &Message) -> String {
+    match self.serialize(msg) {
+      Ok(serialized) =>
+        match str::from_utf8(serialized.bytes.as_slice()) {
+          Some(text) => format!("{} {}", serialized.manifest, text),
+          None => serialized.manifest
+        },
+      Err(_) => "unregistered message".to_string()
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+
+  use actor::Message;
+  use super::Serialization;
+  use super::Unregistered;
+  use super::UnknownManifest;
+
+  #[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+  struct Deposited {
+    account: String,
+    amount: int
+  }
+  impl Message for Deposited {}
+
+  #[deriving(Clone)]
+  struct Secret;
+  impl Message for Secret {}
+
+  fn deposited() -> Deposited {
+    Deposited { account: "a-1".to_string(), amount: 15 }
+  }
+
+  #[test]
+  fn round_trips_json_messages_through_text() {
+    let serialization = Serialization::new();
+    serialization.register_json::<Deposited>("bank.deposited");
+    let text = serialization.to_text(&deposited()).unwrap();
+    assert!(text.as_slice().starts_with("bank.deposited:"));
+    let msg = serialization.from_text(text.as_slice()).unwrap();
+    assert_eq!(msg.as_ref::<Deposited>(), Some(&deposited()));
+  }
+
+  #[test]
+  fn round_trips_binary_messages_through_text() {
+    let serialization = Serialization::new();
+    serialization.register_binary::<Deposited>("bank.deposited");
+    let text = serialization.to_text(&deposited()).unwrap();
+    let msg = serialization.from_text(text.as_slice()).unwrap();
+    assert_eq!(msg.as_ref::<Deposited>(), Some(&deposited()));
+  }
+
+  #[test]
+  fn fails_to_decode_unknown_manifests() {
+    let writer = Serialization::new();
+    writer.register_json::<Deposited>("bank.deposited");
+    let text = writer.to_text(&deposited()).unwrap();
+
+    let reader = Serialization::new();
+    match reader.from_text(text.as_slice()) {
+      Err(err) => assert_eq!(err, UnknownManifest("bank.deposited".to_string())),
+      Ok(_) => fail!("decoded a message of an unknown manifest")
+    }
+  }
+
+  #[test]
+  fn names_the_type_of_unregistered_messages() {
+    let serialization = Serialization::new();
+    serialization.register_json::<Deposited>("bank.deposited");
+    match serialization.to_text(&Secret) {
+      Err(err) => {
+        assert_eq!(err, Unregistered(Secret.type_name()));
+        assert!(err.to_string().as_slice().contains("Secret"));
+      },
+      Ok(_) => fail!("serialized an unregistered message")
+    }
+    assert!(!serialization.is_registered(&Secret));
+  }
+}
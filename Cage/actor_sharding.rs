@@ -0,0 +1,613 @@
+/*
+ * Cluster sharding spreads entity Actors of one type across the
+ * Stages of a cluster. Messages for entities are wrapped in a
+ * ShardEnvelope naming the entity, and sent to the ShardRegion for
+ * the type on any Stage, started there with
+ *   stage.start_sharding::<Account>("accounts".to_string(), 100)
+ * at /accounts on every Stage. Entities receive the messages
+ * unwrapped; other messages sent to a region become dead letters.
+ *
+ * Entities are grouped into shards by a hash of their id. Each shard
+ * lives on one Stage at a time; its region starts entities there on
+ * demand, as children named by their ids. The shard coordinator,
+ * which runs in the region on the cluster's leader, decides which
+ * Stage hosts each shard: regions register with it and ask it where
+ * shards live, and it hands shards off between regions to even out
+ * their number as Stages join, and reallocates those of Stages that
+ * leave or go down.
+ *
+ * An entity's state does not move with its shard; entities that
+ * must keep it are persistent Actors. Messages for entities cross
+ * Stages, so their types are registered with the Serialization.
+ * A Stage that doesn't listen for remote connections hosts every
+ * shard itself.
+ */
+use std::any::AnyRefExt;
+use std::collections::HashMap;
+use std::hash;
+use std::uint;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_cluster::CurrentClusterState;
+use actor_cluster::Member;
+use actor_cluster::MemberEvent;
+use actor_cluster::SubscribeCluster;
+use actor_cluster::leader_of;
+  use actor_cluster::Up;
+  use actor_cluster::Leaving;
+use actor_context::Context;
+use actor_dead_letters::NoRecipient;
+use actor_dead_letters::NotSerializable;
+use actor_remote::RemoteAddress;
+
+/*
+ * Messages handled by regions.
+ */
+
+// Wraps a message for the entity with the given id.
+pub struct ShardEnvelope {
+  pub entity_id: String,
+  pub msg: Box<Message:Send>
+}
+impl Clone for ShardEnvelope {
+  fn clone(&self) -> ShardEnvelope {
+    ShardEnvelope { entity_id: self.entity_id.clone(), msg: self.msg.clone_me() }
+  }
+}
+impl Message for ShardEnvelope {}
+
+// A ShardEnvelope on its way to another Stage's region, with the
+// message as Serialization text.
+#[deriving(Clone, Encodable, Decodable)]
+struct EntityMessage {
+  entity_id: String,
+  msg: String
+}
+impl Message for EntityMessage {}
+
+// Sent by Stage::start_sharding as a region's first message.
+#[deriving(Clone)]
+pub struct ShardingConfig {
+  pub type_name: String,
+  pub shards: uint
+}
+impl Message for ShardingConfig {}
+
+// Sent by a region to the coordinator, with the shards it hosts.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct RegisterRegion {
+  pub address: RemoteAddress,
+  pub shards: Vec<uint>
+}
+impl Message for RegisterRegion {}
+
+// Asks the coordinator where a shard lives, answered with a ShardHome.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct GetShardHome {
+  pub shard: uint
+}
+impl Message for GetShardHome {}
+
+#[deriving(Clone, Encodable, Decodable)]
+pub struct ShardHome {
+  pub shard: uint,
+  pub address: RemoteAddress
+}
+impl Message for ShardHome {}
+
+// Tells a region to stop a shard's entities so it can move,
+// answered with HandOffDone.
+#[deriving(Clone, Encodable, Decodable)]
+pub struct HandOff {
+  pub shard: uint
+}
+impl Message for HandOff {}
+
+#[deriving(Clone, Encodable, Decodable)]
+pub struct HandOffDone {
+  pub shard: uint
+}
+impl Message for HandOffDone {}
+
+// Returns the shard an entity belongs to. The hash is the same in
+// every process, so every region agrees.
+pub fn shard_of(entity_id: &str, shards: uint) -> uint {
+  (hash::hash(&entity_id) % shards as u64) as uint
+}
+
+/*
+ * The coordinator's state, kept by the region on the leader.
+ */
+struct Coordinator {
+  regions: Vec<RemoteAddress>,
+  leaving: Vec<RemoteAddress>,
+  allocations: HashMap<uint, RemoteAddress>,
+  // Shards being handed off, and where they go next.
+  moving: HashMap<uint, RemoteAddress>
+}
+
+impl Coordinator {
+  fn new() -> Coordinator {
+    Coordinator {
+      regions: Vec::new(),
+      leaving: Vec::new(),
+      allocations: HashMap::new(),
+      moving: HashMap::new()
+    }
+  }
+
+  fn load(&self, region: &RemoteAddress) -> uint {
+    self.allocations.values().filter(|a| *a == region).count() +
+    self.moving.values().filter(|a| *a == region).count()
+  }
+
+  // The region taking new shards: the staying one with the fewest.
+  fn least_loaded(&self) -> Option<RemoteAddress> {
+    self.regions.iter()
+                .filter(|r| !self.leaving.contains(*r))
+                .min_by(|r| self.load(*r))
+                .map(|r| r.clone())
+  }
+
+  fn allocate(&mut self, shard: uint) -> Option<RemoteAddress> {
+    if self.moving.contains_key(&shard) {
+      return None;
+    }
+    match self.allocations.find(&shard) {
+      Some(home) => return Some(home.clone()),
+      None => ()
+    }
+    let home = self.least_loaded();
+    match home {
+      Some(ref home) => { self.allocations.insert(shard, home.clone()); },
+      None => ()
+    }
+    home
+  }
+
+  // Returns the shards to hand off, and from which region, so that
+  // leaving regions host none and the rest differ by at most one.
+  fn rebalance(&mut self) -> Vec<(uint, RemoteAddress)> {
+    let mut handoffs = Vec::new();
+    loop {
+      let target = match self.least_loaded() {
+        Some(target) => target,
+        None => break
+      };
+      let source = self.regions.iter()
+                               .filter(|r| self.allocations.values().any(|a| a == *r))
+                               .max_by(|r| if self.leaving.contains(*r) { uint::MAX } else { self.load(*r) })
+                               .map(|r| r.clone());
+      let source = match source {
+        Some(source) => source,
+        None => break
+      };
+      if !self.leaving.contains(&source) && self.load(&source) <= self.load(&target) + 1 {
+        break;
+      }
+      let shard = match self.allocations.iter().find(|&(_, a)| *a == source) {
+        Some((shard, _)) => *shard,
+        None => break
+      };
+      self.allocations.remove(&shard);
+      self.moving.insert(shard, target);
+      handoffs.push((shard, source));
+    }
+    handoffs
+  }
+
+  // Forgets a region that can no longer host shards; its shards are
+  // allocated again when next asked for.
+  fn remove(&mut self, region: &RemoteAddress) {
+    self.regions.retain(|r| r != region);
+    self.leaving.retain(|r| r != region);
+    let lost: Vec<uint> = self.allocations.iter()
+                                          .filter(|&(_, a)| a == region)
+                                          .map(|(shard, _)| *shard)
+                                          .collect();
+    for shard in lost.iter() {
+      self.allocations.remove(shard);
+    }
+    let stranded: Vec<uint> = self.moving.iter()
+                                         .filter(|&(_, a)| a == region)
+                                         .map(|(shard, _)| *shard)
+                                         .collect();
+    for shard in stranded.iter() {
+      self.moving.remove(shard);
+    }
+  }
+}
+
+/*
+ * The region for entities of type T.
+ */
+pub struct ShardRegion<T> {
+  type_name: String,
+  shards: uint,
+  address: Option<RemoteAddress>,
+  members: Vec<Member>,
+  // The leader this region last registered with.
+  coordinator: Option<RemoteAddress>,
+  homes: HashMap<uint, RemoteAddress>,
+  entities: HashMap<uint, Vec<Agent>>,
+  // Messages waiting to learn where their shard lives, with their
+  // entity ids and senders.
+  buffered: HashMap<uint, Vec<(String, Box<Message:Send>, Agent)>>,
+  coordinating: Coordinator
+}
+
+impl<T: Actor> ShardRegion<T> {
+  fn region_uri(&self, address: &RemoteAddress) -> String {
+    address.uri(format!("/{}", self.type_name).as_slice())
+  }
+
+  fn region(&self, context: &Context, address: &RemoteAddress) -> Option<Agent> {
+    context.remote_agent(self.region_uri(address).as_slice())
+  }
+
+  // Registers with a new leader, and asks it about waiting shards.
+  fn update_coordinator(&mut self, context: &mut Context) {
+    let address = match self.address {
+      Some(ref address) => address.clone(),
+      None => return
+    };
+    let up = self.members.iter().any(|m| m.address == address && m.status == Up);
+    let leader = leader_of(self.members.as_slice());
+    if !up || leader.is_none() || leader == self.coordinator {
+      return;
+    }
+    self.coordinator = leader.clone();
+    if leader == self.address {
+      self.coordinating = Coordinator::new();
+    }
+    let hosted: Vec<uint> = self.homes.iter()
+                                      .filter(|&(_, home)| *home == address)
+                                      .map(|(shard, _)| *shard)
+                                      .collect();
+    let leader = leader.unwrap();
+    match self.region(context, &leader) {
+      Some(coordinator) => {
+        coordinator.deliver(context.send(box RegisterRegion { address: address, shards: hosted }));
+        for shard in self.buffered.keys() {
+          coordinator.deliver(context.send(box GetShardHome { shard: *shard }));
+        }
+      },
+      None => ()
+    }
+  }
+
+  fn member_changed(&mut self, context: &mut Context, member: Member) {
+    match self.members.iter().position(|m| m.is(&member)) {
+      Some(i) => *self.members.get_mut(i) = member.clone(),
+      None => self.members.push(member.clone())
+    }
+    if !member.is_live() {
+      // Shards there are lost; ask for their new homes when needed.
+      self.homes.retain(|_, home| *home != member.address);
+      self.coordinating.remove(&member.address);
+    } else if member.status == Leaving && self.coordinating.regions.contains(&member.address) {
+      self.coordinating.leaving.push(member.address.clone());
+      self.rebalance(context);
+    }
+    self.update_coordinator(context);
+  }
+
+  fn rebalance(&mut self, context: &mut Context) {
+    for (shard, source) in self.coordinating.rebalance().move_iter() {
+      match self.region(context, &source) {
+        Some(region) => region.deliver(context.send(box HandOff { shard: shard })),
+        None => ()
+      }
+    }
+  }
+
+  // Tells every region where the shard now lives.
+  fn announce(&self, context: &mut Context, shard: uint, home: &RemoteAddress) {
+    for region in self.coordinating.regions.iter() {
+      match self.region(context, region) {
+        Some(region) => region.deliver(context.send(box ShardHome { shard: shard, address: home.clone() })),
+        None => ()
+      }
+    }
+  }
+
+  fn route(&mut self,
+           context: &mut Context,
+           entity_id: String,
+           msg: Box<Message:Send>,
+           sender: Agent) {
+    let shard = shard_of(entity_id.as_slice(), self.shards);
+    let address = match self.address {
+      Some(ref address) => address.clone(),
+      None => return self.deliver_local(context, shard, entity_id, msg, sender)
+    };
+    match self.homes.find(&shard).map(|home| home.clone()) {
+      Some(ref home) if *home == address => self.deliver_local(context, shard, entity_id, msg, sender),
+      Some(home) => {
+        let text = match context.serialization().to_text(&*msg) {
+          Ok(text) => text,
+          Err(err) => {
+            context.log().warn(format!("can't send a message for {} to {}: {}", entity_id, home, err).as_slice());
+            let agent = context.agent();
+            agent.dead_letter(msg, &agent, &sender, NotSerializable);
+            return;
+          }
+        };
+        match self.region(context, &home) {
+          Some(region) => region.deliver(context.forward(box EntityMessage { entity_id: entity_id, msg: text }, &sender)),
+          None => ()
+        }
+      },
+      None => {
+        let first = !self.buffered.contains_key(&shard);
+        self.buffered.find_or_insert(shard, Vec::new()).push((entity_id, msg, sender));
+        match self.coordinator.clone() {
+          Some(ref coordinator) if first =>
+            match self.region(context, coordinator) {
+              Some(coordinator) => coordinator.deliver(context.send(box GetShardHome { shard: shard })),
+              None => ()
+            },
+          _ => ()
+        }
+      }
+    }
+  }
+
+  // Delivers to the entity, starting it if needed.
+  fn deliver_local(&mut self,
+                   context: &mut Context,
+                   shard: uint,
+                   entity_id: String,
+                   msg: Box<Message:Send>,
+                   sender: Agent) {
+    let existing = self.entities.find(&shard)
+                                .and_then(|entities| entities.iter().find(|e| e.name() == entity_id))
+                                .map(|entity| entity.clone());
+    let entity = match existing {
+      Some(entity) => entity,
+      None => {
+        let entity = context.start_child_name::<T>(entity_id);
+        entity.deliver(context.watch());
+        self.entities.find_or_insert(shard, Vec::new()).push(entity.clone());
+        entity
+      }
+    };
+    entity.deliver(context.forward(msg, &sender));
+  }
+
+  fn shard_home(&mut self, context: &mut Context, shard: uint, home: RemoteAddress) {
+    self.homes.insert(shard, home);
+    match self.buffered.pop(&shard) {
+      Some(waiting) =>
+        for (entity_id, msg, sender) in waiting.move_iter() {
+          self.route(context, entity_id, msg, sender);
+        },
+      None => ()
+    }
+  }
+}
+
+impl<T: Actor> Actor for ShardRegion<T> {
+  fn new() -> ShardRegion<T> {
+    ShardRegion {
+      type_name: String::new(),
+      shards: 1,
+      address: None,
+      members: Vec::new(),
+      coordinator: None,
+      homes: HashMap::new(),
+      entities: HashMap::new(),
+      buffered: HashMap::new(),
+      coordinating: Coordinator::new()
+    }
+  }
+
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    if msg.is::<ShardingConfig>() {
+      let config = msg.as_ref::<ShardingConfig>().unwrap();
+      self.type_name = config.type_name.clone();
+      self.shards = if config.shards == 0 { 1 } else { config.shards };
+      self.address = context.remoting().address();
+      let serialization = context.serialization();
+      serialization.register_json::<RegisterRegion>("cage.sharding.register");
+      serialization.register_json::<GetShardHome>("cage.sharding.get-home");
+      serialization.register_json::<ShardHome>("cage.sharding.home");
+      serialization.register_json::<HandOff>("cage.sharding.hand-off");
+      serialization.register_json::<HandOffDone>("cage.sharding.hand-off-done");
+      serialization.register_json::<EntityMessage>("cage.sharding.entity-message");
+      context.cluster().deliver(context.send(box SubscribeCluster));
+    } else if msg.is::<CurrentClusterState>() {
+      self.members = msg.as_ref::<CurrentClusterState>().unwrap().members.clone();
+      self.update_coordinator(context);
+    } else if msg.is::<MemberEvent>() {
+      let member = msg.as_ref::<MemberEvent>().unwrap().member.clone();
+      self.member_changed(context, member);
+    } else if msg.is::<RegisterRegion>() {
+      let register = msg.as_ref::<RegisterRegion>().unwrap();
+      if !self.coordinating.regions.contains(&register.address) {
+        self.coordinating.regions.push(register.address.clone());
+      }
+      for shard in register.shards.iter() {
+        if !self.coordinating.allocations.contains_key(shard) {
+          self.coordinating.allocations.insert(*shard, register.address.clone());
+        }
+      }
+      self.rebalance(context);
+    } else if msg.is::<GetShardHome>() {
+      let shard = msg.as_ref::<GetShardHome>().unwrap().shard;
+      match self.coordinating.allocate(shard) {
+        Some(home) => sender.deliver(context.send(box ShardHome { shard: shard, address: home })),
+        None => ()
+      }
+    } else if msg.is::<HandOffDone>() {
+      let shard = msg.as_ref::<HandOffDone>().unwrap().shard;
+      match self.coordinating.moving.pop(&shard) {
+        Some(home) => {
+          self.coordinating.allocations.insert(shard, home.clone());
+          self.announce(context, shard, &home);
+        },
+        None => ()
+      }
+    } else if msg.is::<ShardHome>() {
+      let home = msg.as_ref::<ShardHome>().unwrap();
+      self.shard_home(context, home.shard, home.address.clone());
+    } else if msg.is::<HandOff>() {
+      // Messages for the shard wait until it has a new home.
+      let shard = msg.as_ref::<HandOff>().unwrap().shard;
+      match self.entities.pop(&shard) {
+        Some(entities) =>
+          for entity in entities.iter() {
+            entity.deliver(context.unwatch());
+            entity.deliver(context.kill());
+          },
+        None => ()
+      }
+      self.homes.remove(&shard);
+      sender.deliver(context.send(box HandOffDone { shard: shard }));
+    } else if msg.is::<ShardEnvelope>() {
+      let envelope = msg.as_ref::<ShardEnvelope>().unwrap().clone();
+      self.route(context, envelope.entity_id, envelope.msg, sender);
+    } else if msg.is::<EntityMessage>() {
+      let entity_msg = msg.as_ref::<EntityMessage>().unwrap().clone();
+      match context.serialization().from_text(entity_msg.msg.as_slice()) {
+        Ok(inner) => self.route(context, entity_msg.entity_id, inner, sender),
+        Err(err) =>
+          context.log().warn(format!("dropped a message for {}: {}", entity_msg.entity_id, err).as_slice())
+      }
+    } else {
+      let agent = context.agent();
+      agent.dead_letter(msg.clone_me(), &agent, &sender, NoRecipient);
+    }
+  }
+
+  // An entity stopped on its own; it starts again on its next message.
+  fn terminated(&mut self,
+                context: &mut Context,
+                terminated: Agent) {
+    for (_, entities) in self.entities.mut_iter() {
+      entities.retain(|e| *e != terminated);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_remote::RemoteAddress;
+  use actor_stage::Stage;
+  use actor_testkit::TestProbe;
+  use super::Coordinator;
+  use super::ShardEnvelope;
+  use super::shard_of;
+
+  #[deriving(Clone, PartialEq, Show)]
+  struct Ping;
+  impl Message for Ping {}
+
+  // Answers each Ping.
+  struct Entity;
+
+  impl Actor for Entity {
+    fn new() -> Entity {
+      Entity
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      if msg.is::<Ping>() {
+        sender.deliver(context.send(box Ping));
+      }
+    }
+  }
+
+  fn address(port: u16) -> RemoteAddress {
+    RemoteAddress { system: "cage".to_string(), host: "127.0.0.1".to_string(), port: port }
+  }
+
+  // A coordinator of regions at ports 1 and 2, the first hosting shards.
+  fn coordinator(shards: &[uint]) -> Coordinator {
+    let mut coordinator = Coordinator::new();
+    coordinator.regions = vec!(address(1), address(2));
+    for shard in shards.iter() {
+      coordinator.allocations.insert(*shard, address(1));
+    }
+    coordinator
+  }
+
+  #[test]
+  fn puts_entities_in_shards_alike_everywhere() {
+    for id in ["alice", "bob", "carol"].iter() {
+      assert!(shard_of(*id, 10) < 10);
+      assert_eq!(shard_of(*id, 10), shard_of(*id, 10));
+    }
+  }
+
+  #[test]
+  fn allocates_shards_to_the_least_loaded_region() {
+    let mut coordinator = coordinator([0, 1]);
+    assert_eq!(coordinator.allocate(2), Some(address(2)));
+    assert_eq!(coordinator.allocate(0), Some(address(1)));
+    // Shards being handed off have no home for now.
+    coordinator.moving.insert(3, address(2));
+    assert_eq!(coordinator.allocate(3), None);
+  }
+
+  #[test]
+  fn hands_off_shards_until_regions_are_even() {
+    let mut coordinator = coordinator([0, 1, 2, 3, 4]);
+    let handoffs = coordinator.rebalance();
+    assert_eq!(handoffs.len(), 2);
+    assert!(handoffs.iter().all(|&(_, ref source)| *source == address(1)));
+    assert_eq!(coordinator.load(&address(1)), 3);
+    assert_eq!(coordinator.load(&address(2)), 2);
+    // Balanced, there is nothing more to move.
+    assert!(coordinator.rebalance().is_empty());
+  }
+
+  #[test]
+  fn hands_off_every_shard_of_a_leaving_region() {
+    let mut coordinator = coordinator([0, 1]);
+    coordinator.allocations.insert(2, address(2));
+    coordinator.leaving.push(address(1));
+    let handoffs = coordinator.rebalance();
+    assert_eq!(handoffs.len(), 2);
+    assert_eq!(coordinator.load(&address(1)), 0);
+    assert_eq!(coordinator.load(&address(2)), 3);
+  }
+
+  #[test]
+  fn forgets_the_shards_of_removed_regions() {
+    let mut coordinator = coordinator([0, 1]);
+    coordinator.moving.insert(2, address(1));
+    coordinator.remove(&address(1));
+    assert_eq!(coordinator.regions, vec!(address(2)));
+    assert!(coordinator.allocations.is_empty());
+    assert!(coordinator.moving.is_empty());
+    assert_eq!(coordinator.allocate(0), Some(address(2)));
+  }
+
+  #[test]
+  fn delivers_enveloped_messages_to_their_entities() {
+    let mut stage = Stage::deterministic();
+    let region = stage.start_sharding::<Entity>("entities".to_string(), 4);
+    let mut probe = TestProbe::new(&stage);
+    probe.send(&region, box ShardEnvelope { entity_id: "alice".to_string(), msg: box Ping as Box<Message:Send> });
+    stage.run_until_idle();
+    assert_eq!(probe.expect_msg::<Ping>(0), Ping);
+    assert_eq!(probe.sender().unwrap().path().as_slice(), "/entities/alice");
+
+    // Without an envelope, there is no entity to deliver to.
+    probe.send(&region, box Ping);
+    stage.run_until_idle();
+    probe.expect_no_msg(100);
+  }
+}
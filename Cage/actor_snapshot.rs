@@ -0,0 +1,279 @@
+/*
+ * Snapshot stores keep encoded snapshots of persistent Actors'
+ * state, each taken as of some event's sequence number, so recovery
+ * can start from the latest snapshot instead of the first event.
+ */
+use std::collections::HashMap;
+use std::io;
+use std::io::File;
+use std::io::IoResult;
+use std::io::fs;
+use std::num::Bounded;
+
+use actor_journal::file_name_of;
+
+#[deriving(Clone, Show)]
+pub struct SnapshotMetadata {
+  pub persistence_id: String,
+  pub sequence_nr: u64,
+  // Seconds since the epoch when the snapshot was taken.
+  pub timestamp: i64
+}
+
+#[deriving(Clone)]
+pub struct SelectedSnapshot {
+  pub metadata: SnapshotMetadata,
+  pub snapshot: String
+}
+
+// Selects snapshots by the sequence numbers they were taken at.
+#[deriving(Clone, Show)]
+pub struct SnapshotCriteria {
+  pub min_sequence_nr: u64,
+  pub max_sequence_nr: u64
+}
+
+impl SnapshotCriteria {
+  // Every snapshot.
+  pub fn all() -> SnapshotCriteria {
+    SnapshotCriteria { min_sequence_nr: 0, max_sequence_nr: Bounded::max_value() }
+  }
+
+  // Snapshots taken at to_sequence_nr or before.
+  pub fn to(to_sequence_nr: u64) -> SnapshotCriteria {
+    SnapshotCriteria { min_sequence_nr: 0, max_sequence_nr: to_sequence_nr }
+  }
+
+  pub fn matches(&self, sequence_nr: u64) -> bool {
+    self.min_sequence_nr <= sequence_nr && sequence_nr <= self.max_sequence_nr
+  }
+}
+
+pub trait SnapshotStore {
+  fn save(&mut self, metadata: &SnapshotMetadata, snapshot: &str) -> IoResult<()>;
+
+  // Returns the matching snapshot with the highest sequence number.
+  fn load_latest(&mut self,
+                 persistence_id: &str,
+                 criteria: &SnapshotCriteria) -> IoResult<Option<SelectedSnapshot>>;
+
+  // Deletes every matching snapshot.
+  fn delete(&mut self, persistence_id: &str, criteria: &SnapshotCriteria) -> IoResult<()>;
+}
+
+/*
+ * A SnapshotStore kept in memory, lost with the Stage.
+ */
+pub struct InMemorySnapshotStore {
+  snapshots: HashMap<String, Vec<SelectedSnapshot>>
+}
+
+impl InMemorySnapshotStore {
+  pub fn new() -> InMemorySnapshotStore {
+    InMemorySnapshotStore { snapshots: HashMap::new() }
+  }
+}
+
+impl SnapshotStore for InMemorySnapshotStore {
+  fn save(&mut self, metadata: &SnapshotMetadata, snapshot: &str) -> IoResult<()> {
+    self.snapshots.find_or_insert(metadata.persistence_id.clone(), Vec::new())
+                  .push(SelectedSnapshot {
+                    metadata: metadata.clone(),
+                    snapshot: snapshot.to_string()
+                  });
+    Ok(())
+  }
+
+  fn load_latest(&mut self,
+                 persistence_id: &str,
+                 criteria: &SnapshotCriteria) -> IoResult<Option<SelectedSnapshot>> {
+    Ok(match self.snapshots.find_equiv(&persistence_id) {
+      Some(snapshots) =>
+        snapshots.iter()
+                 .filter(|s| criteria.matches(s.metadata.sequence_nr))
+                 .max_by(|s| s.metadata.sequence_nr)
+                 .map(|s| s.clone()),
+      None => None
+    })
+  }
+
+  fn delete(&mut self, persistence_id: &str, criteria: &SnapshotCriteria) -> IoResult<()> {
+    match self.snapshots.find_mut_equiv(&persistence_id) {
+      Some(snapshots) => snapshots.retain(|s| !criteria.matches(s.metadata.sequence_nr)),
+      None => ()
+    }
+    Ok(())
+  }
+}
+
+/*
+ * A SnapshotStore kept on disk, one file per snapshot under a
+ * directory, named <persistence id>-<sequence nr>-<timestamp>.snapshot.
+ */
+pub struct FileSnapshotStore {
+  dir: Path
+}
+
+impl FileSnapshotStore {
+  // Uses (and creates, if needed) the given directory.
+  pub fn new(dir: Path) -> IoResult<FileSnapshotStore> {
+    if !dir.exists() {
+      try!(fs::mkdir_recursive(&dir, io::UserRWX));
+    }
+    Ok(FileSnapshotStore { dir: dir })
+  }
+
+  // Escaped, - in the persistence id can't be mistaken for a separator.
+  fn prefix(persistence_id: &str) -> String {
+    file_name_of(persistence_id)
+  }
+
+  // Lists the stored snapshots of the persistence id that match.
+  fn matching(&self,
+              persistence_id: &str,
+              criteria: &SnapshotCriteria) -> IoResult<Vec<(SnapshotMetadata, Path)>> {
+    let prefix = FileSnapshotStore::prefix(persistence_id).append("-");
+    let mut found = Vec::new();
+    for path in try!(fs::readdir(&self.dir)).move_iter() {
+      let name = match path.filename_str() {
+        Some(name) => name.to_string(),
+        None => continue
+      };
+      if !name.as_slice().starts_with(prefix.as_slice()) ||
+         !name.as_slice().ends_with(".snapshot") {
+        continue;
+      }
+      let rest = name.as_slice().slice(prefix.len(), name.len() - ".snapshot".len());
+      let fields: Vec<&str> = rest.split('-').collect();
+      if fields.len() != 2 {
+        continue;
+      }
+      match (from_str::<u64>(fields[0]), from_str::<i64>(fields[1])) {
+        (Some(seq), Some(timestamp)) if criteria.matches(seq) => {
+          let metadata = SnapshotMetadata {
+            persistence_id: persistence_id.to_string(),
+            sequence_nr: seq,
+            timestamp: timestamp
+          };
+          found.push((metadata, path.clone()));
+        },
+        _ => ()
+      }
+    }
+    Ok(found)
+  }
+}
+
+impl SnapshotStore for FileSnapshotStore {
+  // Writes to a temporary file first, so a crash never leaves a
+  // partial snapshot behind.
+  fn save(&mut self, metadata: &SnapshotMetadata, snapshot: &str) -> IoResult<()> {
+    let name = format!("{}-{}-{}.snapshot",
+                       FileSnapshotStore::prefix(metadata.persistence_id.as_slice()),
+                       metadata.sequence_nr,
+                       metadata.timestamp);
+    let tmp = self.dir.join(name.clone().append(".tmp"));
+    {
+      let mut file = try!(File::create(&tmp));
+      try!(file.write_str(snapshot));
+      try!(file.fsync());
+    }
+    fs::rename(&tmp, &self.dir.join(name))
+  }
+
+  fn load_latest(&mut self,
+                 persistence_id: &str,
+                 criteria: &SnapshotCriteria) -> IoResult<Option<SelectedSnapshot>> {
+    let found = try!(self.matching(persistence_id, criteria));
+    match found.move_iter().max_by(|&(ref metadata, _)| metadata.sequence_nr) {
+      Some((metadata, path)) => {
+        let snapshot = try!(File::open(&path).read_to_string());
+        Ok(Some(SelectedSnapshot { metadata: metadata, snapshot: snapshot }))
+      },
+      None => Ok(None)
+    }
+  }
+
+  fn delete(&mut self, persistence_id: &str, criteria: &SnapshotCriteria) -> IoResult<()> {
+    for (_, path) in try!(self.matching(persistence_id, criteria)).move_iter() {
+      try!(fs::unlink(&path));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::TempDir;
+  use std::io::fs;
+
+  use super::FileSnapshotStore;
+  use super::InMemorySnapshotStore;
+  use super::SnapshotCriteria;
+  use super::SnapshotMetadata;
+  use super::SnapshotStore;
+
+  fn save(store: &mut SnapshotStore, persistence_id: &str, sequence_nr: u64, snapshot: &str) {
+    let metadata = SnapshotMetadata {
+      persistence_id: persistence_id.to_string(),
+      sequence_nr: sequence_nr,
+      timestamp: 1400000000 + sequence_nr as i64
+    };
+    store.save(&metadata, snapshot).unwrap();
+  }
+
+  // The sequence number and snapshot of the latest match, if any.
+  fn latest(store: &mut SnapshotStore, persistence_id: &str, criteria: SnapshotCriteria) -> Option<(u64, String)> {
+    store.load_latest(persistence_id, &criteria).unwrap()
+         .map(|selected| (selected.metadata.sequence_nr, selected.snapshot))
+  }
+
+  // Saves snapshots of two ids, loads and deletes by criteria.
+  fn selects_snapshots_by_criteria(store: &mut SnapshotStore) {
+    save(store, "a", 1, "one");
+    save(store, "a", 3, "three");
+    save(store, "a", 2, "two");
+    save(store, "a-b", 5, "five");
+
+    assert_eq!(latest(store, "a", SnapshotCriteria::all()), Some((3, "three".to_string())));
+    assert_eq!(latest(store, "a", SnapshotCriteria::to(2)), Some((2, "two".to_string())));
+    assert_eq!(latest(store, "a", SnapshotCriteria { min_sequence_nr: 4, max_sequence_nr: 9 }), None);
+    assert_eq!(latest(store, "c", SnapshotCriteria::all()), None);
+
+    store.delete("a", &SnapshotCriteria::to(2)).unwrap();
+    assert_eq!(latest(store, "a", SnapshotCriteria::to(2)), None);
+    assert_eq!(latest(store, "a", SnapshotCriteria::all()), Some((3, "three".to_string())));
+    assert_eq!(latest(store, "a-b", SnapshotCriteria::all()), Some((5, "five".to_string())));
+
+    store.delete("a", &SnapshotCriteria::all()).unwrap();
+    assert_eq!(latest(store, "a", SnapshotCriteria::all()), None);
+    assert_eq!(latest(store, "a-b", SnapshotCriteria::all()), Some((5, "five".to_string())));
+  }
+
+  #[test]
+  fn in_memory_store_selects_snapshots_by_criteria() {
+    selects_snapshots_by_criteria(&mut InMemorySnapshotStore::new());
+  }
+
+  #[test]
+  fn file_store_selects_snapshots_by_criteria() {
+    let dir = TempDir::new("snapshots").unwrap();
+    let mut store = FileSnapshotStore::new(dir.path().clone()).unwrap();
+    selects_snapshots_by_criteria(&mut store);
+  }
+
+  #[test]
+  fn file_store_names_files_by_escaped_id_sequence_nr_and_time() {
+    let dir = TempDir::new("snapshots").unwrap();
+    let mut store = FileSnapshotStore::new(dir.path().clone()).unwrap();
+    save(&mut store, "/bank/a-1", 7, "state");
+
+    // The temporary file was renamed into place.
+    let names: Vec<String> = fs::readdir(dir.path()).unwrap().iter()
+                               .filter_map(|path| path.filename_str().map(|name| name.to_string()))
+                               .collect();
+    assert_eq!(names, vec!("%2Fbank%2Fa%2D1-7-1400000007.snapshot".to_string()));
+    assert_eq!(latest(&mut store, "/bank/a-1", SnapshotCriteria::all()), Some((7, "state".to_string())));
+    assert_eq!(latest(&mut store, "/bank/a", SnapshotCriteria::all()), None);
+  }
+}
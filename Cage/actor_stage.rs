@@ -1,12 +1,48 @@
+use std::io::IoResult;
 use sync::Arc;
 use sync::Mutex;
 
 use actor::Actor;
 use actor::Message;
+use actor_admin::Admin;
+use actor_admin::AdminServer;
 use actor_agent::Agent;
 use actor_agent::NO_ADDRESS;
+use actor_agent::SYSTEM_ADDRESS;
+use actor_chaos::Chaos;
+use actor_context;
+use actor_context::ActorCell;
 use actor_context::Context;
+use actor_dead_letters::PathNotFound;
+use actor_dispatcher::Dispatcher;
+use actor_dispatcher::Runnable;
+use actor_dispatcher::Step;
+  use actor_dispatcher::Handled;
+  use actor_dispatcher::Idle;
+use actor_event_stream::EventStream;
+use actor_cluster::JoinCluster;
+use actor_cluster::LeaveCluster;
+use actor_journal::Journal;
+use actor_log::Logger;
+use actor_metrics;
+use actor_metrics::ActorMetrics;
+use actor_metrics::FailureRecord;
+use actor_remote::RemoteAddress;
+use actor_remote::Remoting;
+use actor_serialization::Serialization;
+use actor_sharding::ShardRegion;
+use actor_sharding::ShardingConfig;
+use actor_snapshot::SnapshotStore;
+use actor_router::PoolConfig;
+use actor_router::Routing;
+use actor_trace::Tracer;
+use actor_tree;
+use actor_tree::ActorNode;
+use actor_tree::Running;
+use actor_router::Resizer;
+use actor_dead_letters::Unreturnable;
 use cage_message::CageMessage;
+use cage_message::Envelope;
   use cage_message::UserMessage;
   use cage_message::Find;
   use cage_message::Terminated;
@@ -46,20 +82,241 @@ impl Stage {
   pub fn start_name<T: Actor>(&mut self, name: String) -> Agent {
     self.root.lock().start_child_name::<T>(name)
   }
+
+  // A Router with size routees of type T, each made by T::new.
+  pub fn start_router<T: Actor>(&mut self, routing: Routing, size: uint) -> Agent {
+    self.root.lock().start_router::<T>(routing, size)
+  }
+
+  // A Router whose pool of routees of type T is resized with load.
+  pub fn start_resizable_router<T: Actor>(&mut self, routing: Routing, resizer: Resizer) -> Agent {
+    self.root.lock().start_resizable_router::<T>(routing, resizer)
+  }
+
+  // A Router over a pool configured as given, ex. with an init
+  // message for its routees.
+  pub fn start_pool<T: Actor>(&mut self, config: PoolConfig) -> Agent {
+    self.root.lock().start_pool::<T>(config)
+  }
+
+  // A Router over the existing Actors at the given paths.
+  pub fn start_group_router(&mut self, routing: Routing, paths: Vec<String>) -> Agent {
+    self.root.lock().start_group_router(routing, paths)
+  }
+
+  // The ShardRegion at /type_name for entities of type T, spread over
+  // the given number of shards. Every Stage in the cluster starts one.
+  pub fn start_sharding<T: Actor>(&mut self, type_name: String, shards: uint) -> Agent {
+    let region = self.root.lock().start_child_name::<ShardRegion<T>>(type_name.clone());
+    region.fire_and_forget(box ShardingConfig { type_name: type_name, shards: shards });
+    region
+  }
+
+  // An Actor of type T under the root that only runs when a test
+  // drives it, ex. through a TestActorRef.
+  pub fn start_inline<T: Actor>(&mut self, name: String) -> ActorCell<T> {
+    self.root.lock().inline_child::<T>(name)
+  }
+
+  // Returns an Agent to the dead letter office at /system/deadLetters.
+  pub fn dead_letters(&self) -> Agent {
+    self.root.lock().dead_letters()
+  }
+
+  // Returns the Stage-wide EventStream.
+  pub fn event_stream(&self) -> EventStream {
+    self.root.lock().event_stream()
+  }
+
+  // Returns a Logger recording under the root, for logging from
+  // outside any Actor, ex. stage.log().info("started").
+  pub fn log(&self) -> Logger {
+    self.root.lock().log()
+  }
+
+  // Sets the most verbose log level for the Actor at path and
+  // everything beneath it, ex. stage.set_log_level("/forest", log::DEBUG).
+  pub fn set_log_level(&self, path: &str, level: u32) {
+    self.root.lock().log().filter().set_level(path, level);
+  }
+
+  // Replaces the Journal persistent Actors on this Stage write to,
+  // ex. stage.set_journal(box FileJournal::new(Path::new("journal")).unwrap()).
+  pub fn set_journal(&self, journal: Box<Journal:Send>) {
+    self.root.lock().persistence().set_journal(journal);
+  }
+
+  // Replaces the SnapshotStore persistent Actors on this Stage save to.
+  pub fn set_snapshot_store(&self, snapshot_store: Box<SnapshotStore:Send>) {
+    self.root.lock().persistence().set_snapshot_store(snapshot_store);
+  }
+
+  // Lets Actors on other Stages reach this Stage's Actors at
+  // cage://system@host:port/path. Port 0 picks a free port. Only
+  // loopback hosts are accepted; see listen_unauthenticated.
+  pub fn listen(&self, system: &str, host: &str, port: u16) -> IoResult<RemoteAddress> {
+    self.root.lock().remoting().listen(system, host, port)
+  }
+
+  // Listens as listen does, on any host. Other Stages aren't
+  // authenticated, so whatever reaches host:port can message, watch
+  // and kill the Stage's Actors.
+  pub fn listen_unauthenticated(&self,
+                                system: &str,
+                                host: &str,
+                                port: u16) -> IoResult<RemoteAddress> {
+    self.root.lock().remoting().listen_unauthenticated(system, host, port)
+  }
+
+  // Returns the Stage's Remoting.
+  pub fn remoting(&self) -> Remoting {
+    self.root.lock().remoting()
+  }
+
+  // Returns the Stage's message Serialization, ex. to register
+  // message types with stage.serialization().register_json::<T>(manifest).
+  pub fn serialization(&self) -> Serialization {
+    self.root.lock().serialization()
+  }
+
+  // Returns an Agent for the Actor at a cage://system@host:port/path address.
+  pub fn remote_agent(&self, uri: &str) -> Option<Agent> {
+    self.root.lock().remote_agent(uri)
+  }
+
+  // Joins the cluster through the seed Stages' addresses, ex.
+  // cage://system@host:port. The Stage must listen first.
+  pub fn join_cluster(&self, seeds: Vec<String>) {
+    self.cluster().fire_and_forget(box JoinCluster { seeds: seeds });
+  }
+
+  // Leaves the cluster; the leader then removes this Stage.
+  pub fn leave_cluster(&self) {
+    self.cluster().fire_and_forget(box LeaveCluster);
+  }
+
+  // Returns an Agent to the cluster daemon, ex. to subscribe an Actor
+  // to membership events with SubscribeCluster.
+  pub fn cluster(&self) -> Agent {
+    self.root.lock().cluster()
+  }
+
+  // Returns a snapshot of the whole Actor hierarchy, which prints as
+  // an indented tree.
+  pub fn tree(&self) -> ActorNode {
+    let root = self.root.lock();
+    actor_tree::build(&root.agent(), root.registry().agents())
+  }
+
+  // Returns a snapshot of the metrics of every running Actor, by path.
+  // ex. to_prometheus(stage.metrics().as_slice()) for a scraper.
+  pub fn metrics(&self) -> Vec<ActorMetrics> {
+    actor_metrics::collect(self.root.lock().registry().agents().as_slice())
+  }
+
+  // Returns the Stage's most recent failures, oldest first.
+  pub fn failures(&self) -> Vec<FailureRecord> {
+    self.root.lock().failures().recent()
+  }
+
+  // Serves the tree, metrics, dead letter counts and failures as JSON
+  // on localhost:port, and lets operators holding the token returned
+  // stop Actors by path. Port 0 picks a free port; the server
+  // returned holds the one chosen, and stops serving once dropped.
+  pub fn serve_admin(&self, port: u16) -> IoResult<AdminServer> {
+    let root = self.root.lock();
+    let admin = Admin::new(root.agent(),
+                           root.registry(),
+                           root.dead_letters(),
+                           root.failures(),
+                           root.log().for_path(SYSTEM_ADDRESS.to_string().append("/admin")));
+    admin.serve(port)
+  }
+
+  // Returns the Stage's Tracer, ex. to turn tracing on with
+  // stage.tracer().add_collector(box RecentSpans::new(1000)).
+  pub fn tracer(&self) -> Tracer {
+    self.root.lock().tracer()
+  }
+
+  // Returns the Stage's fault injector, ex. to turn on chaos mode
+  // with stage.chaos().inject("/bank", faults).
+  pub fn chaos(&self) -> Chaos {
+    self.root.lock().chaos()
+  }
+
+  // Returns the Dispatcher the Stage's Actors run on.
+  pub fn dispatcher(&self) -> Dispatcher {
+    self.root.lock().dispatcher()
+  }
+
+  /*
+   * Driving a deterministic Stage.
+   */
+  // Lets one Actor handle one waiting message. Returns false if
+  // none was waiting.
+  pub fn step(&self) -> bool {
+    self.dispatcher().step()
+  }
+
+  // Handles messages until none are waiting, returning how many were.
+  pub fn run_until_idle(&self) -> uint {
+    self.dispatcher().run_until_idle()
+  }
+
+  // Moves the virtual clock on by millis, delivering scheduled messages.
+  pub fn advance(&self, millis: u64) {
+    self.dispatcher().advance(millis)
+  }
+
+  // The virtual time in milliseconds.
+  pub fn now(&self) -> u64 {
+    self.dispatcher().now()
+  }
+
+  // Publishes an event from outside any Actor. Replies to
+  // the event go to the dead letter office.
+  pub fn publish(&self, event: Box<Message:Send>) {
+    let root = self.root.lock();
+    root.event_stream().publish(event, &root.dead_letters());
+  }
   
   // A context object for Actors to be created in.
   pub fn new() -> Stage {
+    Stage::with_dispatcher(Dispatcher::threaded())
+  }
+
+  // A Stage whose Actors only run when the test steps it, in the
+  // current task, with scheduled messages on a virtual clock.
+  pub fn deterministic() -> Stage {
+    Stage::with_dispatcher(Dispatcher::deterministic())
+  }
+
+  // A deterministic Stage that lets a random waiting Actor go at
+  // each step; the same seed always gives the same order.
+  pub fn exploring(seed: uint) -> Stage {
+    Stage::with_dispatcher(Dispatcher::exploring(seed))
+  }
+
+  fn with_dispatcher(dispatcher: Dispatcher) -> Stage {
     // Create a channel for an Agent.
-    let (send, recv) = channel::<CageMessage>();
+    let (send, recv) = channel::<Envelope>();
+
+    // Create channels for the dead letter office and logging Actor.
+    let (dl_send, dl_recv) = channel::<Envelope>();
+    let (log_send, log_recv) = channel::<Envelope>();
   
     // Setup an Agent and a dummy parent.
-    let (_send, _recv) = channel::<CageMessage>();
+    let (_send, _recv) = channel::<Envelope>();
     let dummy_parent = Agent::new(_send,
                                   NO_ADDRESS.to_string(),
                                   NO_ADDRESS.to_string());
 
     // Create a context.
-    let root_context = Context::root(send, dummy_parent);
+    let mut root_context = Context::root(send, dummy_parent, dl_send, log_send, dispatcher);
+
+    // Start /system and the system Actors under it.
+    root_context.start_system(dl_recv, log_recv);
 
     // Wrap the context in a lock.
     let root_context = Arc::new(Mutex::new(root_context));
@@ -73,56 +330,13 @@ impl Stage {
 
   // Starts an "Actor" that will handle "Find" requests, but
   // will Send String Failures otherwise.
-  fn start_root(recv: Receiver<CageMessage>, context: Arc<Mutex<Context>>) {
-    spawn(proc() {
-      loop {
-        match recv.recv() {
-          UserMessage(_, sender) => sender.deliver(
-            Stage::stage_failure(MESSAGE_ERROR, &context)
-          ),
-          Find(path, msg, sender) => {
-            let mut _path = path;
-            match _path.pop() {
-              Some(ref s) =>
-                match s.as_slice() {
-                  "*" => {
-                    for child in context.lock().children().iter() {
-                      child.deliver(UserMessage(msg.clone_me(), sender.clone()));
-                    }  
-                  },
-                  ".." =>  sender.deliver(
-                    Stage::stage_failure(PARENT_ERROR, &context)
-                  ),
-                  _ => {
-                    for child in context.lock().children().iter() {
-                      if child.name() == *s {
-                        child.deliver(Find(_path, msg, sender));
-                        break;
-                      }
-                    }
-                  }
-                },
-              None => sender.deliver(
-                Stage::stage_failure(MESSAGE_ERROR, &context)
-              ),
-            }
-          },
-          Terminated(_) => (), // this should never happen
-          Failure(_, failed) =>  failed.deliver(
-            Stage::stage_failure(FAILURE_ERROR, &context)
-          ),
-          Undelivered(_, _) => (),
-          Watch(watcher) => watcher.deliver(
-            Stage::stage_failure(WATCH_ERROR, &context)
-          ),
-          Unwatch(unwatcher) => unwatcher.deliver(
-            Stage::stage_failure(UNWATCH_ERROR, &context)
-          ),
-          Kill(killer) => killer.deliver(
-            Stage::stage_failure(KILL_ERROR, &context)
-          )
-        }  
-      }
+  fn start_root(recv: Receiver<Envelope>, context: Arc<Mutex<Context>>) {
+    let dispatcher = context.lock().dispatcher();
+    let agent = context.lock().agent();
+    agent.set_type_name("Stage".to_string());
+    agent.set_state(Running);
+    dispatcher.run(proc() {
+      box Root { recv: recv, context: context } as Box<Runnable>
     });
   }
   
@@ -131,6 +345,99 @@ impl Stage {
   }
 }
 
+// The "Actor" at the root of the Stage.
+struct Root {
+  recv: Receiver<Envelope>,
+  context: Arc<Mutex<Context>>
+}
+
+impl Runnable for Root {
+  fn step(&mut self, block: bool) -> Step {
+    let envelope = if block {
+      self.recv.recv()
+    } else {
+      match self.recv.try_recv() {
+        Ok(envelope) => envelope,
+        Err(_) => return Idle
+      }
+    };
+    let cage_msg = envelope.msg;
+    let context = &self.context;
+    let agent = context.lock().agent();
+    agent.dequeued(envelope.enqueued);
+    let tracer = context.lock().tracer();
+    let span = tracer.enter(agent.path().as_slice(), &cage_msg, envelope.trace);
+    match cage_msg {
+      UserMessage(_, sender) => sender.deliver(
+        Stage::stage_failure(MESSAGE_ERROR, context)
+      ),
+      Find(path, msg, sender) => {
+        let mut _path = path;
+        match _path.pop() {
+          Some(ref s) =>
+            match s.as_slice() {
+              "*" => {
+                for child in context.lock().children().iter() {
+                  child.deliver(actor_context::wildcard(&_path, msg.clone_me(), sender.clone()));
+                }  
+              },
+              ".." =>  sender.deliver(
+                Stage::stage_failure(PARENT_ERROR, context)
+              ),
+              _ => {
+                let root = context.lock();
+                match root.children().iter().find(|child| child.name() == *s) {
+                  Some(child) => child.deliver(Find(_path, msg, sender)),
+                  None => {
+                    agent.dead_letter(msg.clone_me(), &agent, &sender, PathNotFound);
+                    sender.deliver(Undelivered(agent.clone(), msg));
+                  }
+                }
+              }
+            },
+          None => sender.deliver(
+            Stage::stage_failure(MESSAGE_ERROR, context)
+          ),
+        }
+      },
+      Terminated(_) => (), // this should never happen
+      Failure(err, failed) => {
+        context.lock().count_failure(&*err, &failed);
+        failed.deliver(Stage::stage_failure(FAILURE_ERROR, context));
+      },
+      Undelivered(target, orig) =>
+        agent.dead_letter(orig, &target, &agent, Unreturnable),
+      Watch(watcher) => watcher.deliver(
+        Stage::stage_failure(WATCH_ERROR, context)
+      ),
+      Unwatch(unwatcher) => unwatcher.deliver(
+        Stage::stage_failure(UNWATCH_ERROR, context)
+      ),
+      Kill(killer) => killer.deliver(
+        Stage::stage_failure(KILL_ERROR, context)
+      )
+    }  
+    tracer.exit(span);
+    Handled
+  }
+}
+
+// The Actor at /system, parent of the system Actors.
+pub struct SystemGuardian;
+
+impl Actor for SystemGuardian {
+  fn new() -> SystemGuardian {
+    SystemGuardian
+  }
+
+  // System Actors are reached by path; broadcasts from the root that
+  // land here are ignored.
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {}
+}
+
 #[deriving(Clone)]
 pub struct StageError {
   pub err: String
@@ -0,0 +1,524 @@
+/*
+ * Tools for testing Actors.
+ *
+ * A TestProbe stands in for an Actor in a test: it owns an Agent that
+ * other Actors can send to, watch and reply to, and lets the test
+ * block until an expected message arrives, ex.
+ *
+ *   let mut probe = TestProbe::new(&stage);
+ *   probe.send(&calculator, box Add { x: 1, y: 2 });
+ *   let sum = probe.expect_msg::<Sum>(500);
+ *   assert_eq!(sum.result, 3);
+ *
+ * Handing the probe's Agent to an Actor (ex. inside a message) checks
+ * what it sends to third parties. Expectations that aren't met fail
+ * the test's task. On a deterministic Stage, expectations step the
+ * Stage themselves, and their timeouts pass on its virtual clock.
+ *
+ * A TestActorRef runs an Actor's own code in the test's task instead,
+ * with a real Context under the Stage's root, so a test can call its
+ * receive and other hooks directly and then look at its state, ex.
+ *
+ *   let mut counter = TestActorRef::<Counter>::new(&mut stage, "counter".to_string());
+ *   counter.receive(box Increment);
+ *   assert_eq!(counter.actor().count, 1);
+ *
+ * explore runs a test against many delivery orders, each on an
+ * exploring Stage with its own seed, ex.
+ *
+ *   fn transfer(stage: &mut Stage) { ... }
+ *   explore(0, 1000, transfer);
+ *
+ * and fails naming the seed of the first order the test failed under;
+ * replay(seed, transfer) runs the test under that order again. Only
+ * the choice of which waiting Actor goes next is explored: messages
+ * in one mailbox keep the order they were sent in, and scheduled
+ * messages still arrive in the order they fall due on the virtual
+ * clock, so orders that need timers to fire differently aren't tried.
+ */
+use std::any::AnyRefExt;
+use std::io::Timer;
+use std::rand;
+use std::rand::Rng;
+use std::task;
+
+use actor::Actor;
+use actor::Message;
+use actor_agent::Agent;
+use actor_agent::NAME_LENGTH;
+use actor_context::ActorCell;
+use actor_context::Context;
+use actor_dispatcher::Dispatcher;
+use actor_dispatcher::Runnable;
+  use actor_dispatcher::Handled;
+  use actor_dispatcher::Idle;
+  use actor_dispatcher::Stopped;
+use actor_stage::Stage;
+use cage_message::CageMessage;
+use cage_message::Envelope;
+  use cage_message::UserMessage;
+  use cage_message::Find;
+  use cage_message::Terminated;
+  use cage_message::Failure;
+  use cage_message::Undelivered;
+  use cage_message::Watch;
+  use cage_message::Unwatch;
+  use cage_message::Kill;
+
+// Describes a message for a failed expectation.
+fn describe(cage_msg: &CageMessage) -> String {
+  match *cage_msg {
+    UserMessage(_, ref sender) => format!("a message from {}", sender.path()),
+    Find(ref path, _, ref sender) => format!("Find {} from {}", path, sender.path()),
+    Terminated(ref agent) => format!("Terminated of {}", agent.path()),
+    Failure(_, ref failed) => format!("Failure of {}", failed.path()),
+    Undelivered(ref target, _) => format!("Undelivered to {}", target.path()),
+    Watch(ref watcher) => format!("Watch from {}", watcher.path()),
+    Unwatch(ref unwatcher) => format!("Unwatch from {}", unwatcher.path()),
+    Kill(ref killer) => format!("Kill from {}", killer.path())
+  }
+}
+
+pub struct TestProbe {
+  agent: Agent,
+  inbox: Receiver<Envelope>,
+  dispatcher: Dispatcher,
+  // Every message taken from the inbox, in order.
+  received: Vec<CageMessage>,
+  // The sender of the last user message, for reply.
+  last_sender: Option<Agent>
+}
+
+impl TestProbe {
+  // Returns a probe on the Stage, whose undeliverable replies go to
+  // the Stage's dead letter office. Its Agent sits at a random path
+  // under the root, but can't be found by path.
+  pub fn new(stage: &Stage) -> TestProbe {
+    let (send, recv) = channel::<Envelope>();
+    let name = rand::task_rng().gen_ascii_chars().take(NAME_LENGTH).collect();
+    TestProbe {
+      agent: Agent::with_dead_letters(send, "/".to_string(), name, Some(stage.dead_letters())),
+      inbox: recv,
+      dispatcher: stage.dispatcher(),
+      received: Vec::new(),
+      last_sender: None
+    }
+  }
+
+  // Returns the probe's Agent, to hand to the Actors under test.
+  pub fn agent(&self) -> Agent {
+    self.agent.clone()
+  }
+
+  // Sends the message to target as if from the probe, so replies
+  // come back to it.
+  pub fn send(&self, target: &Agent, msg: Box<Message:Send>) {
+    target.deliver(UserMessage(msg, self.agent.clone()));
+  }
+
+  // Replies to the sender of the last message the probe received.
+  pub fn reply(&self, msg: Box<Message:Send>) {
+    match self.last_sender {
+      Some(ref sender) => self.send(sender, msg),
+      None => fail!("TestProbe {} has no message to reply to", self.agent.path())
+    }
+  }
+
+  // The sender of the last message the probe received.
+  pub fn sender(&self) -> Option<Agent> {
+    self.last_sender.clone()
+  }
+
+  // Asks target to send the probe Terminated when it stops.
+  pub fn watch(&self, target: &Agent) {
+    target.deliver(Watch(self.agent.clone()));
+  }
+
+  pub fn unwatch(&self, target: &Agent) {
+    target.deliver(Unwatch(self.agent.clone()));
+  }
+
+  // Every message the probe has received so far, user and system
+  // alike, in the order they arrived.
+  pub fn received<'a>(&'a self) -> &'a [CageMessage] {
+    self.received.as_slice()
+  }
+
+  /*
+   * Expectations.
+   */
+
+  // Waits up to timeout milliseconds for the next message, which
+  // must be a user message of type T, and returns it.
+  pub fn expect_msg<T: Message>(&mut self, timeout: u64) -> T {
+    let path = self.agent.path();
+    let i = match self.receive(timeout) {
+      Some(i) => i,
+      None => fail!("TestProbe {} received no message in {}ms", path, timeout)
+    };
+    match self.received[i] {
+      UserMessage(ref msg, _) if msg.is::<T>() => msg.as_ref::<T>().unwrap().clone(),
+      ref other => fail!("TestProbe {} expected a message of another type, but received {}",
+                         path, describe(other))
+    }
+  }
+
+  // Waits duration milliseconds, failing if any message arrives.
+  pub fn expect_no_msg(&mut self, duration: u64) {
+    let path = self.agent.path();
+    match self.receive(duration) {
+      Some(i) => fail!("TestProbe {} expected no message, but received {}",
+                       path, describe(&self.received[i])),
+      None => ()
+    }
+  }
+
+  // Waits up to timeout milliseconds for the next message, which must
+  // say that agent has stopped. The probe must be watching agent.
+  pub fn expect_terminated(&mut self, agent: &Agent, timeout: u64) {
+    let path = self.agent.path();
+    let i = match self.receive(timeout) {
+      Some(i) => i,
+      None => fail!("TestProbe {} was not told {} terminated in {}ms",
+                    path, agent.path(), timeout)
+    };
+    match self.received[i] {
+      Terminated(ref terminated) if terminated == agent => (),
+      ref other => fail!("TestProbe {} expected Terminated of {}, but received {}",
+                         path, agent.path(), describe(other))
+    }
+  }
+
+  // Takes the next message from the inbox within timeout milliseconds,
+  // recording it, and returns its index in received. On a
+  // deterministic Stage the probe runs the Stage until idle, then
+  // if nothing came, moves its clock on by timeout and looks again.
+  fn receive(&mut self, timeout: u64) -> Option<uint> {
+    let envelope = if self.dispatcher.is_deterministic() {
+      self.dispatcher.run_until_idle();
+      match self.inbox.try_recv() {
+        Ok(envelope) => Some(envelope),
+        Err(_) => {
+          self.dispatcher.advance(timeout);
+          self.inbox.try_recv().ok()
+        }
+      }
+    } else {
+      let mut timer = Timer::new().unwrap();
+      let deadline = timer.oneshot(timeout);
+      let inbox = &self.inbox;
+      select! {
+        envelope = inbox.recv() => Some(envelope),
+        () = deadline.recv() => None
+      }
+    };
+    match envelope {
+      Some(envelope) => {
+        self.agent.dequeued(envelope.enqueued);
+        let cage_msg = envelope.msg;
+        match cage_msg {
+          UserMessage(_, ref sender) => self.last_sender = Some(sender.clone()),
+          _ => ()
+        }
+        self.received.push(cage_msg);
+        Some(self.received.len() - 1)
+      },
+      None => None
+    }
+  }
+}
+
+pub struct TestActorRef<T> {
+  cell: ActorCell<T>,
+  stopped: bool
+}
+
+impl<T: Actor> TestActorRef<T> {
+  // Makes the Actor at /name with Actor::new() and calls its pre_start.
+  pub fn new(stage: &mut Stage, name: String) -> TestActorRef<T> {
+    let mut cell = stage.start_inline::<T>(name);
+    cell.step(false);
+    TestActorRef { cell: cell, stopped: false }
+  }
+
+  // Returns the Actor's Agent. Messages sent to it wait in its mailbox
+  // until run_mailbox.
+  pub fn agent(&mut self) -> Agent {
+    self.cell.context().agent()
+  }
+
+  // Returns the Actor itself, to look at or change its state.
+  pub fn actor<'a>(&'a mut self) -> &'a mut T {
+    self.cell.actor()
+  }
+
+  pub fn context<'a>(&'a mut self) -> &'a mut Context {
+    self.cell.context()
+  }
+
+  /*
+   * Calling the Actor's hooks, as the Cage system would.
+   */
+
+  // Calls receive with the message, as if sent from outside any Actor;
+  // replies go to the dead letter office.
+  pub fn receive(&mut self, msg: Box<Message:Send>) {
+    let sender = self.cell.context().dead_letters();
+    self.receive_from(msg, &sender);
+  }
+
+  // Calls receive with the message as if from sender, ex. a TestProbe's Agent.
+  pub fn receive_from(&mut self, msg: Box<Message:Send>, sender: &Agent) {
+    self.process(UserMessage(msg, sender.clone()));
+  }
+
+  pub fn terminated(&mut self, terminated: &Agent) {
+    self.process(Terminated(terminated.clone()));
+  }
+
+  pub fn failed(&mut self, err: Box<Message:Send>, failed: &Agent) {
+    self.process(Failure(err, failed.clone()));
+  }
+
+  pub fn undelivered(&mut self, target: &Agent, orig_msg: Box<Message:Send>) {
+    self.process(Undelivered(target.clone(), orig_msg));
+  }
+
+  // Kills the Actor, calling killed then post_stop.
+  pub fn stop(&mut self) {
+    let agent = self.agent();
+    self.process(Kill(agent));
+  }
+
+  // Handles the messages waiting in the Actor's mailbox, returning how
+  // many there were.
+  pub fn run_mailbox(&mut self) -> uint {
+    let mut handled = 0;
+    while !self.stopped {
+      self.cell.enter();
+      match self.cell.step(false) {
+        Handled => handled += 1,
+        Stopped => {
+          self.stopped = true;
+          handled += 1;
+        },
+        Idle => break
+      }
+    }
+    handled
+  }
+
+  pub fn is_stopped(&self) -> bool {
+    self.stopped
+  }
+
+  fn process(&mut self, cage_msg: CageMessage) {
+    if self.stopped {
+      fail!("TestActorRef {} used after it stopped", self.cell.context().agent().path());
+    }
+    self.cell.enter();
+    if self.cell.process(cage_msg, None) == Stopped {
+      self.stopped = true;
+    }
+  }
+}
+
+// Runs test on exploring Stages seeded first, first + 1, ... for
+// runs runs, each in a task of its own, failing with the seed of the
+// first run that fails.
+pub fn explore(first: uint, runs: uint, test: fn(&mut Stage)) {
+  for seed in range(first, first + runs) {
+    let result = task::try(proc() {
+      let mut stage = Stage::exploring(seed);
+      test(&mut stage);
+    });
+    if result.is_err() {
+      fail!("test failed under the delivery order with seed {}; replay it with replay({}, test)",
+            seed, seed);
+    }
+  }
+}
+
+// Runs test under the delivery order of seed, in a task of its own as
+// explore does, so no Stage the current task made shares its
+// dispatcher, and the order is the one explore saw.
+pub fn replay(seed: uint, test: fn(&mut Stage)) {
+  let result = task::try(proc() {
+    let mut stage = Stage::exploring(seed);
+    test(&mut stage);
+  });
+  if result.is_err() {
+    fail!("test failed under the delivery order with seed {}", seed);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+  use std::task;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use cage_message::Kill;
+  use super::TestActorRef;
+  use super::TestProbe;
+  use super::explore;
+  use super::replay;
+
+  #[deriving(Clone)]
+  struct Increment;
+  impl Message for Increment {}
+
+  #[deriving(Clone)]
+  struct GetCount;
+  impl Message for GetCount {}
+
+  #[deriving(Clone, PartialEq, Show)]
+  struct Count(uint);
+  impl Message for Count {}
+
+  struct Counter {
+    count: uint,
+    terminated: Vec<String>,
+    stopped: bool
+  }
+
+  impl Actor for Counter {
+    fn new() -> Counter {
+      Counter { count: 0, terminated: Vec::new(), stopped: false }
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      if msg.is::<Increment>() {
+        self.count += 1;
+      } else if msg.is::<GetCount>() {
+        sender.deliver(context.send(box Count(self.count)));
+      }
+    }
+
+    fn terminated(&mut self, context: &mut Context, terminated: Agent) {
+      self.terminated.push(terminated.path());
+    }
+
+    fn post_stop(&mut self, context: &mut Context) {
+      self.stopped = true;
+    }
+  }
+
+  #[test]
+  fn calls_the_actors_hooks_inline() {
+    let mut stage = Stage::deterministic();
+    let mut counter = TestActorRef::<Counter>::new(&mut stage, "counter".to_string());
+    counter.receive(box Increment);
+    counter.receive(box Increment);
+    assert_eq!(counter.actor().count, 2);
+
+    let probe = TestProbe::new(&stage);
+    counter.terminated(&probe.agent());
+    assert_eq!(counter.actor().terminated, vec!(probe.agent().path()));
+
+    counter.stop();
+    assert!(counter.is_stopped());
+    assert!(counter.actor().stopped);
+  }
+
+  #[test]
+  fn leaves_sent_messages_in_the_mailbox_until_run() {
+    let mut stage = Stage::deterministic();
+    let mut counter = TestActorRef::<Counter>::new(&mut stage, "counter".to_string());
+    let agent = counter.agent();
+    agent.fire_and_forget(box Increment);
+    agent.fire_and_forget(box Increment);
+    stage.run_until_idle();
+    assert_eq!(counter.actor().count, 0);
+    assert_eq!(counter.run_mailbox(), 2);
+    assert_eq!(counter.actor().count, 2);
+  }
+
+  #[test]
+  fn probes_receive_and_reply() {
+    let mut stage = Stage::deterministic();
+    let mut counter = TestActorRef::<Counter>::new(&mut stage, "counter".to_string());
+    let mut probe = TestProbe::new(&stage);
+    counter.receive_from(box GetCount, &probe.agent());
+    assert_eq!(probe.expect_msg::<Count>(0), Count(0));
+    assert_eq!(probe.sender().unwrap().path(), counter.agent().path());
+
+    probe.reply(box Increment);
+    assert_eq!(counter.run_mailbox(), 1);
+    assert_eq!(counter.actor().count, 1);
+    assert_eq!(probe.received().len(), 1);
+  }
+
+  #[test]
+  fn waits_for_no_message_on_the_virtual_clock() {
+    let stage = Stage::deterministic();
+    let mut probe = TestProbe::new(&stage);
+    probe.expect_no_msg(500);
+    assert_eq!(stage.now(), 500);
+  }
+
+  #[test]
+  #[should_fail]
+  fn fails_on_a_message_of_another_type() {
+    let mut stage = Stage::deterministic();
+    let mut counter = TestActorRef::<Counter>::new(&mut stage, "counter".to_string());
+    let mut probe = TestProbe::new(&stage);
+    counter.receive_from(box GetCount, &probe.agent());
+    probe.expect_msg::<Increment>(0);
+  }
+
+  #[test]
+  fn expects_watched_actors_to_terminate() {
+    let mut stage = Stage::deterministic();
+    let counter = stage.start_name::<Counter>("counter".to_string());
+    let mut probe = TestProbe::new(&stage);
+    probe.watch(&counter);
+    counter.deliver(Kill(probe.agent()));
+    probe.expect_terminated(&counter, 100);
+  }
+
+  #[test]
+  #[should_fail]
+  fn fails_if_a_watched_actor_keeps_running() {
+    let mut stage = Stage::deterministic();
+    let counter = stage.start_name::<Counter>("counter".to_string());
+    let mut probe = TestProbe::new(&stage);
+    probe.watch(&counter);
+    probe.expect_terminated(&counter, 100);
+  }
+
+  // Fails unless the Actor started first handles its message first.
+  fn first_started_goes_first(stage: &mut Stage) {
+    let first = stage.start_name::<Counter>("first".to_string());
+    let second = stage.start_name::<Counter>("second".to_string());
+    let mut probe = TestProbe::new(stage);
+    probe.send(&second, box GetCount);
+    probe.send(&first, box GetCount);
+    probe.expect_msg::<Count>(0);
+    assert_eq!(probe.sender().unwrap().path(), first.path());
+  }
+
+  fn starts_at_time_zero(stage: &mut Stage) {
+    assert_eq!(stage.now(), 0);
+  }
+
+  #[test]
+  fn replays_the_orders_explore_fails_under() {
+    let failing = range(0u, 20).find(|&seed| {
+      task::try(proc() explore(seed, 1, first_started_goes_first)).is_err()
+    }).expect("no seed took the second Actor first");
+    assert!(task::try(proc() replay(failing, first_started_goes_first)).is_err());
+    assert!(task::try(proc() replay(failing, first_started_goes_first)).is_err());
+  }
+
+  #[test]
+  fn replays_on_a_fresh_stage() {
+    let stage = Stage::deterministic();
+    stage.advance(1000);
+    replay(0, starts_at_time_zero);
+  }
+}
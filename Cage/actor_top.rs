@@ -0,0 +1,494 @@
+/*
+ * What the cage-top monitor shows of a Stage, apart from the terminal
+ * it shows it on: the table of Actors with their throughput, the tree
+ * with its folded subtrees, the cursor, and the keys that move them.
+ * cage-top reads the Stage through an AdminClient, hands each reading
+ * to update, and draws the lines of screen.
+ */
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use actor_metrics::ActorMetrics;
+use actor_tree::ActorNode;
+
+pub static USAGE: &'static str = "usage: cage-top port [refresh-ms]";
+// Milliseconds between refreshes, unless given.
+pub static DEFAULT_REFRESH: u64 = 1000;
+
+static REVERSE: &'static str = "\x1b[7m";
+static BOLD: &'static str = "\x1b[1m";
+static RESET: &'static str = "\x1b[0m";
+static ESCAPE: u8 = 0x1b;
+
+// Lines of the screen that aren't the table or tree: the two header
+// lines, the column titles, the help, and one spare.
+static CHROME: uint = 5;
+
+#[deriving(PartialEq, Show)]
+pub enum View {
+  Table,
+  Tree
+}
+
+#[deriving(PartialEq, Show)]
+pub enum SortBy {
+  ByMailbox,
+  ByThroughput,
+  ByFailures,
+  ByPath
+}
+
+// One Actor in the table.
+pub struct Row {
+  pub path: String,
+  pub type_name: String,
+  pub mailbox: uint,
+  // Messages handled per second since the last refresh.
+  pub throughput: f64,
+  pub failures: uint
+}
+
+pub struct Top {
+  address: String,
+  view: View,
+  sort: SortBy,
+  rows: Vec<Row>,
+  tree: Option<ActorNode>,
+  dead_letters: uint,
+  // Paths of the tree's folded subtrees.
+  folded: HashSet<String>,
+  cursor: uint,
+  // The messages each Actor had handled at the last refresh, and when.
+  processed: HashMap<String, uint>,
+  refreshed_ns: u64,
+  error: Option<String>
+}
+
+impl Top {
+  // Shows the Stage whose admin server is at address.
+  pub fn new(address: String) -> Top {
+    Top {
+      address: address,
+      view: Table,
+      sort: ByMailbox,
+      rows: Vec::new(),
+      tree: None,
+      dead_letters: 0,
+      folded: HashSet::new(),
+      cursor: 0,
+      processed: HashMap::new(),
+      refreshed_ns: 0,
+      error: None
+    }
+  }
+
+  pub fn rows<'a>(&'a self) -> &'a [Row] {
+    self.rows.as_slice()
+  }
+
+  pub fn cursor(&self) -> uint {
+    self.cursor
+  }
+
+  /*
+   * Readings.
+   */
+  // Takes a reading of the Stage made at now_ns, in nanoseconds.
+  pub fn update(&mut self, metrics: Vec<ActorMetrics>, tree: ActorNode, dead_letters: uint, now_ns: u64) {
+    self.tree = Some(tree);
+    self.update_rows(metrics, now_ns);
+    self.dead_letters = dead_letters;
+    self.error = None;
+    self.clamp_cursor();
+  }
+
+  // Notes that the Stage couldn't be read, keeping the last reading.
+  pub fn failed(&mut self, error: String) {
+    self.error = Some(error);
+  }
+
+  fn update_rows(&mut self, metrics: Vec<ActorMetrics>, now_ns: u64) {
+    let elapsed = (now_ns - self.refreshed_ns) as f64 / 1e9;
+    let mut processed = HashMap::new();
+    let mut types = HashMap::new();
+    match self.tree {
+      Some(ref tree) => tree.each(|node| { types.insert(node.path.clone(), node.type_name.clone()); }),
+      None => ()
+    }
+    let rows = metrics.iter().map(|m| {
+      // Actors new since the last refresh count from zero.
+      let before = self.processed.find(&m.path).map_or(0, |n| *n);
+      let throughput = if self.refreshed_ns == 0 || m.processed < before || elapsed <= 0.0 {
+        0.0
+      } else {
+        (m.processed - before) as f64 / elapsed
+      };
+      processed.insert(m.path.clone(), m.processed);
+      Row {
+        path: m.path.clone(),
+        type_name: types.find(&m.path).map_or(String::new(), |t| t.clone()),
+        mailbox: m.mailbox_size,
+        throughput: throughput,
+        failures: m.failures
+      }
+    }).collect();
+    self.rows = rows;
+    self.processed = processed;
+    self.refreshed_ns = now_ns;
+    self.sort_rows();
+  }
+
+  fn sort_rows(&mut self) {
+    match self.sort {
+      ByMailbox => self.rows.sort_by(|a, b| b.mailbox.cmp(&a.mailbox)),
+      ByThroughput => self.rows.sort_by(|a, b| b.throughput.partial_cmp(&a.throughput).unwrap_or(Equal)),
+      ByFailures => self.rows.sort_by(|a, b| b.failures.cmp(&a.failures)),
+      ByPath => self.rows.sort_by(|a, b| a.path.cmp(&b.path))
+    }
+  }
+
+  /*
+   * Keys.
+   */
+  // Returns false once the user quits.
+  pub fn key(&mut self, key: char) -> bool {
+    match key {
+      'q' => return false,
+      'v' => {
+        self.view = if self.view == Table { Tree } else { Table };
+        self.cursor = 0;
+      },
+      'm' => self.sort_by(ByMailbox),
+      't' => self.sort_by(ByThroughput),
+      'f' => self.sort_by(ByFailures),
+      'p' => self.sort_by(ByPath),
+      'j' => self.cursor += 1,
+      'k' => if self.cursor > 0 { self.cursor -= 1 },
+      ' ' | '\r' => self.fold(),
+      _ => ()
+    }
+    self.clamp_cursor();
+    true
+  }
+
+  fn sort_by(&mut self, sort: SortBy) {
+    self.sort = sort;
+    self.sort_rows();
+  }
+
+  // Keeps the cursor on a line of the view, as lines come and go.
+  fn clamp_cursor(&mut self) {
+    let lines = self.lines();
+    if self.cursor >= lines {
+      self.cursor = if lines > 0 { lines - 1 } else { 0 };
+    }
+  }
+
+  // Folds the subtree under the cursor, or unfolds it if folded.
+  fn fold(&mut self) {
+    if self.view != Tree {
+      return;
+    }
+    let path = match self.visible().as_slice().get(self.cursor) {
+      Some(&(_, node)) if !node.children.is_empty() => node.path.clone(),
+      _ => return
+    };
+    if !self.folded.remove(&path) {
+      self.folded.insert(path);
+    }
+  }
+
+  // How many lines the current view has.
+  fn lines(&self) -> uint {
+    match self.view {
+      Table => self.rows.len(),
+      Tree => self.visible().len()
+    }
+  }
+
+  // The tree's nodes outside folded subtrees, with their depth.
+  pub fn visible<'a>(&'a self) -> Vec<(uint, &'a ActorNode)> {
+    let mut visible = Vec::new();
+    match self.tree {
+      Some(ref tree) => self.add_visible(tree, 0, &mut visible),
+      None => ()
+    }
+    visible
+  }
+
+  fn add_visible<'a>(&'a self, node: &'a ActorNode, depth: uint, visible: &mut Vec<(uint, &'a ActorNode)>) {
+    visible.push((depth, node));
+    if !self.folded.contains(&node.path) {
+      for child in node.children.iter() {
+        self.add_visible(child, depth + 1, visible);
+      }
+    }
+  }
+
+  /*
+   * Drawing.
+   */
+  // The lines of a screen height lines high, scrolled to keep the
+  // cursor on it.
+  pub fn screen(&self, height: uint) -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(format!("{}cage-top {}{}  {} actors, {} dead letters",
+                       BOLD, self.address, RESET, self.rows.len(), self.dead_letters));
+    lines.push(match self.error {
+      Some(ref err) => format!("error: {}", err),
+      None => String::new()
+    });
+    let body = match self.view {
+      Table => self.table(),
+      Tree => self.tree_lines()
+    };
+
+    let room = if height > CHROME { height - CHROME } else { 1 };
+    let first = if self.cursor >= room { self.cursor + 1 - room } else { 0 };
+    lines.push(match self.view {
+      Table => self.table_title(),
+      Tree => format!("{}{:<50} {:>8} {:>8}{}", BOLD, "ACTOR", "MAILBOX", "FAILURES", RESET)
+    });
+    for (i, line) in body.iter().enumerate().skip(first).take(room) {
+      if i == self.cursor {
+        lines.push(format!("{}{}{}", REVERSE, line, RESET));
+      } else {
+        lines.push(line.clone());
+      }
+    }
+    lines.push("q quit  v table/tree  m t f p sort  j k move  space fold".to_string());
+    lines
+  }
+
+  fn table_title(&self) -> String {
+    let mark = |sort: SortBy, title: &str| -> String {
+      if self.sort == sort { format!("{}*", title) } else { title.to_string() }
+    };
+    format!("{}{:<40} {:<24} {:>8} {:>10} {:>9}{}",
+            BOLD,
+            mark(ByPath, "PATH"),
+            "TYPE",
+            mark(ByMailbox, "MAILBOX"),
+            mark(ByThroughput, "MSG/S"),
+            mark(ByFailures, "FAILURES"),
+            RESET)
+  }
+
+  fn table(&self) -> Vec<String> {
+    self.rows.iter().map(|row| {
+      format!("{:<40} {:<24} {:>8} {:>10.1} {:>9}",
+              row.path, short_type(row.type_name.as_slice()), row.mailbox, row.throughput, row.failures)
+    }).collect()
+  }
+
+  fn tree_lines(&self) -> Vec<String> {
+    let failures: HashMap<String, uint> = self.rows.iter().map(|row| (row.path.clone(), row.failures)).collect();
+    self.visible().iter().map(|&(depth, node)| {
+      let fold = if node.children.is_empty() {
+        "   "
+      } else if self.folded.contains(&node.path) {
+        "[+]"
+      } else {
+        "[-]"
+      };
+      let label = format!("{}{} {} ({})",
+                          "  ".repeat(depth), fold, node.name, short_type(node.type_name.as_slice()));
+      format!("{:<50} {:>8} {:>8}",
+              label, node.mailbox_size, failures.find(&node.path).map_or(0, |n| *n))
+    }).collect()
+  }
+}
+
+// The type name without its module path.
+fn short_type<'a>(type_name: &'a str) -> &'a str {
+  match type_name.rfind(':') {
+    Some(i) => type_name.slice_from(i + 1),
+    None => type_name
+  }
+}
+
+// The port and refresh interval in cage-top's arguments, or None if
+// they are malformed. A refresh of 0 ms is malformed.
+pub fn parse_args(args: &[String]) -> Option<(u16, u64)> {
+  if args.len() < 2 || args.len() > 3 {
+    return None;
+  }
+  let port = from_str::<u16>(args[1].as_slice());
+  let refresh = match args.get(2) {
+    Some(ms) => from_str::<u64>(ms.as_slice()),
+    None => Some(DEFAULT_REFRESH)
+  };
+  match (port, refresh) {
+    (Some(port), Some(refresh)) if refresh > 0 => Some((port, refresh)),
+    _ => None
+  }
+}
+
+// The keys in what one read of the terminal returned, with the arrow
+// keys as k and j. A terminal writes a key's escape sequence at once,
+// so an escape ending the bytes was pressed alone, and is ignored, as
+// are other sequences.
+pub fn keys_of(bytes: &[u8]) -> Vec<char> {
+  let mut keys = Vec::new();
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] != ESCAPE {
+      keys.push(bytes[i] as char);
+      i += 1;
+      continue;
+    }
+    match (bytes.get(i + 1), bytes.get(i + 2)) {
+      (Some(&b'['), Some(&b'A')) => keys.push('k'),
+      (Some(&b'['), Some(&b'B')) => keys.push('j'),
+      _ => ()
+    }
+    // Skip the escape and what it introduces.
+    i += 1;
+    if bytes.get(i) == Some(&b'[') {
+      i += 1;
+      while i < bytes.len() && !(bytes[i] >= b'@' && bytes[i] <= b'~') {
+        i += 1;
+      }
+      i += 1;
+    }
+  }
+  keys
+}
+
+#[cfg(test)]
+mod test {
+  use actor_metrics::ActorMetrics;
+  use actor_tree::ActorNode;
+    use actor_tree::Running;
+  use super::DEFAULT_REFRESH;
+  use super::Top;
+  use super::keys_of;
+  use super::parse_args;
+
+  static SECOND: u64 = 1000000000;
+
+  fn metrics(path: &str, mailbox_size: uint, processed: uint, failures: uint) -> ActorMetrics {
+    ActorMetrics {
+      path: path.to_string(),
+      mailbox_size: mailbox_size,
+      processed: processed,
+      receive_ns: 0,
+      wait_ns: 0,
+      failures: failures
+    }
+  }
+
+  fn node(path: &str, name: &str, children: Vec<ActorNode>) -> ActorNode {
+    ActorNode {
+      path: path.to_string(),
+      name: name.to_string(),
+      type_name: "bank::Account".to_string(),
+      mailbox_size: 0,
+      state: Running,
+      watchers: 0,
+      children: children
+    }
+  }
+
+  // / with /bank, which holds /bank/a and /bank/b, and /shop.
+  fn tree() -> ActorNode {
+    node("/", "/", vec![
+      node("/bank", "bank", vec![node("/bank/a", "a", vec![]), node("/bank/b", "b", vec![])]),
+      node("/shop", "shop", vec![])
+    ])
+  }
+
+  fn paths(top: &Top) -> Vec<String> {
+    top.rows().iter().map(|row| row.path.clone()).collect()
+  }
+
+  #[test]
+  fn measures_throughput_between_readings() {
+    let mut top = Top::new("127.0.0.1:8558".to_string());
+    top.update(vec![metrics("/bank", 0, 10, 0)], tree(), 0, SECOND);
+    assert_eq!(top.rows()[0].throughput, 0.0);
+
+    top.update(vec![metrics("/bank", 0, 30, 0), metrics("/shop", 0, 5, 0)], tree(), 0, 3 * SECOND);
+    top.key('p');
+    assert_eq!(top.rows()[0].throughput, 10.0);
+    // Actors new since the last reading count from zero.
+    assert_eq!(top.rows()[1].throughput, 2.5);
+  }
+
+  #[test]
+  fn sorts_rows_by_the_key_pressed() {
+    let mut top = Top::new("127.0.0.1:8558".to_string());
+    let readings = vec![metrics("/a", 1, 0, 7), metrics("/b", 9, 0, 0), metrics("/c", 4, 0, 3)];
+    top.update(readings, tree(), 0, SECOND);
+    assert_eq!(paths(&top), vec!["/b".to_string(), "/c".to_string(), "/a".to_string()]);
+    top.key('f');
+    assert_eq!(paths(&top), vec!["/a".to_string(), "/c".to_string(), "/b".to_string()]);
+    top.key('p');
+    assert_eq!(paths(&top), vec!["/a".to_string(), "/b".to_string(), "/c".to_string()]);
+  }
+
+  #[test]
+  fn folds_and_unfolds_the_subtree_under_the_cursor() {
+    let mut top = Top::new("127.0.0.1:8558".to_string());
+    top.update(Vec::new(), tree(), 0, SECOND);
+    top.key('v');
+    assert_eq!(top.visible().len(), 5);
+
+    top.key('j');
+    top.key(' ');
+    let visible: Vec<(uint, String)> = top.visible().iter().map(|&(depth, node)| (depth, node.path.clone())).collect();
+    assert_eq!(visible, vec![(0, "/".to_string()), (1, "/bank".to_string()), (1, "/shop".to_string())]);
+
+    top.key(' ');
+    assert_eq!(top.visible().len(), 5);
+  }
+
+  #[test]
+  fn keeps_the_cursor_on_the_view() {
+    let mut top = Top::new("127.0.0.1:8558".to_string());
+    top.update(Vec::new(), tree(), 0, SECOND);
+    top.key('v');
+    for _ in range(0u, 10) {
+      top.key('j');
+    }
+    assert_eq!(top.cursor(), 4);
+
+    // Folding the root leaves one line, and the cursor on it.
+    top.key('k');
+    top.key('k');
+    top.key('k');
+    top.key('k');
+    top.key(' ');
+    assert_eq!(top.cursor(), 0);
+    top.key('j');
+    assert_eq!(top.cursor(), 0);
+
+    // So does a reading with fewer Actors.
+    top.key(' ');
+    top.key('j');
+    top.key('j');
+    top.update(Vec::new(), node("/", "/", vec![]), 0, 2 * SECOND);
+    assert_eq!(top.cursor(), 0);
+  }
+
+  #[test]
+  fn reads_arrow_keys_and_ignores_a_bare_escape() {
+    assert_eq!(keys_of(b"jq"), vec!['j', 'q']);
+    assert_eq!(keys_of(b"\x1b[A\x1b[B"), vec!['k', 'j']);
+    assert_eq!(keys_of(b"\x1b"), vec![]);
+    assert_eq!(keys_of(b"\x1b[1;5Cv"), vec!['v']);
+  }
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn parses_the_port_and_refresh() {
+    assert_eq!(parse_args(args(["cage-top", "8558"]).as_slice()), Some((8558, DEFAULT_REFRESH)));
+    assert_eq!(parse_args(args(["cage-top", "8558", "500"]).as_slice()), Some((8558, 500)));
+    assert_eq!(parse_args(args(["cage-top", "8558", "0"]).as_slice()), None);
+    assert_eq!(parse_args(args(["cage-top", "port"]).as_slice()), None);
+    assert_eq!(parse_args(args(["cage-top"]).as_slice()), None);
+  }
+}
@@ -0,0 +1,315 @@
+/*
+ * Tracing follows one flow of messages through the Actors it visits.
+ * Each message an Actor handles runs in a span, naming the Actor's
+ * path, the message's sender and type, and how long it took. A
+ * message sent while handling another carries the span's trace
+ * context in its envelope, so the span it starts is a child of the
+ * sender's, and the whole flow, Find hops included, shares one trace.
+ *
+ * Tracing is off until a SpanCollector is added to the Stage's
+ * Tracer, ex. to keep the last thousand spans in memory:
+ *
+ *   let spans = RecentSpans::new(1000);
+ *   stage.tracer().add_collector(box spans.clone());
+ *   ...
+ *   for span in spans.trace(trace_id).iter() { println!("{}", span); }
+ *
+ * Trace contexts stay within a Stage; messages from other Stages
+ * start traces of their own.
+ *
+ * The Tracer is Cage's own rather than a general tracing library's.
+ * A span here follows a message from one Actor's task to the next
+ * through its envelope, and on a deterministic Stage every Actor
+ * runs in the same task, so spans can't be tied to tasks the way
+ * such libraries tie them. A SpanCollector can pass spans on to
+ * another tracing system.
+ */
+use std::collections::RingBuf;
+use std::collections::Deque;
+use std::fmt;
+use std::rand;
+use std::sync::atomics::AtomicBool;
+use std::sync::atomics::SeqCst;
+use sync::Arc;
+use sync::Mutex;
+use sync::RWLock;
+use time;
+
+use actor::Message;
+use actor_serialization::Serialization;
+use cage_message::CageMessage;
+  use cage_message::UserMessage;
+  use cage_message::Find;
+  use cage_message::Terminated;
+  use cage_message::Failure;
+  use cage_message::Undelivered;
+  use cage_message::Watch;
+  use cage_message::Unwatch;
+  use cage_message::Kill;
+
+// The span the current task is handling a message in, if any.
+local_data_key!(CURRENT: TraceContext)
+
+// What a message carries to link the span it starts to its sender's.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct TraceContext {
+  pub trace_id: u64,
+  pub span_id: u64
+}
+
+impl TraceContext {
+  // The context of the span the current task is in, if any.
+  pub fn current() -> Option<TraceContext> {
+    CURRENT.get().map(|current| current.clone())
+  }
+}
+
+// One Actor handling one message.
+#[deriving(Clone, PartialEq)]
+pub struct Span {
+  pub trace_id: u64,
+  pub span_id: u64,
+  // The span the message was sent from; None starts a trace.
+  pub parent_id: Option<u64>,
+  pub path: String,
+  pub sender: String,
+  pub message: String,
+  pub start_ns: u64,
+  pub duration_ns: u64
+}
+
+impl fmt::Show for Span {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "trace {:016x} span {:016x} ", self.trace_id, self.span_id)
+      .and_then(|_| match self.parent_id {
+        Some(parent_id) => write!(f, "(from {:016x}) ", parent_id),
+        None => Ok(())
+      })
+      .and_then(|_| write!(f, "{} received {} from {} in {}ns",
+                           self.path, self.message, self.sender, self.duration_ns))
+  }
+}
+
+// A span that hasn't ended yet.
+pub struct ActiveSpan {
+  span: Span
+}
+
+/*
+ * Collectors.
+ */
+pub trait SpanCollector {
+  fn collect(&self, span: &Span);
+}
+
+// Keeps the most recent spans in memory.
+#[deriving(Clone)]
+pub struct RecentSpans {
+  spans: Arc<Mutex<RingBuf<Span>>>,
+  capacity: uint
+}
+
+impl RecentSpans {
+  pub fn new(capacity: uint) -> RecentSpans {
+    RecentSpans {
+      spans: Arc::new(Mutex::new(RingBuf::new())),
+      capacity: capacity
+    }
+  }
+
+  // The kept spans, oldest first.
+  pub fn spans(&self) -> Vec<Span> {
+    self.spans.lock().iter().map(|span| span.clone()).collect()
+  }
+
+  // The kept spans of one trace, oldest first.
+  pub fn trace(&self, trace_id: u64) -> Vec<Span> {
+    self.spans.lock().iter().filter(|span| span.trace_id == trace_id).map(|span| span.clone()).collect()
+  }
+}
+
+impl SpanCollector for RecentSpans {
+  fn collect(&self, span: &Span) {
+    let mut spans = self.spans.lock();
+    if spans.len() == self.capacity {
+      spans.pop_front();
+    }
+    spans.push_back(span.clone());
+  }
+}
+
+/*
+ * The Stage-wide tracer.
+ */
+#[deriving(Clone)]
+pub struct Tracer {
+  // Whether any collector is added, checked for each message.
+  enabled: Arc<AtomicBool>,
+  collectors: Arc<RWLock<Vec<Box<SpanCollector:Send+Share>>>>
+}
+
+impl Tracer {
+  pub fn new() -> Tracer {
+    Tracer {
+      enabled: Arc::new(AtomicBool::new(false)),
+      collectors: Arc::new(RWLock::new(Vec::new()))
+    }
+  }
+
+  // Turns tracing on, giving every span to the collector as it ends.
+  pub fn add_collector(&self, collector: Box<SpanCollector:Send+Share>) {
+    self.collectors.write().push(collector);
+    self.enabled.store(true, SeqCst);
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled.load(SeqCst)
+  }
+
+  // Called by the Cage system as the Actor at path starts handling
+  // cage_msg, which was sent in the span trace, if any.
+  pub fn enter(&self,
+               path: &str,
+               cage_msg: &CageMessage,
+               trace: Option<TraceContext>) -> Option<ActiveSpan> {
+    if !self.is_enabled() {
+      return None;
+    }
+    let (sender, message) = Tracer::describe(cage_msg);
+    let context = TraceContext {
+      trace_id: match trace { Some(ref t) => t.trace_id, None => rand::random() },
+      span_id: rand::random()
+    };
+    CURRENT.replace(Some(context.clone()));
+    Some(ActiveSpan {
+      span: Span {
+        trace_id: context.trace_id,
+        span_id: context.span_id,
+        parent_id: trace.map(|t| t.span_id),
+        path: path.to_string(),
+        sender: sender,
+        message: message,
+        start_ns: time::precise_time_ns(),
+        duration_ns: 0
+      }
+    })
+  }
+
+  // Called by the Cage system once the message is handled.
+  pub fn exit(&self, active: Option<ActiveSpan>) {
+    match active {
+      Some(active) => {
+        CURRENT.replace(None);
+        let mut span = active.span;
+        span.duration_ns = time::precise_time_ns() - span.start_ns;
+        for collector in self.collectors.read().iter() {
+          collector.collect(&span);
+        }
+      },
+      None => ()
+    }
+  }
+
+  // The sender of the message and what kind it is.
+  fn describe(cage_msg: &CageMessage) -> (String, String) {
+    match *cage_msg {
+      UserMessage(ref msg, ref sender) => (sender.path(), Tracer::type_of(&**msg)),
+      Find(_, ref msg, ref sender) => (sender.path(), format!("Find of {}", Tracer::type_of(&**msg))),
+      Terminated(ref agent) => (agent.path(), "Terminated".to_string()),
+      Failure(_, ref failed) => (failed.path(), "Failure".to_string()),
+      Undelivered(ref target, _) => (target.path(), "Undelivered".to_string()),
+      Watch(ref watcher) => (watcher.path(), "Watch".to_string()),
+      Unwatch(ref unwatcher) => (unwatcher.path(), "Unwatch".to_string()),
+      Kill(ref killer) => (killer.path(), "Kill".to_string())
+    }
+  }
+
+  // The message's manifest if it is registered for serialization,
+  // otherwise the name of its type.
+  fn type_of(msg: &Message) -> String {
+    Serialization::current().and_then(|serialization| serialization.manifest_of(msg))
+                            .unwrap_or_else(|| msg.type_name())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_context::Context;
+  use actor_stage::Stage;
+  use actor_testkit::TestActorRef;
+  use super::RecentSpans;
+  use super::Tracer;
+
+  #[deriving(Clone)]
+  struct Ping;
+  impl Message for Ping {}
+
+  #[deriving(Clone)]
+  struct Deposit {
+    amount: uint
+  }
+  impl Message for Deposit {}
+
+  #[deriving(Clone)]
+  struct Pair(uint, uint);
+  impl Message for Pair {}
+
+  #[test]
+  fn names_unregistered_messages_by_their_type() {
+    assert_eq!(Tracer::type_of(&Ping), "Ping".to_string());
+    assert_eq!(Tracer::type_of(&Deposit { amount: 5 }), "Deposit".to_string());
+    assert_eq!(Tracer::type_of(&Pair(1, 2)), "Pair".to_string());
+  }
+
+  struct Echo;
+
+  impl Actor for Echo {
+    fn new() -> Echo {
+      Echo
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {}
+  }
+
+  // Finds /echo for every message.
+  struct Finder;
+
+  impl Actor for Finder {
+    fn new() -> Finder {
+      Finder
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      context.find("/echo".to_string(), box Ping);
+    }
+  }
+
+  #[test]
+  fn follows_a_find_through_the_root() {
+    let mut stage = Stage::deterministic();
+    let spans = RecentSpans::new(10);
+    stage.tracer().add_collector(box spans.clone());
+    stage.start_name::<Echo>("echo".to_string());
+    let mut finder = TestActorRef::<Finder>::new(&mut stage, "finder".to_string());
+    stage.run_until_idle();
+
+    finder.receive(box Ping);
+    stage.run_until_idle();
+
+    let found = spans.spans().move_iter().find(|span| span.path == "/finder".to_string()).unwrap();
+    assert_eq!(found.parent_id, None);
+    let trace = spans.trace(found.trace_id);
+    assert_eq!(trace.len(), 3);
+    let (root, echo) = (trace.get(1), trace.get(2));
+    assert_eq!(root.trace_id, found.trace_id);
+    assert_eq!(root.parent_id, Some(found.span_id));
+    assert_eq!(root.message, "Find of Ping".to_string());
+    assert_eq!(echo.path, "/echo".to_string());
+    assert_eq!(echo.trace_id, found.trace_id);
+    assert_eq!(echo.parent_id, Some(root.span_id));
+    assert_eq!(echo.sender, "/finder".to_string());
+  }
+}
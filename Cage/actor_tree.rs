@@ -0,0 +1,297 @@
+/*
+ * A snapshot of a Stage's whole Actor hierarchy, taken with
+ * stage.tree(), ex. to dump during an incident:
+ *
+ *   println!("{}", stage.tree());
+ *
+ * prints each Actor beneath its parent:
+ *
+ *   / (Stage) running, mailbox 0, watchers 0
+ *   |-- bank (Bank) running, mailbox 12, watchers 1
+ *   |   `-- audit (Auditor) starting, mailbox 3, watchers 0
+ *   `-- system (SystemGuardian) running, mailbox 0, watchers 0
+ *       ...
+ *
+ * The snapshot is built from each Actor's counters as they stand, so
+ * Actors starting or stopping meanwhile may or may not appear.
+ */
+use std::collections::HashMap;
+use std::fmt;
+
+use actor_agent::Agent;
+use actor_agent::ROOT_ADDRESS;
+
+// Where an Actor is in its life.
+#[deriving(Clone, PartialEq, Eq, Encodable, Decodable)]
+pub enum ActorState {
+  // Created, but pre_start hasn't finished.
+  Starting,
+  Running,
+  // Killed, and cleaning up.
+  Stopping,
+  // Stopped after being killed.
+  Stopped,
+  // Its task failed while handling a message.
+  Failed
+}
+
+impl fmt::Show for ActorState {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Starting => write!(f, "starting"),
+      Running => write!(f, "running"),
+      Stopping => write!(f, "stopping"),
+      Stopped => write!(f, "stopped"),
+      Failed => write!(f, "failed")
+    }
+  }
+}
+
+// One Actor in the snapshot.
+#[deriving(Clone, PartialEq, Encodable, Decodable)]
+pub struct ActorNode {
+  pub path: String,
+  pub name: String,
+  pub type_name: String,
+  pub mailbox_size: uint,
+  pub state: ActorState,
+  pub watchers: uint,
+  pub children: Vec<ActorNode>
+}
+
+impl ActorNode {
+  fn of(agent: &Agent) -> ActorNode {
+    ActorNode {
+      path: agent.path(),
+      name: agent.name(),
+      type_name: agent.type_name(),
+      mailbox_size: agent.mailbox_size(),
+      state: agent.state(),
+      watchers: agent.watcher_count(),
+      children: Vec::new()
+    }
+  }
+
+  // Returns the node at path, if it lies in this subtree.
+  pub fn find<'a>(&'a self, path: &str) -> Option<&'a ActorNode> {
+    if self.path.as_slice() == path {
+      return Some(self);
+    }
+    self.children.iter().filter_map(|child| child.find(path)).next()
+  }
+
+  // Calls f on every node in the subtree, parents before children.
+  pub fn each(&self, mut f: |&ActorNode|) {
+    self.walk(&mut f);
+  }
+
+  fn walk(&self, f: &mut |&ActorNode|) {
+    (*f)(self);
+    for child in self.children.iter() {
+      child.walk(f);
+    }
+  }
+
+  fn write_line(&self, f: &mut fmt::Formatter, label: &str) -> fmt::Result {
+    write!(f, "{} ({}) {}, mailbox {}, watchers {}\n",
+           label, self.type_name, self.state, self.mailbox_size, self.watchers)
+  }
+
+  fn write_children(&self, f: &mut fmt::Formatter, indent: &str) -> fmt::Result {
+    let last = self.children.len();
+    for (i, child) in self.children.iter().enumerate() {
+      let (branch, more) = if i + 1 == last { ("`-- ", "    ") } else { ("|-- ", "|   ") };
+      try!(write!(f, "{}{}", indent, branch));
+      try!(child.write_line(f, child.name.as_slice()));
+      try!(child.write_children(f, format!("{}{}", indent, more).as_slice()));
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Show for ActorNode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    try!(self.write_line(f, self.path.as_slice()));
+    self.write_children(f, "")
+  }
+}
+
+// The path of the Actor's parent.
+fn parent_of(path: &str) -> String {
+  match path.rfind('/') {
+    Some(0) => ROOT_ADDRESS.to_string(),
+    Some(i) => path.slice_to(i).to_string(),
+    None => ROOT_ADDRESS.to_string()
+  }
+}
+
+// Builds the hierarchy beneath root from the Agents of every running
+// Actor, children in order of name.
+pub fn build(root: &Agent, agents: Vec<Agent>) -> ActorNode {
+  let mut by_parent: HashMap<String, Vec<Agent>> = HashMap::new();
+  for agent in agents.move_iter() {
+    if agent.path() != root.path() {
+      by_parent.find_or_insert(parent_of(agent.path().as_slice()), Vec::new()).push(agent);
+    }
+  }
+  build_node(root, &mut by_parent)
+}
+
+fn build_node(agent: &Agent, by_parent: &mut HashMap<String, Vec<Agent>>) -> ActorNode {
+  let mut node = ActorNode::of(agent);
+  let mut children = by_parent.pop(&agent.path()).unwrap_or(Vec::new());
+  children.sort_by(|a, b| a.name().cmp(&b.name()));
+  for child in children.iter() {
+    node.children.push(build_node(child, by_parent));
+  }
+  node
+}
+
+#[cfg(test)]
+mod test {
+  use std::any::AnyRefExt;
+
+  use actor::Actor;
+  use actor::Message;
+  use actor_agent::Agent;
+  use actor_agent::NO_ADDRESS;
+  use actor_agent::ROOT_ADDRESS;
+  use actor_context::Context;
+  use actor_context::TaskFailed;
+  use actor_stage::Stage;
+  use actor_testkit::TestProbe;
+  use super::build;
+  use super::Running;
+  use super::Starting;
+  use super::Failed;
+
+  fn agent(dir: &str, name: &str) -> Agent {
+    let (send, _) = channel();
+    Agent::new(send, dir.to_string(), name.to_string())
+  }
+
+  #[test]
+  fn builds_children_beneath_parents_in_order_of_name() {
+    let root = agent(NO_ADDRESS, ROOT_ADDRESS);
+    let bank = agent("/", "bank");
+    bank.set_type_name("Bank".to_string());
+    bank.set_state(Running);
+    let agents = vec!(agent("/bank/", "teller"), agent("/", "shop"), root.clone(),
+                      agent("/bank/", "audit"), bank);
+
+    let tree = build(&root, agents);
+    let paths: Vec<String> = tree.children.iter().map(|child| child.path.clone()).collect();
+    assert_eq!(paths, vec!("/bank".to_string(), "/shop".to_string()));
+    let bank = tree.find("/bank").unwrap();
+    assert_eq!(bank.type_name, "Bank".to_string());
+    assert_eq!(bank.state, Running);
+    let names: Vec<String> = bank.children.iter().map(|child| child.name.clone()).collect();
+    assert_eq!(names, vec!("audit".to_string(), "teller".to_string()));
+    assert_eq!(tree.find("/shop").unwrap().state, Starting);
+  }
+
+  #[test]
+  fn leaves_out_actors_whose_parent_is_gone() {
+    let root = agent(NO_ADDRESS, ROOT_ADDRESS);
+    let tree = build(&root, vec!(agent("/gone/", "orphan")));
+    assert!(tree.children.is_empty());
+    assert!(tree.find("/gone/orphan").is_none());
+  }
+
+  #[test]
+  fn draws_each_actor_beneath_its_parent() {
+    let root = agent(NO_ADDRESS, ROOT_ADDRESS);
+    root.set_type_name("Stage".to_string());
+    let bank = agent("/", "bank");
+    bank.set_type_name("Bank".to_string());
+    let audit = agent("/bank/", "audit");
+    audit.set_type_name("Auditor".to_string());
+    let shop = agent("/", "shop");
+    shop.set_type_name("Shop".to_string());
+
+    let tree = build(&root, vec!(root.clone(), bank, audit, shop));
+    assert_eq!(format!("{}", tree), concat!(
+      "/ (Stage) starting, mailbox 0, watchers 0\n",
+      "|-- bank (Bank) starting, mailbox 0, watchers 0\n",
+      "|   `-- audit (Auditor) starting, mailbox 0, watchers 0\n",
+      "`-- shop (Shop) starting, mailbox 0, watchers 0\n").to_string());
+  }
+
+  #[deriving(Clone)]
+  struct Crash;
+  impl Message for Crash {}
+
+  struct Crasher;
+
+  impl Actor for Crasher {
+    fn new() -> Crasher {
+      Crasher
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      fail!("crashed");
+    }
+  }
+
+  #[deriving(Clone)]
+  struct StartCrasher;
+  impl Message for StartCrasher {}
+
+  #[deriving(Clone)]
+  struct CrasherStarted {
+    crasher: Agent
+  }
+  impl Message for CrasherStarted {}
+
+  // Starts a Crasher for whoever asks, and passes its failures on to them.
+  struct Supervisor {
+    asker: Option<Agent>
+  }
+
+  impl Actor for Supervisor {
+    fn new() -> Supervisor {
+      Supervisor { asker: None }
+    }
+
+    fn receive(&mut self, context: &mut Context, msg: Box<Message>, sender: Agent) {
+      if msg.is::<StartCrasher>() {
+        let crasher = context.start_child_name::<Crasher>("crasher".to_string());
+        sender.deliver(context.send(box CrasherStarted { crasher: crasher }));
+        self.asker = Some(sender);
+      }
+    }
+
+    fn failed(&mut self, context: &mut Context, err: Box<Message>, failed: Agent) {
+      match self.asker {
+        Some(ref asker) => asker.deliver(context.send(err.clone_me())),
+        None => ()
+      }
+    }
+  }
+
+  #[test]
+  fn takes_an_actor_out_once_its_task_fails() {
+    let mut stage = Stage::new();
+    let supervisor = stage.start_name::<Supervisor>("supervisor".to_string());
+    let mut probe = TestProbe::new(&stage);
+    probe.send(&supervisor, box StartCrasher);
+    let crasher = probe.expect_msg::<CrasherStarted>(5000).crasher;
+    probe.watch(&crasher);
+    crasher.fire_and_forget(box Crash);
+
+    // Its watchers and its parent hear of it as of any other stop.
+    probe.expect_terminated(&crasher, 5000);
+    assert_eq!(crasher.state(), Failed);
+    assert!(stage.tree().find("/supervisor/crasher").is_none());
+    assert_eq!(probe.expect_msg::<TaskFailed>(5000), TaskFailed { path: crasher.path() });
+    assert_eq!(crasher.metrics().failures, 1);
+  }
+
+  #[test]
+  fn names_actors_by_their_type() {
+    let mut stage = Stage::deterministic();
+    stage.start_name::<Crasher>("crasher".to_string());
+    stage.run_until_idle();
+    assert_eq!(stage.tree().find("/crasher").unwrap().type_name, "Crasher".to_string());
+  }
+}
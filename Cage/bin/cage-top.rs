@@ -0,0 +1,160 @@
+/*
+ * cage-top watches a running Stage through its admin server, ex.
+ * for a Stage that called stage.serve_admin(8558):
+ *
+ *   cage-top 8558
+ *   cage-top 8558 500
+ *
+ * The admin server only listens on 127.0.0.1, so cage-top runs on
+ * the Stage's machine; to watch from elsewhere, forward the port,
+ * ex. ssh -L 8558:127.0.0.1:8558 stage-host, then cage-top 8558.
+ * cage-top only reads, so it needs no admin token.
+ *
+ * The second argument is how often to refresh, in milliseconds. The
+ * table lists every Actor with its mailbox depth, the messages it
+ * handled per second since the last refresh, and how often it failed.
+ * The tree shows the hierarchy, with subtrees folded and unfolded.
+ *
+ * Keys:
+ *   v        switch between the table and the tree
+ *   m t f p  sort the table by mailbox, throughput, failures or path
+ *   j k      move the cursor (or the arrow keys)
+ *   space    fold or unfold the subtree under the cursor
+ *   q        quit
+ */
+extern crate cage;
+extern crate time;
+
+use std::io;
+use std::io::Timer;
+use std::io::process::Command;
+use std::io::process::InheritFd;
+use std::os;
+use std::str;
+
+use cage::actor_admin::AdminClient;
+use cage::actor_admin::ADMIN_HOST;
+use cage::actor_top::Top;
+use cage::actor_top::USAGE;
+use cage::actor_top::keys_of;
+use cage::actor_top::parse_args;
+
+static CLEAR: &'static str = "\x1b[2J\x1b[H";
+
+// Takes a reading of the Stage, or notes why there is none.
+fn refresh(client: &AdminClient, top: &mut Top) {
+  let read = client.metrics().and_then(|metrics| {
+    client.tree().and_then(|tree| {
+      client.dead_letters().map(|dead_letters| (metrics, tree, dead_letters))
+    })
+  });
+  match read {
+    Ok((metrics, tree, dead_letters)) =>
+      top.update(metrics, tree, dead_letters.count, time::precise_time_ns()),
+    Err(err) => top.failed(format!("{}", err))
+  }
+}
+
+fn draw(top: &Top) {
+  let mut out = io::stdout();
+  let _ = out.write_str(CLEAR);
+  let _ = out.write_str(top.screen(height()).as_slice().connect("\r\n").as_slice());
+  let _ = out.flush();
+}
+
+/*
+ * The terminal.
+ */
+fn stty(args: &[&str]) -> Option<String> {
+  match Command::new("stty").args(args).stdin(InheritFd(0)).output() {
+    Ok(output) if output.status.success() =>
+      str::from_utf8(output.output.as_slice()).map(|s| s.trim().to_string()),
+    _ => None
+  }
+}
+
+// Keeps the terminal raw and unechoed, so keys arrive one at a time,
+// until dropped, even if cage-top fails.
+struct RawTerminal {
+  saved: Option<String>
+}
+
+impl RawTerminal {
+  fn new() -> RawTerminal {
+    let saved = stty(&["-g"]);
+    stty(&["raw", "-echo"]);
+    RawTerminal { saved: saved }
+  }
+}
+
+impl Drop for RawTerminal {
+  fn drop(&mut self) {
+    match self.saved {
+      Some(ref saved) => { stty(&[saved.as_slice()]); },
+      None => { stty(&["sane"]); }
+    }
+  }
+}
+
+// The terminal's height in lines.
+fn height() -> uint {
+  stty(&["size"]).and_then(|size| size.as_slice().words().next().and_then(|rows| from_str(rows)))
+                .unwrap_or(24)
+}
+
+// Reads keys from the terminal, sending the arrow keys as j and k.
+// Each read returns whatever keys are waiting, so an escape key alone
+// is read whole rather than waited on.
+fn read_keys(keys: Sender<char>) {
+  let mut stdin = io::stdio::stdin_raw();
+  let mut buf = [0u8, ..64];
+  loop {
+    let read = match stdin.read(buf) {
+      Ok(n) => keys_of(buf.slice_to(n)),
+      Err(_) => vec!['q']
+    };
+    for &key in read.iter() {
+      if keys.send_opt(key).is_err() || key == 'q' {
+        return;
+      }
+    }
+  }
+}
+
+fn usage() {
+  let _ = io::stderr().write_line(USAGE);
+  os::set_exit_status(2);
+}
+
+fn main() {
+  let args = os::args();
+  let (port, refresh_ms) = match parse_args(args.as_slice()) {
+    Some(parsed) => parsed,
+    None => {
+      usage();
+      return;
+    }
+  };
+
+  let client = AdminClient::new(ADMIN_HOST, port);
+  let mut top = Top::new(format!("{}:{}", ADMIN_HOST, port));
+
+  let terminal = RawTerminal::new();
+  let (send, keys) = channel();
+  spawn(proc() read_keys(send));
+
+  let mut timer = Timer::new().unwrap();
+  let ticks = timer.periodic(refresh_ms);
+  refresh(&client, &mut top);
+  draw(&top);
+  loop {
+    select! {
+      () = ticks.recv() => refresh(&client, &mut top),
+      key = keys.recv() => if !top.key(key) { break }
+    }
+    draw(&top);
+  }
+
+  drop(terminal);
+  print!("{}", CLEAR);
+}
@@ -4,6 +4,7 @@
  */
 use actor::Message;
 use actor_agent::Agent;
+use actor_trace::TraceContext;
 
 pub enum CageMessage {
   UserMessage(Box<Message:Send>, Agent),
@@ -15,3 +16,11 @@ pub enum CageMessage {
   Unwatch(Agent),
   Kill(Agent)
 }
+
+// A message as it sits in a mailbox, with the trace context of the
+// span it was sent from and when it was enqueued, in nanoseconds.
+pub struct Envelope {
+  pub msg: CageMessage,
+  pub trace: Option<TraceContext>,
+  pub enqueued: u64
+}
@@ -7,6 +7,12 @@ use cage::actor::Actor;
 use cage::actor::Message;
 use cage::actor_agent::Agent;
 use cage::actor_context::Context;
+use cage::actor_fsm::Fsm;
+use cage::actor_fsm::FsmActor;
+use cage::actor_fsm::Transition;
+use cage::actor_fsm::goto;
+use cage::actor_fsm::unhandled;
+use cage::actor_router::Broadcast;
 use cage::actor_stage::Stage;
 
 use std::any::AnyRefExt;
@@ -40,6 +46,7 @@ impl Message for Sum {}
 
 struct Generator {
   boss: Option<Agent>,
+  calculators: Option<Agent>,
   first: Option<int>
 }
 
@@ -47,6 +54,7 @@ impl Actor for Generator {
   fn new() -> Generator {
     Generator {
       boss: None,
+      calculators: None,
       first: None
     }
   }
@@ -58,8 +66,9 @@ impl Actor for Generator {
       if Rounds {
         &Rounds{ rounds } => {
            self.boss = Some(sender.clone());
-           for child in context.children().iter() {
-             child.deliver(context.send(msg.clone_me()));
+           match self.calculators {
+             Some(ref c) => c.deliver(context.send(msg.clone_me())),
+             None => () // this should never happen
            }
         }
       },
@@ -83,50 +92,73 @@ impl Actor for Generator {
     };
   }
   fn pre_start(&mut self, context: &mut Context) {
-    context.start_child::<Calculator>();
-    context.start_child::<Calculator>();
+    self.calculators = Some(context.start_router::<Fsm<CalcState, CalcData, Calculator>>(Broadcast, 2));
   }
 }
 
-struct Calculator {
+#[deriving(Clone, PartialEq, Show)]
+enum CalcState {
+  Idle,
+  Adding,
+  Done
+}
+
+struct CalcData {
   sum: int,
-  name: String,
   rounds: int
 }
 
-impl Actor for Calculator {
+struct Calculator {
+  name: String
+}
+
+impl FsmActor<CalcState, CalcData> for Calculator {
   fn new() -> Calculator {
     Calculator {
-      sum: 0,
-      name: "Nicolas Cage".to_string(),
-      rounds: 0
+      name: "Nicolas Cage".to_string()
     }
   }
-  fn receive(&mut self,
-             context: &mut Context,
-             msg: Box<Message>,
-             sender: Agent) {
-    match_any! { msg match
-      if Rounds {
-        &Rounds{ rounds } => {
-          self.rounds = rounds
+  fn start_with(&mut self, context: &mut Context) -> (CalcState, CalcData) {
+    (Idle, CalcData { sum: 0, rounds: 0 })
+  }
+  fn when(&mut self,
+          context: &mut Context,
+          state: &CalcState,
+          data: &CalcData,
+          event: &Box<Message>,
+          sender: &Agent) -> Transition<CalcState, CalcData> {
+    match *state {
+      Idle => match_any! { event match
+        if Rounds {
+          &Rounds{ rounds } => Calculator::next_round(context, sender, CalcData { sum: 0, rounds: rounds })
         }
+        else { unhandled() }
       },
-      if AddNum {
-        &AddNum{ num } => {
-          self.sum += num;
-          self.rounds -= 1;
+      Adding => match_any! { event match
+        if AddNum {
+          &AddNum{ num } => Calculator::next_round(context, sender, CalcData {
+            sum: data.sum + num,
+            rounds: data.rounds - 1
+          })
         }
-      }
-      else {
-        ()
-      }
-    };
+        else { unhandled() }
+      },
+      Done => unhandled()
+    }
+  }
+}
 
-    if self.rounds > 0 {
-      sender.deliver(context.send(box WantNumber)); 
+impl Calculator {
+  // Asks for another number, or reports the sum once the rounds are up.
+  fn next_round(context: &mut Context,
+                sender: &Agent,
+                data: CalcData) -> Transition<CalcState, CalcData> {
+    if data.rounds > 0 {
+      sender.deliver(context.send(box WantNumber));
+      goto(Adding).using(data)
     } else {
-      sender.deliver(context.send(box Sum { sum: self.sum }));
+      sender.deliver(context.send(box Sum { sum: data.sum }));
+      goto(Done).using(data)
     }
   }
 }
@@ -136,17 +168,18 @@ fn main() {
   let gen = stage.start::<Generator>();
   let msg = box Rounds { rounds: 10 };
   let winner = gen.request(msg);
+  let log = stage.log();
   match winner.unwrap() {
     Some(msg) => {
       match_any! { msg match
         if Sum {
-          &Sum{ sum } => println!("The calculators summed to {}", sum)
+          &Sum{ sum } => log.info(format!("The calculators summed to {}", sum).as_slice())
         }
         else {
-          println!("Both calculators malfunctioned.")
+          log.error("Both calculators malfunctioned.")
         }
       };
     } ,
-    None => println!("Generator malfunctioned.")
+    None => log.error("Generator malfunctioned.")
   }
 }
@@ -0,0 +1,68 @@
+#![feature(macro_rules)]
+#![feature(phase)]
+
+extern crate cage;
+
+use cage::actor::Actor;
+use cage::actor::Message;
+use cage::actor_agent::Agent;
+use cage::actor_cluster::CurrentClusterState;
+use cage::actor_cluster::MemberEvent;
+use cage::actor_cluster::SubscribeCluster;
+use cage::actor_context::Context;
+use cage::actor_stage::Stage;
+
+use std::any::AnyRefExt;
+use std::io::timer;
+
+#[macro_escape] mod match_any;
+
+/*
+ * Prints the membership of the cluster as its Stage sees it.
+ */
+struct Listener;
+
+impl Actor for Listener {
+  fn new() -> Listener {
+    Listener
+  }
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    match_any! { msg match
+      if CurrentClusterState {
+        &CurrentClusterState{ ref members, .. } => context.log().info(format!("members: {}", members).as_slice())
+      },
+      if MemberEvent {
+        &MemberEvent{ ref member } => context.log().info(format!("member {}", member).as_slice())
+      }
+      else { () }
+    }
+  }
+  fn pre_start(&mut self, context: &mut Context) {
+    context.cluster().deliver(context.send(box SubscribeCluster));
+  }
+}
+
+/*
+ * Top-level code: three Stages on localhost, as if in three processes.
+ */
+fn main() {
+  let mut stages = vec![Stage::new(), Stage::new(), Stage::new()];
+  let addresses: Vec<String> = stages.iter().map(|stage| {
+    stage.listen("cluster", "127.0.0.1", 0).unwrap().to_string()
+  }).collect();
+
+  stages.get_mut(0).start_name::<Listener>("listener".to_string());
+
+  // Every Stage joins through the first, which starts the cluster.
+  let seeds = vec![addresses[0].clone()];
+  for stage in stages.iter() {
+    stage.join_cluster(seeds.clone());
+  }
+  timer::sleep(5000);
+
+  stages[2].leave_cluster();
+  timer::sleep(5000);
+}
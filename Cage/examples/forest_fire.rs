@@ -95,7 +95,7 @@ impl Actor for Fir {
     };
     
     if self.on_fire {
-      println!("FIRE");
+      context.log().info("FIRE");
       // Message broadcasting.
       context.find("../*".to_string(), box Fire);
     }
@@ -116,5 +116,5 @@ fn main() {
 
   // Unpacking the response.
   arsonist.unwrap();
-  println!("only you can prevent forest fires");
+  stage.log().info("only you can prevent forest fires");
 }
@@ -0,0 +1,125 @@
+#![feature(macro_rules)]
+#![feature(phase)]
+
+extern crate cage;
+extern crate serialize;
+
+use cage::actor::Actor;
+use cage::actor::Message;
+use cage::actor_agent::Agent;
+use cage::actor_context::Context;
+use cage::actor_stage::Stage;
+
+use std::any::AnyRefExt;
+use std::io::timer;
+
+#[macro_escape] mod match_any;
+
+/*
+ * Message types. Those crossing between Stages are serialized.
+ */
+#[deriving(Clone)]
+struct Start {
+  ponger: String
+}
+
+#[deriving(Clone, Encodable, Decodable)]
+struct Ping {
+  n: int
+}
+
+#[deriving(Clone, Encodable, Decodable)]
+struct Pong {
+  n: int
+}
+
+impl Message for Start {}
+impl Message for Ping {}
+impl Message for Pong {}
+
+/*
+ * Actor types.
+ */
+
+// Lives on the first Stage; pings the Ponger until told it's gone.
+struct Pinger;
+
+impl Actor for Pinger {
+  fn new() -> Pinger {
+    Pinger
+  }
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    match_any! { msg match
+      if Start {
+        &Start{ ref ponger } =>
+          match context.remote_agent(ponger.as_slice()) {
+            Some(ponger) => {
+              ponger.deliver(context.watch());
+              ponger.deliver(context.send(box Ping { n: 1 }));
+            },
+            None => context.log().error("malformed address")
+          }
+      },
+      if Pong {
+        &Pong{ n } => {
+          context.log().info(format!("pong {} from {}", n, sender.path()).as_slice());
+          if n < 3 {
+            sender.deliver(context.send(box Ping { n: n + 1 }));
+          } else {
+            sender.deliver(context.kill());
+          }
+        }
+      }
+      else { () }
+    }
+  }
+  fn terminated(&mut self,
+                context: &mut Context,
+                terminated: Agent) {
+    context.log().info(format!("{} terminated", terminated.path()).as_slice());
+  }
+}
+
+// Lives on the second Stage; answers each Ping.
+struct Ponger;
+
+impl Actor for Ponger {
+  fn new() -> Ponger {
+    Ponger
+  }
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    match_any! { msg match
+      if Ping {
+        &Ping{ n } => sender.deliver(context.send(box Pong { n: n }))
+      }
+      else { () }
+    }
+  }
+}
+
+/*
+ * Top-level code: two Stages on localhost, as if in two processes.
+ */
+fn main() {
+  let mut pings = Stage::new();
+  let mut pongs = Stage::new();
+  for stage in [&pings, &pongs].iter() {
+    stage.serialization().register_binary::<Ping>("ping");
+    stage.serialization().register_json::<Pong>("pong");
+  }
+
+  pings.listen("pings", "127.0.0.1", 0).unwrap();
+  let address = pongs.listen("pongs", "127.0.0.1", 0).unwrap();
+
+  pongs.start_name::<Ponger>("ponger".to_string());
+  let pinger = pings.start_name::<Pinger>("pinger".to_string());
+  pinger.fire_and_forget(box Start { ponger: address.uri("/ponger") });
+
+  timer::sleep(1000);
+}
@@ -0,0 +1,99 @@
+#![feature(macro_rules)]
+#![feature(phase)]
+
+extern crate cage;
+extern crate serialize;
+
+use cage::actor::Actor;
+use cage::actor::Message;
+use cage::actor_agent::Agent;
+use cage::actor_context::Context;
+use cage::actor_sharding::ShardEnvelope;
+use cage::actor_stage::Stage;
+
+use std::any::AnyRefExt;
+use std::io::timer;
+
+#[macro_escape] mod match_any;
+
+/*
+ * Message types. Each is sent in a ShardEnvelope naming its account.
+ */
+#[deriving(Clone, Encodable, Decodable)]
+struct Deposit {
+  account: String,
+  amount: int
+}
+
+impl Message for Deposit {}
+
+fn deposit(account: &str, amount: int) -> Box<ShardEnvelope> {
+  box ShardEnvelope {
+    entity_id: account.to_string(),
+    msg: box Deposit { account: account.to_string(), amount: amount } as Box<Message:Send>
+  }
+}
+
+/*
+ * Actor types.
+ */
+
+// One per account, started on whichever Stage hosts its shard.
+struct Account {
+  balance: int
+}
+
+impl Actor for Account {
+  fn new() -> Account {
+    Account { balance: 0 }
+  }
+  fn receive(&mut self,
+             context: &mut Context,
+             msg: Box<Message>,
+             sender: Agent) {
+    match_any! { msg match
+      if Deposit {
+        &Deposit{ ref account, amount } => {
+          self.balance += amount;
+          context.log().info(format!("{} at {}: balance {}", account, context.agent().path(), self.balance).as_slice());
+        }
+      }
+      else { () }
+    }
+  }
+}
+
+/*
+ * Top-level code: three Stages on localhost, as if in three processes.
+ */
+fn main() {
+  let mut stages = vec![Stage::new(), Stage::new(), Stage::new()];
+  let addresses: Vec<String> = stages.iter().map(|stage| {
+    stage.serialization().register_json::<Deposit>("bank.deposit");
+    stage.listen("bank", "127.0.0.1", 0).unwrap().to_string()
+  }).collect();
+
+  let regions: Vec<Agent> = stages.mut_iter().map(|stage| {
+    stage.start_sharding::<Account>("accounts".to_string(), 10)
+  }).collect();
+
+  let seeds = vec![addresses[0].clone()];
+  for stage in stages.iter() {
+    stage.join_cluster(seeds.clone());
+  }
+  timer::sleep(5000);
+
+  // Deposits through any region reach the same account.
+  for (i, region) in regions.iter().enumerate() {
+    for name in ["alice", "bob", "carol", "dave"].iter() {
+      region.fire_and_forget(deposit(*name, i as int + 1));
+    }
+  }
+  timer::sleep(2000);
+
+  // The third Stage's shards move to the other two.
+  stages[2].leave_cluster();
+  timer::sleep(5000);
+  regions[0].fire_and_forget(deposit("carol", 10));
+  timer::sleep(1000);
+}